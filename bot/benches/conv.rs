@@ -0,0 +1,48 @@
+use std::time::Instant;
+use rand::Rng;
+use rand::rngs::StdRng;
+use bot::net::{Conv2d, Layer, NetImage, SimpleNetwork};
+use bot::rng::stream_rng;
+
+fn random_image(w : usize, h : usize, c : usize, rnd : &mut StdRng) -> NetImage {
+    let mut img = NetImage::new(w, h, c);
+    for val in img.data.iter_mut() {
+        *val = rnd.gen_range(-1.0..=1.0);
+    }
+    img
+}
+
+fn time_it<F : FnMut()>(iters : usize, mut f : F) -> f64 {
+    f();
+    let start = Instant::now();
+    for _ in 0..iters {
+        f();
+    }
+    start.elapsed().as_secs_f64() * 1e6 / iters as f64
+}
+
+fn bench_conv(size : usize, in_c : usize, out_c : usize, iters : usize, rnd : &mut StdRng) {
+    //24x12 is the largest map, padded like central_conv2d does
+    let inp = random_image(24 + size - 1, 12 + size - 1, in_c, rnd);
+    let mut conv = Conv2d::new(size, size, in_c, out_c, rnd);
+    let mut dst = conv.allocate_output(&inp);
+
+    let reference = time_it(iters, || conv.process_reference(&inp, &mut dst));
+    let fast = time_it(iters, || conv.process(&inp, &mut dst));
+    println!("conv {}x{} {:>2} -> {:>2}: reference {:>9.1} us, fast {:>9.1} us, x{:.2}",
+        size, size, in_c, out_c, reference, fast, reference / fast);
+}
+
+fn main() {
+    let mut rnd = stream_rng(0, 0);
+    bench_conv(5, 4, 16, 200, &mut rnd);
+    bench_conv(5, 16, 16, 200, &mut rnd);
+    bench_conv(5, 16, 4, 200, &mut rnd);
+    bench_conv(3, 16, 16, 200, &mut rnd);
+
+    let inp = random_image(24, 12, 4, &mut rnd);
+    let mut net = SimpleNetwork::simple_maker(5, 4, 16, 4, 2, &mut rnd);
+    let mut dst = net.allocate_output(&inp);
+    let full = time_it(200, || net.process(&inp, &mut dst));
+    println!("simple_maker(5, 4, 16, 4, 2) on 24x12: {:.1} us", full);
+}
@@ -0,0 +1,62 @@
+use std::time::Instant;
+use rand::Rng;
+use rand::rngs::StdRng;
+use bot::{Action, Map, MoveAction, SpawnAction, TileOwner};
+use bot::rng::stream_rng;
+
+const MAP : &str = include_str!("../../start_map.txt");
+
+//every owned tile sends its units to random target and spawns one unit
+fn random_actions(map : &Map, side : &TileOwner, rnd : &mut StdRng) -> Vec<Action> {
+    let mut res = vec![];
+    for y in 0..map.h {
+        for x in 0..map.w {
+            let tile = &map.data[y * map.w + x];
+            if tile.owner != *side || tile.scrap_amount == 0 || tile.recycler {
+                continue;
+            }
+            if tile.units != 0 {
+                res.push(Action::Move(MoveAction {
+                    amount : tile.units.unsigned_abs(),
+                    fromX : x,
+                    fromY : y,
+                    toX : rnd.gen_range(0..map.w),
+                    toY : rnd.gen_range(0..map.h)
+                }));
+            }
+            if rnd.gen_bool(0.1) {
+                res.push(Action::Spawn(SpawnAction { amount : 1, x, y }));
+            }
+        }
+    }
+    res
+}
+
+//plays seeded games, returns time per game in us and final tile counts
+fn play_games(reference : bool, games : u64, turns : usize) -> (f64, Vec<(usize, usize)>) {
+    let mut results = vec![];
+    let mut total = 0.0;
+    for game in 0..games {
+        let mut rnd = stream_rng(game, 0);
+        let mut map = Map::load(MAP.trim().to_string());
+        map.scratch_mut().pathfinder.reference = reference;
+
+        for _ in 0..turns {
+            let my = random_actions(&map, &TileOwner::Me, &mut rnd);
+            let enemy = random_actions(&map, &TileOwner::Enemy, &mut rnd);
+            let start = Instant::now();
+            map.next_turn(&my, &enemy);
+            total += start.elapsed().as_secs_f64();
+        }
+        results.push((map.tile_count(&TileOwner::Me), map.tile_count(&TileOwner::Enemy)));
+    }
+    (total * 1e6 / games as f64, results)
+}
+
+fn main() {
+    let (reference, reference_results) = play_games(true, 20, 100);
+    let (cached, cached_results) = play_games(false, 20, 100);
+    assert_eq!(reference_results, cached_results, "cached pathfinder changed game results");
+    println!("100 turn game, simulation only: reference BFS {:.1} us, cached distances {:.1} us, x{:.2}",
+        reference, cached, reference / cached);
+}
@@ -0,0 +1,127 @@
+use std::time::Instant;
+use rand::Rng;
+use rand::rngs::StdRng;
+use bot::{Action, Map, MoveAction, SpawnAction, TileOwner, TurnScratch, TurnUndo};
+use bot::bitboard::BitState;
+use bot::rng::stream_rng;
+
+const MAP : &str = include_str!("../../start_map.txt");
+const NODES : usize = 20000;
+const DEPTH : usize = 4;
+
+//my and enemy actions for every level of one line
+type Line = Vec<(Vec<Action>, Vec<Action>)>;
+
+fn random_actions(map : &Map, side : &TileOwner, rnd : &mut StdRng) -> Vec<Action> {
+    let mut res = vec![];
+    for (idx, tile) in map.data.iter().enumerate() {
+        if tile.owner != *side || tile.scrap_amount == 0 {
+            continue;
+        }
+        let (x, y) = (idx % map.w, idx / map.w);
+        if tile.units != 0 {
+            res.push(Action::Move(MoveAction {
+                amount : tile.units.unsigned_abs(),
+                fromX : x,
+                fromY : y,
+                toX : rnd.gen_range(0..map.w),
+                toY : rnd.gen_range(0..map.h)
+            }));
+        }
+        if rnd.gen_bool(0.1) {
+            res.push(Action::Spawn(SpawnAction { amount : 1, x, y }));
+        }
+    }
+    res
+}
+
+//root with some units on board and action pairs for every level of every line
+fn setup() -> (Map, Vec<Line>) {
+    let mut rnd = stream_rng(0, 0);
+    let mut root = Map::load(MAP.trim().to_string());
+    for _ in 0..10 {
+        let my = random_actions(&root, &TileOwner::Me, &mut rnd);
+        let enemy = random_actions(&root, &TileOwner::Enemy, &mut rnd);
+        root.next_turn(&my, &enemy);
+    }
+    let lines = (0..64).map(|_| (0..DEPTH).map(|_| {
+        (random_actions(&root, &TileOwner::Me, &mut rnd), random_actions(&root, &TileOwner::Enemy, &mut rnd))
+    }).collect()).collect();
+    (root, lines)
+}
+
+fn checksum(map : &Map) -> i64 {
+    map.data.iter().enumerate().map(|(idx, t)| (idx as i64 + 1) * (t.units as i64 * 31 + t.scrap_amount as i64)).sum::<i64>()
+        + map.my_scrap as i64 * 7 + map.enemy_scrap as i64
+}
+
+//every node of line is a fresh copy of its parent, returns time per node in us and checksum of leaves
+fn clone_per_node<F : FnMut(&Map, &(Vec<Action>, Vec<Action>)) -> Map>(root : &Map, lines : &[Line], mut step : F) -> (f64, i64) {
+    let start = Instant::now();
+    let mut sum = 0;
+    for n in 0..NODES / DEPTH {
+        let mut node = root.clone();
+        for actions in lines[n % lines.len()].iter() {
+            node = step(&node, actions);
+        }
+        sum += checksum(&node);
+    }
+    (start.elapsed().as_secs_f64() * 1e6 / NODES as f64, sum)
+}
+
+fn main() {
+    let (root, lines) = setup();
+
+    //old way, every copy needs its own pathfinder
+    let (own, own_sum) = clone_per_node(&root, &lines, |parent, (my, enemy)| {
+        let mut child = parent.clone();
+        child.next_turn(my, enemy);
+        child
+    });
+
+    let mut scratch = TurnScratch::new(root.w, root.h);
+    let (shared, shared_sum) = clone_per_node(&root, &lines, |parent, (my, enemy)| {
+        let mut child = parent.clone();
+        child.next_turn_with(my, enemy, &mut scratch);
+        child
+    });
+
+    //walks line down and back up in place
+    let start = Instant::now();
+    let mut undo_sum = 0;
+    let mut node = root.clone();
+    let mut undos = vec![TurnUndo::default(); DEPTH];
+    for n in 0..NODES / DEPTH {
+        for (actions, undo) in lines[n % lines.len()].iter().zip(undos.iter_mut()) {
+            node.apply_turn(&actions.0, &actions.1, &mut scratch, undo);
+        }
+        undo_sum += checksum(&node);
+        for undo in undos.iter().rev() {
+            node.undo_turn(undo);
+        }
+    }
+    let undo = start.elapsed().as_secs_f64() * 1e6 / NODES as f64;
+
+    let start = Instant::now();
+    let mut bits_sum = 0;
+    let bits_root = BitState::from_map(&root);
+    for n in 0..NODES / DEPTH {
+        let mut node = bits_root.clone();
+        for (my, enemy) in lines[n % lines.len()].iter() {
+            let mut child = node.clone();
+            child.next_turn(my, enemy, &mut scratch.pathfinder);
+            node = child;
+        }
+        bits_sum += checksum(&node.to_map());
+    }
+    let bits = start.elapsed().as_secs_f64() * 1e6 / NODES as f64;
+
+    assert_eq!(own_sum, shared_sum);
+    assert_eq!(own_sum, undo_sum);
+    assert_eq!(own_sum, bits_sum);
+    println!("{} nodes, lines of depth {}, us per node:", NODES, DEPTH);
+    println!("  clone with own pathfinder {:.2}", own);
+    println!("  clone with shared scratch {:.2}", shared);
+    println!("  apply and undo in place   {:.2}", undo);
+    println!("  bitboard clone            {:.2}", bits);
+}
@@ -0,0 +1,619 @@
+use crate::net::NetImage;
+use crate::encoding::side_sign;
+use crate::{Map, Tile, TileOwner, UNREACHABLE};
+
+pub const TERRITORY_CHANNELS : usize = 4;
+
+//grass and recyclers block movement, same as walls in move_spawn
+#[inline(always)]
+pub fn is_walkable(tile : &Tile) -> bool {
+    tile.scrap_amount > 0 && !tile.recycler
+}
+
+//walkable neighbours of tile in left, up, right, down order
+pub fn walkable_neighbours(map : &Map, idx : usize) -> impl Iterator<Item = usize> + '_ {
+    let x = idx % map.w;
+    let y = idx / map.w;
+    [
+        (x > 0).then(|| idx - 1),
+        (y > 0).then(|| idx - map.w),
+        (x + 1 < map.w).then(|| idx + 1),
+        (y + 1 < map.h).then(|| idx + map.w)
+    ].into_iter().flatten().filter(move |n| is_walkable(&map.data[*n]))
+}
+
+//BFS from all walkable tiles owned by side, units always stand on owned tiles so they are covered too
+pub fn side_distances(map : &Map, side : &TileOwner) -> Vec<u16> {
+    distances_from(map, |tile| tile.owner == *side)
+}
+
+//BFS from all walkable tiles passing filter
+pub fn distances_from<F : Fn(&Tile) -> bool>(map : &Map, is_source : F) -> Vec<u16> {
+    let mut dist = vec![UNREACHABLE; map.data.len()];
+    let mut queue = vec![];
+    for (idx, tile) in map.data.iter().enumerate() {
+        if is_source(tile) && is_walkable(tile) {
+            dist[idx] = 0;
+            queue.push(idx);
+        }
+    }
+
+    let mut head = 0;
+    while head < queue.len() {
+        let idx = queue[head];
+        head += 1;
+        for next in walkable_neighbours(map, idx) {
+            if dist[next] == UNREACHABLE {
+                dist[next] = dist[idx] + 1;
+                queue.push(next);
+            }
+        }
+    }
+    dist
+}
+
+//voronoi split of walkable tiles by which side reaches them first
+pub struct Territory {
+    pub w : usize,
+    pub h : usize,
+    pub dist_me : Vec<u16>,
+    pub dist_enemy : Vec<u16>,
+    //side which reaches tile strictly first, No for ties and unreachable tiles
+    pub owner : Vec<TileOwner>,
+    //reachable by both sides with distance difference at most one
+    pub frontier : Vec<bool>,
+    pub my_count : usize,
+    pub enemy_count : usize,
+    pub contested_count : usize
+}
+
+impl Territory {
+    pub fn new(map : &Map) -> Self {
+        let dist_me = side_distances(map, &TileOwner::Me);
+        let dist_enemy = side_distances(map, &TileOwner::Enemy);
+
+        let n = map.data.len();
+        let mut owner = vec![TileOwner::No; n];
+        let mut frontier = vec![false; n];
+        let mut my_count = 0;
+        let mut enemy_count = 0;
+        let mut contested_count = 0;
+
+        for idx in 0..n {
+            let (me, enemy) = (dist_me[idx], dist_enemy[idx]);
+            if me < enemy {
+                owner[idx] = TileOwner::Me;
+                my_count += 1;
+            } else if enemy < me {
+                owner[idx] = TileOwner::Enemy;
+                enemy_count += 1;
+            } else if me != UNREACHABLE {
+                contested_count += 1;
+            }
+            frontier[idx] = me != UNREACHABLE && enemy != UNREACHABLE && me.abs_diff(enemy) <= 1;
+        }
+
+        Self {
+            w : map.w,
+            h : map.h,
+            dist_me,
+            dist_enemy,
+            owner,
+            frontier,
+            my_count,
+            enemy_count,
+            contested_count
+        }
+    }
+
+    pub fn count(&self, side : &TileOwner) -> usize {
+        match side {
+            TileOwner::Me => {self.my_count}
+            TileOwner::Enemy => {self.enemy_count}
+            TileOwner::No => {self.contested_count}
+        }
+    }
+
+    //channels from offset: side reach, opponent reach, voronoi owner, frontier
+    //reach is 1 / (1 + distance), 0 when unreachable; owner is seen from side
+    pub fn fill_channels(&self, side : &TileOwner, image : &mut NetImage, offset : usize) {
+        let k = side_sign(side);
+        let (side_dist, other_dist) = match side {
+            TileOwner::Me => {(&self.dist_me, &self.dist_enemy)}
+            _ => {(&self.dist_enemy, &self.dist_me)}
+        };
+        let reach = |d : u16| if d == UNREACHABLE { 0.0 } else { 1.0 / (1.0 + d as f32) };
+
+        for y in 0..self.h {
+            for x in 0..self.w {
+                let idx = y * self.w + x;
+                *image.get_mut(x, y, offset) = reach(side_dist[idx]);
+                *image.get_mut(x, y, offset + 1) = reach(other_dist[idx]);
+                *image.get_mut(x, y, offset + 2) = match self.owner[idx] {
+                    TileOwner::Me => {k}
+                    TileOwner::Enemy => {-k}
+                    TileOwner::No => {0.0}
+                };
+                *image.get_mut(x, y, offset + 3) = if self.frontier[idx] { 1.0 } else { 0.0 };
+            }
+        }
+    }
+}
+
+pub const NO_ISLAND : usize = usize::MAX;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IslandKind {
+    //only my tiles and neutral tiles, enemy can never enter
+    Mine,
+    Enemy,
+    //nobody owns anything, stays neutral forever
+    Neutral,
+    Contested
+}
+
+//connected walkable tiles, walls never disappear so islands can only split later
+#[derive(Debug, Clone)]
+pub struct Island {
+    pub tiles : Vec<usize>,
+    pub kind : IslandKind,
+    pub my_tiles : usize,
+    pub enemy_tiles : usize,
+    pub my_units : i32,
+    pub enemy_units : i32
+}
+
+impl Island {
+    //final owner of all island tiles is already certain
+    pub fn locked(&self) -> bool {
+        self.kind != IslandKind::Contested
+    }
+}
+
+pub struct Islands {
+    //island index per tile, NO_ISLAND for walls and recyclers
+    pub component : Vec<usize>,
+    pub islands : Vec<Island>,
+    //recyclers belong to no island but can not change owner, so they are locked too
+    pub my_recyclers : usize,
+    pub enemy_recyclers : usize
+}
+
+impl Islands {
+    pub fn island_at(&self, idx : usize) -> Option<&Island> {
+        self.islands.get(self.component[idx])
+    }
+
+    //tiles side gets from locked islands once it spreads over them, plus its recyclers
+    pub fn locked_tiles(&self, side : &TileOwner) -> usize {
+        let (kind, recyclers) = match side {
+            TileOwner::Me => {(IslandKind::Mine, self.my_recyclers)}
+            TileOwner::Enemy => {(IslandKind::Enemy, self.enemy_recyclers)}
+            TileOwner::No => {(IslandKind::Neutral, 0)}
+        };
+        recyclers + self.islands.iter().filter(|i| i.kind == kind).map(|i| i.tiles.len()).sum::<usize>()
+    }
+
+    pub fn all_locked(&self) -> bool {
+        self.islands.iter().all(|i| i.locked())
+    }
+
+    //winner when every island is locked, No for draw; assumes nobody recycles own islands away
+    pub fn locked_winner(&self) -> Option<TileOwner> {
+        if !self.all_locked() {
+            return None;
+        }
+        let me = self.locked_tiles(&TileOwner::Me);
+        let enemy = self.locked_tiles(&TileOwner::Enemy);
+        Some(match me.cmp(&enemy) {
+            std::cmp::Ordering::Greater => {TileOwner::Me}
+            std::cmp::Ordering::Less => {TileOwner::Enemy}
+            std::cmp::Ordering::Equal => {TileOwner::No}
+        })
+    }
+}
+
+impl Map {
+    pub fn islands(&self) -> Islands {
+        let mut component = vec![NO_ISLAND; self.data.len()];
+        let mut islands = vec![];
+
+        for start in 0..self.data.len() {
+            if component[start] != NO_ISLAND || !is_walkable(&self.data[start]) {
+                continue;
+            }
+            let id = islands.len();
+            component[start] = id;
+            let mut tiles = vec![start];
+            let mut head = 0;
+            while head < tiles.len() {
+                let idx = tiles[head];
+                head += 1;
+                for next in walkable_neighbours(self, idx) {
+                    if component[next] == NO_ISLAND {
+                        component[next] = id;
+                        tiles.push(next);
+                    }
+                }
+            }
+
+            let mut island = Island {
+                tiles,
+                kind : IslandKind::Neutral,
+                my_tiles : 0,
+                enemy_tiles : 0,
+                my_units : 0,
+                enemy_units : 0
+            };
+            for idx in island.tiles.iter() {
+                let tile = &self.data[*idx];
+                match tile.owner {
+                    TileOwner::Me => {
+                        island.my_tiles += 1;
+                        island.my_units += tile.units;
+                    }
+                    TileOwner::Enemy => {
+                        island.enemy_tiles += 1;
+                        island.enemy_units -= tile.units;
+                    }
+                    TileOwner::No => {}
+                }
+            }
+            island.kind = match (island.my_tiles > 0, island.enemy_tiles > 0) {
+                (true, true) => {IslandKind::Contested}
+                (true, false) => {IslandKind::Mine}
+                (false, true) => {IslandKind::Enemy}
+                (false, false) => {IslandKind::Neutral}
+            };
+            islands.push(island);
+        }
+
+        let recyclers = |side : TileOwner| self.data.iter().filter(|t| t.recycler && t.scrap_amount > 0 && t.owner == side).count();
+        Islands {
+            component,
+            islands,
+            my_recyclers : recyclers(TileOwner::Me),
+            enemy_recyclers : recyclers(TileOwner::Enemy)
+        }
+    }
+}
+
+//what recycler built at tile would do if map stayed as it is, counted from build turn as turn 1
+#[derive(Debug, Clone)]
+pub struct RecyclerForecast {
+    //scrap side gets from tiles new recycler covers while it works
+    pub total_yield : i32,
+    //extra scrap compared to not building, tiles shared with own recyclers are not counted twice
+    pub marginal_yield : i32,
+    //turns until recycler tile itself turns to grass
+    pub lifetime : usize,
+    //covered tile and turn it turns to grass, None when it survives
+    pub grass_turns : Vec<(usize, Option<usize>)>,
+    //owned tiles separated from main part of side territory, units on them
+    pub cut_off_tiles : Vec<usize>,
+    pub cut_off_units : i32
+}
+
+struct RecyclerRun {
+    income : i32,
+    candidate_yield : i32,
+    grass_turn : Vec<Option<usize>>,
+    scrap : Vec<i32>
+}
+
+fn covered_tiles(map : &Map, idx : usize) -> impl Iterator<Item = usize> {
+    let (x, y, w, h) = (idx % map.w, idx / map.w, map.w, map.h);
+    [
+        Some(idx),
+        (x > 0).then(|| idx - 1),
+        (y > 0).then(|| idx - w),
+        (x + 1 < w).then(|| idx + 1),
+        (y + 1 < h).then(|| idx + w)
+    ].into_iter().flatten()
+}
+
+//replays recycler_process only, every recycler works until its own tile is grass
+fn run_recyclers(map : &Map, side : &TileOwner, candidate : Option<usize>) -> RecyclerRun {
+    let n = map.data.len();
+    let mut scrap : Vec<i32> = map.data.iter().map(|t| t.scrap_amount).collect();
+    let mut recyclers : Vec<Option<TileOwner>> = map.data.iter()
+        .map(|t| t.recycler.then(|| t.owner.clone()))
+        .collect();
+    if let Some(idx) = candidate {
+        recyclers[idx] = Some(side.clone());
+    }
+
+    let mut run = RecyclerRun {
+        income : 0,
+        candidate_yield : 0,
+        grass_turn : vec![None; n],
+        scrap : vec![]
+    };
+    let mut by_side = vec![false; n];
+    let mut by_other = vec![false; n];
+    let mut by_candidate = vec![false; n];
+
+    for turn in 1.. {
+        by_side.fill(false);
+        by_other.fill(false);
+        by_candidate.fill(false);
+        for (idx, recycler) in recyclers.iter().enumerate() {
+            if let Some(owner) = recycler {
+                let covered = if owner == side { &mut by_side } else { &mut by_other };
+                for c in covered_tiles(map, idx) {
+                    covered[c] = true;
+                    by_candidate[c] |= candidate == Some(idx);
+                }
+            }
+        }
+
+        let mut changed = false;
+        for idx in 0..n {
+            if scrap[idx] == 0 || !(by_side[idx] || by_other[idx]) {
+                continue;
+            }
+            changed = true;
+            scrap[idx] -= 1;
+            if by_side[idx] {
+                run.income += 1;
+            }
+            if by_candidate[idx] {
+                run.candidate_yield += 1;
+            }
+            if scrap[idx] == 0 {
+                run.grass_turn[idx] = Some(turn);
+                recyclers[idx] = None;
+            }
+        }
+        if !changed {
+            break;
+        }
+    }
+    run.scrap = scrap;
+    run
+}
+
+//owned tiles of side outside of island holding most of them, with remaining scrap as walls
+fn cut_off(map : &Map, side : &TileOwner, scrap : &[i32]) -> Vec<usize> {
+    let mut component = vec![NO_ISLAND; scrap.len()];
+    let mut owned_count = vec![];
+    for start in 0..scrap.len() {
+        if component[start] != NO_ISLAND || scrap[start] == 0 {
+            continue;
+        }
+        let id = owned_count.len();
+        owned_count.push(0);
+        component[start] = id;
+        let mut queue = vec![start];
+        while let Some(idx) = queue.pop() {
+            if map.data[idx].owner == *side {
+                owned_count[id] += 1;
+            }
+            for next in covered_tiles(map, idx).skip(1) {
+                if component[next] == NO_ISLAND && scrap[next] > 0 {
+                    component[next] = id;
+                    queue.push(next);
+                }
+            }
+        }
+    }
+
+    let main = (0..owned_count.len()).max_by_key(|id| (owned_count[*id], usize::MAX - id));
+    (0..scrap.len())
+        .filter(|idx| map.data[*idx].owner == *side && scrap[*idx] > 0 && Some(component[*idx]) != main)
+        .collect()
+}
+
+impl Map {
+    //None when tile is grass or already has recycler; ownership and units of tile are not checked
+    pub fn forecast_recycler(&self, x : usize, y : usize, side : &TileOwner) -> Option<RecyclerForecast> {
+        let idx = y * self.w + x;
+        if !is_walkable(&self.data[idx]) {
+            return None;
+        }
+        let with = run_recyclers(self, side, Some(idx));
+        let without = run_recyclers(self, side, None);
+
+        let before = cut_off(self, side, &without.scrap);
+        let cut_off_tiles : Vec<usize> = cut_off(self, side, &with.scrap)
+            .into_iter()
+            .filter(|t| !before.contains(t))
+            .collect();
+        let cut_off_units = cut_off_tiles.iter().map(|t| self.data[*t].units.abs()).sum();
+
+        Some(RecyclerForecast {
+            total_yield : with.candidate_yield,
+            marginal_yield : with.income - without.income,
+            lifetime : with.grass_turn[idx].expect("Recycler tile always runs out of scrap"),
+            grass_turns : covered_tiles(self, idx).map(|t| (t, with.grass_turn[t])).collect(),
+            cut_off_tiles,
+            cut_off_units
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::encoding::{fill_extended_input, EXTENDED_INPUT_CHANNELS};
+    use crate::{Action, BuildAction};
+
+    //'.' neutral, 'M' mine, 'E' enemy, '#' grass, 'R' my recycler, 'r' enemy recycler, all with 5 scrap
+    //digit is neutral tile with that much scrap
+    fn map_from(rows : &[&str]) -> Map {
+        let mut data = format!("{} {};10 10", rows[0].len(), rows.len());
+        for row in rows {
+            for c in row.chars() {
+                data.push_str(match c {
+                    '.' => {";5 -1 0 0 0 0 0"}
+                    'M' => {";5 1 0 0 0 0 0"}
+                    'E' => {";5 0 0 0 0 0 0"}
+                    'R' => {";5 1 0 1 0 0 0"}
+                    'r' => {";5 0 0 1 0 0 0"}
+                    '#' => {";0 -1 0 0 0 0 0"}
+                    _ => {
+                        data.push_str(&format!(";{} -1 0 0 0 0 0", c));
+                        continue;
+                    }
+                });
+            }
+        }
+        Map::load(data)
+    }
+
+    #[test]
+    fn test_territory() {
+        let map = map_from(&[
+            "M....",
+            ".#...",
+            "....E"]);
+        let territory = Territory::new(&map);
+        assert_eq!(territory.dist_me[4], 4);
+        assert_eq!(territory.dist_enemy[4], 2);
+        assert_eq!(territory.dist_me[6], UNREACHABLE);
+        //(3, 0), (2, 1) and (1, 2) are tied
+        assert_eq!(territory.owner[2], TileOwner::Me);
+        assert_eq!(territory.owner[7], TileOwner::No);
+        assert_eq!(territory.owner[8], TileOwner::Enemy);
+        assert_eq!((territory.my_count, territory.enemy_count, territory.contested_count), (5, 6, 3));
+        assert!(territory.frontier[7] && territory.frontier[11] && !territory.frontier[8]);
+    }
+
+    #[test]
+    fn test_territory_walls() {
+        //recycler splits board, right part is only reachable by enemy
+        let map = map_from(&[
+            "M.R..",
+            "..#.E"]);
+        let territory = Territory::new(&map);
+        assert_eq!(territory.count(&TileOwner::Me), 4);
+        assert_eq!(territory.count(&TileOwner::Enemy), 4);
+        assert_eq!(territory.count(&TileOwner::No), 0);
+        assert!(territory.frontier.iter().all(|f| !f));
+    }
+
+    #[test]
+    fn test_territory_channels() {
+        let map = map_from(&[
+            "M..E"]);
+        let mut input = NetImage::new(map.w, map.h, EXTENDED_INPUT_CHANNELS);
+        fill_extended_input(&map, &TileOwner::Enemy, &mut input);
+        let offset = EXTENDED_INPUT_CHANNELS - TERRITORY_CHANNELS;
+        assert_eq!(input.get(3, 0, offset), 1.0);
+        assert_eq!(input.get(2, 0, offset), 0.5);
+        assert_eq!(input.get(1, 0, offset + 1), 0.5);
+        //base owner channel is absolute, territory owner is seen from enemy side
+        assert_eq!(input.get(0, 0, 2), 1.0);
+        assert_eq!(input.get(2, 0, offset + 2), 1.0);
+        assert_eq!(input.get(1, 0, offset + 2), -1.0);
+        assert_eq!(input.get(1, 0, offset + 3), 1.0);
+        assert_eq!(input.get(0, 0, offset + 3), 0.0);
+    }
+
+    #[test]
+    fn test_islands() {
+        let map = map_from(&[
+            "M.#..#E.",
+            "..#..#..",
+            "##R####.",
+            ".E...M.."]);
+        let islands = map.islands();
+        let kinds : Vec<IslandKind> = islands.islands.iter().map(|i| i.kind).collect();
+        assert_eq!(kinds, vec![IslandKind::Mine, IslandKind::Neutral, IslandKind::Contested]);
+        assert_eq!(islands.islands[0].tiles.len(), 4);
+        assert_eq!(islands.component[16], NO_ISLAND);
+        assert_eq!(islands.component[18], NO_ISLAND);
+        assert_eq!(islands.island_at(25).unwrap().kind, IslandKind::Contested);
+        //owned recycler counts for its owner next to the 4 island tiles
+        assert_eq!((islands.my_recyclers, islands.enemy_recyclers), (1, 0));
+        assert_eq!(islands.locked_tiles(&TileOwner::Me), 5);
+        assert_eq!(islands.locked_winner(), None);
+    }
+
+    #[test]
+    fn test_locked_islands() {
+        let mut map = map_from(&[
+            "M..#..E",
+            "...#..."]);
+        map.data[0].units = 2;
+        map.data[6].units = -3;
+        let islands = map.islands();
+        assert!(islands.all_locked());
+        assert_eq!(islands.islands[0].my_units, 2);
+        assert_eq!(islands.islands[1].enemy_units, 3);
+        assert_eq!(islands.locked_tiles(&TileOwner::Me), 6);
+        assert_eq!(islands.locked_tiles(&TileOwner::Enemy), 6);
+        assert_eq!(islands.locked_winner(), Some(TileOwner::No));
+
+        //recycler leaves the island but keeps counting for enemy, still a draw
+        let mut recycled = map.clone();
+        recycled.data[5].owner = TileOwner::Enemy;
+        recycled.data[5].recycler = true;
+        let islands = recycled.islands();
+        assert_eq!(islands.islands[1].tiles.len(), 5);
+        assert_eq!(islands.locked_tiles(&TileOwner::Enemy), 6);
+        assert_eq!(islands.locked_winner(), Some(TileOwner::No));
+
+        //grass cuts one enemy tile off into neutral island
+        map.data[4].scrap_amount = 0;
+        map.data[12].scrap_amount = 0;
+        let islands = map.islands();
+        assert_eq!(islands.locked_tiles(&TileOwner::Enemy), 3);
+        assert_eq!(islands.locked_tiles(&TileOwner::No), 1);
+        assert_eq!(islands.locked_winner(), Some(TileOwner::Me));
+    }
+
+    #[test]
+    fn test_recycler_forecast_matches_simulation() {
+        let mut map = map_from(&[
+            "M.3..",
+            "M28r.",
+            "M.9.."]);
+        let forecast = map.forecast_recycler(1, 1, &TileOwner::Me).unwrap();
+        //recycler tile has 2 scrap, so it works two turns
+        assert_eq!(forecast.lifetime, 2);
+        assert_eq!(forecast.total_yield, 10);
+        //(2, 1) is also covered by enemy recycler, own income is still counted
+        assert_eq!(forecast.marginal_yield, 10);
+
+        map.data[6].owner = TileOwner::Me;
+        let start_scrap = map.my_scrap;
+        let build = vec![Action::Build(BuildAction { x : 1, y : 1 })];
+        let wait = vec![];
+        let mut grass = vec![None; map.data.len()];
+        for turn in 1..=12 {
+            map.next_turn(if turn == 1 { &build } else { &wait }, &wait);
+            for (idx, tile) in map.data.iter().enumerate() {
+                if tile.scrap_amount == 0 && grass[idx].is_none() {
+                    grass[idx] = Some(turn);
+                }
+            }
+        }
+        assert_eq!(map.my_scrap - start_scrap + 10 - 12 * 10, forecast.marginal_yield);
+        for (idx, turn) in forecast.grass_turns.iter() {
+            assert_eq!(grass[*idx], *turn);
+        }
+        assert_eq!(forecast.grass_turns[0], (6, Some(2)));
+        assert_eq!(forecast.grass_turns[4], (11, None));
+    }
+
+    #[test]
+    fn test_recycler_forecast_overlap_and_cut_off() {
+        let map = map_from(&[
+            "MM1MM",
+            "R#.#M",
+            ".M.MM"]);
+        //(0, 2) tile is shared with existing own recycler, counted once
+        let forecast = map.forecast_recycler(1, 2, &TileOwner::Me).unwrap();
+        assert_eq!(forecast.total_yield, 5 + 5 + 5);
+        assert_eq!(forecast.marginal_yield, 5 + 5);
+
+        //middle recycler eats (2, 0) and (2, 2), which link (1, 0) and (1, 2) to the rest
+        let mut map = map;
+        map.data[1].units = 3;
+        let forecast = map.forecast_recycler(2, 1, &TileOwner::Me).unwrap();
+        assert_eq!(forecast.cut_off_tiles, vec![1, 11]);
+        assert_eq!(forecast.cut_off_units, 3);
+        assert_eq!(map.forecast_recycler(1, 1, &TileOwner::Me).map(|f| f.lifetime), None);
+    }
+}
@@ -0,0 +1,412 @@
+use std::ops::{BitAnd, BitOr, Not};
+use crate::{Action, Map, Pathfinder, TVec2, Tile, TileOwner};
+
+//largest CodinGame map is 24x12
+pub const WORDS : usize = 5;
+pub const MAX_TILES : usize = WORDS * 64;
+
+#[derive(Default, Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Bits {
+    pub words : [u64; WORDS]
+}
+
+impl Bits {
+    #[inline(always)]
+    pub fn get(&self, idx : usize) -> bool {
+        self.words[idx / 64] >> (idx % 64) & 1 != 0
+    }
+
+    #[inline(always)]
+    pub fn set(&mut self, idx : usize, val : bool) {
+        let bit = 1u64 << (idx % 64);
+        if val {
+            self.words[idx / 64] |= bit;
+        } else {
+            self.words[idx / 64] &= !bit;
+        }
+    }
+
+    pub fn first_n(n : usize) -> Self {
+        let mut res = Self::default();
+        for idx in 0..n {
+            res.set(idx, true);
+        }
+        res
+    }
+
+    pub fn count(&self) -> usize {
+        self.words.iter().map(|w| w.count_ones() as usize).sum()
+    }
+
+    //moves bit idx to idx + k, k < 64
+    pub fn shl(&self, k : usize) -> Self {
+        let mut res = Self::default();
+        for i in 0..WORDS {
+            res.words[i] = self.words[i] << k;
+            if i > 0 && k > 0 {
+                res.words[i] |= self.words[i - 1] >> (64 - k);
+            }
+        }
+        res
+    }
+
+    //moves bit idx to idx - k, k < 64
+    pub fn shr(&self, k : usize) -> Self {
+        let mut res = Self::default();
+        for i in 0..WORDS {
+            res.words[i] = self.words[i] >> k;
+            if i + 1 < WORDS && k > 0 {
+                res.words[i] |= self.words[i + 1] << (64 - k);
+            }
+        }
+        res
+    }
+
+    pub fn ones(&self) -> impl Iterator<Item = usize> + '_ {
+        self.words.iter().enumerate().flat_map(|(i, word)| {
+            let mut w = *word;
+            std::iter::from_fn(move || {
+                if w == 0 {
+                    return None;
+                }
+                let bit = w.trailing_zeros() as usize;
+                w &= w - 1;
+                Some(i * 64 + bit)
+            })
+        })
+    }
+}
+
+impl BitAnd for Bits {
+    type Output = Bits;
+    fn bitand(mut self, rhs : Bits) -> Bits {
+        for i in 0..WORDS {
+            self.words[i] &= rhs.words[i];
+        }
+        self
+    }
+}
+
+impl BitOr for Bits {
+    type Output = Bits;
+    fn bitor(mut self, rhs : Bits) -> Bits {
+        for i in 0..WORDS {
+            self.words[i] |= rhs.words[i];
+        }
+        self
+    }
+}
+
+impl Not for Bits {
+    type Output = Bits;
+    fn not(mut self) -> Bits {
+        for i in 0..WORDS {
+            self.words[i] = !self.words[i];
+        }
+        self
+    }
+}
+
+//compact copy of Map state with the same turn rules, fixed size so clone is one memcpy
+//pathfinder is not part of state and is passed to next_turn as scratch
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct BitState {
+    pub w : usize,
+    pub h : usize,
+    pub mine : Bits,
+    pub enemy : Bits,
+    pub recycler : Bits,
+    //scrap_amount == 0
+    pub grass : Bits,
+    pub scrap : [u8; MAX_TILES],
+    //u16 holds any stack reachable in a game, larger values are clamped
+    pub my_units : [u16; MAX_TILES],
+    pub enemy_units : [u16; MAX_TILES],
+    pub my_scrap : i32,
+    pub enemy_scrap : i32,
+    board : Bits,
+    not_first_col : Bits,
+    not_last_col : Bits
+}
+
+impl BitState {
+    pub fn from_map(map : &Map) -> Self {
+        let n = map.w * map.h;
+        assert!(n <= MAX_TILES && map.w < 64, "Map is too large for bitboard");
+        let mut res = Self {
+            w : map.w,
+            h : map.h,
+            mine : Bits::default(),
+            enemy : Bits::default(),
+            recycler : Bits::default(),
+            grass : Bits::default(),
+            scrap : [0; MAX_TILES],
+            my_units : [0; MAX_TILES],
+            enemy_units : [0; MAX_TILES],
+            my_scrap : map.my_scrap,
+            enemy_scrap : map.enemy_scrap,
+            board : Bits::first_n(n),
+            not_first_col : Bits::default(),
+            not_last_col : Bits::default()
+        };
+        for (idx, tile) in map.data.iter().enumerate() {
+            res.mine.set(idx, tile.owner == TileOwner::Me);
+            res.enemy.set(idx, tile.owner == TileOwner::Enemy);
+            res.recycler.set(idx, tile.recycler);
+            res.grass.set(idx, tile.scrap_amount == 0);
+            res.scrap[idx] = tile.scrap_amount.clamp(0, 255) as u8;
+            res.my_units[idx] = tile.units.clamp(0, u16::MAX as i32) as u16;
+            res.enemy_units[idx] = (-tile.units).clamp(0, u16::MAX as i32) as u16;
+            res.not_first_col.set(idx, idx % map.w != 0);
+            res.not_last_col.set(idx, idx % map.w != map.w - 1);
+        }
+        res
+    }
+
+    pub fn to_map(&self) -> Map {
+        let n = self.w * self.h;
+        let data = (0..n).map(|idx| Tile {
+            scrap_amount : self.scrap[idx] as i32,
+            recycler : self.recycler.get(idx),
+            units : self.units(idx),
+            delta_units : 0,
+            owner : self.owner(idx)
+        }).collect();
+
+        Map::new(data, self.w, self.h, self.my_scrap, self.enemy_scrap)
+    }
+
+    pub fn owner(&self, idx : usize) -> TileOwner {
+        if self.mine.get(idx) {
+            TileOwner::Me
+        } else if self.enemy.get(idx) {
+            TileOwner::Enemy
+        } else {
+            TileOwner::No
+        }
+    }
+
+    //signed like Tile::units, positive for my units
+    #[inline(always)]
+    pub fn units(&self, idx : usize) -> i32 {
+        self.my_units[idx] as i32 - self.enemy_units[idx] as i32
+    }
+
+    pub fn tile_count(&self, owner : &TileOwner) -> usize {
+        let owned = match owner {
+            TileOwner::Me => {self.mine}
+            TileOwner::Enemy => {self.enemy}
+            TileOwner::No => {self.board & !(self.mine | self.enemy)}
+        };
+        (owned & !self.grass).count()
+    }
+
+    //same phases as Map::next_turn
+    pub fn next_turn(&mut self, my_actions : &[Action], enemy_actions : &[Action], pathfinder : &mut Pathfinder) {
+        self.build(my_actions, &TileOwner::Me);
+        self.build(enemy_actions, &TileOwner::Enemy);
+
+        let mut delta = [0i32; MAX_TILES];
+        let walls = self.recycler | self.grass;
+        pathfinder.update_walls((0..self.w * self.h).map(|idx| walls.get(idx)));
+        self.move_spawn(my_actions, &TileOwner::Me, &mut delta, pathfinder);
+        self.move_spawn(enemy_actions, &TileOwner::Enemy, &mut delta, pathfinder);
+
+        self.tile_process(&delta);
+        self.recycler_process();
+
+        self.my_scrap += 10;
+        self.enemy_scrap += 10;
+    }
+
+    fn build(&mut self, actions : &[Action], side : &TileOwner) {
+        let (owned, units, scrap) = match side {
+            TileOwner::Me => {(self.mine, &self.my_units, &mut self.my_scrap)}
+            _ => {(self.enemy, &self.enemy_units, &mut self.enemy_scrap)}
+        };
+        for a in actions.iter() {
+            if *scrap < 10 {
+                break;
+            }
+            if let Action::Build(build) = a {
+                let idx = build.y * self.w + build.x;
+                if owned.get(idx) && units[idx] == 0 {
+                    self.recycler.set(idx, true);
+                    *scrap -= 10;
+                }
+            }
+        }
+    }
+
+    fn move_spawn(&mut self, actions : &[Action], side : &TileOwner, delta : &mut [i32; MAX_TILES], pathfinder : &mut Pathfinder) {
+        let (owned, units, scrap, sign) = match side {
+            TileOwner::Me => {(self.mine, &mut self.my_units, &mut self.my_scrap, 1)}
+            _ => {(self.enemy, &mut self.enemy_units, &mut self.enemy_scrap, -1)}
+        };
+        for a in actions.iter() {
+            if let Action::Move(mv) = a {
+                let idx = mv.fromY * self.w + mv.fromX;
+                if owned.get(idx) && units[idx] > 0 {
+                    let move_amount = (units[idx] as u32).min(mv.amount);
+                    let dst = pathfinder.find_path(&TVec2::new(mv.fromX, mv.fromY), &TVec2::new(mv.toX, mv.toY));
+                    delta[dst.y * self.w + dst.x] += sign * move_amount as i32;
+                    units[idx] -= move_amount as u16;
+                }
+            } else if let Action::Spawn(sp) = a {
+                if sp.amount > 0 {
+                    let cost = sp.amount as i32 * 10;
+                    if *scrap >= cost {
+                        delta[sp.y * self.w + sp.x] += sign * sp.amount as i32;
+                        *scrap -= cost;
+                    }
+                }
+            }
+        }
+    }
+
+    fn tile_process(&mut self, delta : &[i32; MAX_TILES]) {
+        for (idx, d) in delta.iter().enumerate().take(self.w * self.h) {
+            if *d == 0 {
+                continue;
+            }
+            let units = self.units(idx) + d;
+            self.my_units[idx] = units.clamp(0, u16::MAX as i32) as u16;
+            self.enemy_units[idx] = (-units).clamp(0, u16::MAX as i32) as u16;
+            if units != 0 {
+                self.mine.set(idx, units > 0);
+                self.enemy.set(idx, units < 0);
+            }
+        }
+    }
+
+    //tiles covered by recyclers in set, recycler tile itself included
+    fn coverage(&self, recyclers : Bits) -> Bits {
+        (recyclers
+            | (recyclers.shl(1) & self.not_first_col)
+            | (recyclers.shr(1) & self.not_last_col)
+            | recyclers.shl(self.w)
+            | recyclers.shr(self.w))
+            & self.board
+    }
+
+    fn recycler_process(&mut self) {
+        //recycler on tile not owned by me works for enemy, as in Map
+        let by_me = self.coverage(self.recycler & self.mine);
+        let by_enemy = self.coverage(self.recycler & !self.mine);
+
+        for idx in ((by_me | by_enemy) & !self.grass).ones() {
+            self.scrap[idx] -= 1;
+            if by_me.get(idx) {
+                self.my_scrap += 1;
+            }
+            if by_enemy.get(idx) {
+                self.enemy_scrap += 1;
+            }
+            if self.scrap[idx] == 0 {
+                self.grass.set(idx, true);
+                self.mine.set(idx, false);
+                self.enemy.set(idx, false);
+                self.recycler.set(idx, false);
+                self.my_units[idx] = 0;
+                self.enemy_units[idx] = 0;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::Rng;
+    use rand::rngs::StdRng;
+    use crate::{BuildAction, MoveAction, SpawnAction};
+    use crate::rng::stream_rng;
+
+    fn random_map(rnd : &mut StdRng, max_units : i32, max_scrap : i32) -> Map {
+        let w = rnd.gen_range(3..=12);
+        let h = rnd.gen_range(2..=8);
+        let mut data = format!("{} {};{} {}", w, h, rnd.gen_range(0..max_scrap), rnd.gen_range(0..max_scrap));
+        for _ in 0..w * h {
+            let scrap = if rnd.gen_bool(0.15) { 0 } else { rnd.gen_range(1..=10) };
+            let owner = if scrap == 0 { -1 } else { rnd.gen_range(-1..=1) };
+            let units = if owner == -1 { 0 } else { rnd.gen_range(0..max_units) };
+            let recycler = (scrap > 0 && units == 0 && rnd.gen_bool(0.1)) as i32;
+            data.push_str(&format!(";{} {} {} {} 0 0 0", scrap, owner, units, recycler));
+        }
+        Map::load(data)
+    }
+
+    //mostly sensible actions with some invalid ones to cover rule corner cases
+    fn random_actions(map : &Map, side : &TileOwner, max_spawn : u32, rnd : &mut StdRng) -> Vec<Action> {
+        let mut res = vec![];
+        for idx in 0..map.data.len() {
+            let tile = &map.data[idx];
+            let (x, y) = (idx % map.w, idx / map.w);
+            if tile.owner != *side && !rnd.gen_bool(0.02) {
+                continue;
+            }
+            if rnd.gen_bool(0.6) {
+                res.push(Action::Move(MoveAction {
+                    amount : rnd.gen_range(1..=tile.units.unsigned_abs() + 1),
+                    fromX : x,
+                    fromY : y,
+                    toX : rnd.gen_range(0..map.w),
+                    toY : rnd.gen_range(0..map.h)
+                }));
+            }
+            if rnd.gen_bool(0.15) {
+                res.push(Action::Spawn(SpawnAction { amount : rnd.gen_range(0..max_spawn), x, y }));
+            }
+            if rnd.gen_bool(0.05) {
+                res.push(Action::Build(BuildAction { x, y }));
+            }
+        }
+        res
+    }
+
+    #[test]
+    fn test_bits() {
+        let mut bits = Bits::default();
+        bits.set(3, true);
+        bits.set(63, true);
+        bits.set(200, true);
+        assert_eq!(bits.ones().collect::<Vec<_>>(), vec![3, 63, 200]);
+        assert_eq!(bits.shl(12).ones().collect::<Vec<_>>(), vec![15, 75, 212]);
+        assert_eq!(bits.shr(4).ones().collect::<Vec<_>>(), vec![59, 196]);
+        assert_eq!((bits & !Bits::first_n(64)).count(), 1);
+    }
+
+    fn check_games(games : u64, max_units : i32, max_scrap : i32, max_spawn : u32) {
+        for game in 0..games {
+            let mut rnd = stream_rng(game, max_units as u64);
+            let mut map = random_map(&mut rnd, max_units, max_scrap);
+            let mut state = BitState::from_map(&map);
+            let mut pathfinder = Pathfinder::new(map.w, map.h);
+            assert_eq!(BitState::from_map(&state.to_map()), state);
+
+            for turn in 0..30 {
+                let my = random_actions(&map, &TileOwner::Me, max_spawn, &mut rnd);
+                let enemy = random_actions(&map, &TileOwner::Enemy, max_spawn, &mut rnd);
+                map.next_turn(&my, &enemy);
+                state.next_turn(&my, &enemy, &mut pathfinder);
+                assert_eq!(BitState::from_map(&map), state, "game {} turn {}", game, turn);
+                //from_map would clamp the same way, so units are compared on Map side too
+                let units : Vec<i32> = map.data.iter().map(|t| t.units).collect();
+                let state_units : Vec<i32> = state.to_map().data.iter().map(|t| t.units).collect();
+                assert_eq!(units, state_units, "game {} turn {}", game, turn);
+                assert_eq!(map.tile_count(&TileOwner::Me), state.tile_count(&TileOwner::Me));
+            }
+        }
+    }
+
+    #[test]
+    fn test_bitstate_matches_map() {
+        check_games(2000, 3, 60, 3);
+    }
+
+    //stacks around and above 255, where u8 storage used to clamp
+    #[test]
+    fn test_large_stacks() {
+        check_games(200, 400, 6000, 200);
+    }
+}
@@ -0,0 +1,456 @@
+use rand::Rng;
+use crate::mutation::gaussian;
+
+//covariance model, full matrix with eigendecomposition for small dimension,
+//diagonal (sep-CMA) otherwise
+#[derive(Clone)]
+pub enum Covariance {
+    Full {
+        c : Vec<f64>,
+        b : Vec<f64>,
+        d : Vec<f64>,
+        eigen_eval : usize
+    },
+    Diagonal {
+        c : Vec<f64>
+    }
+}
+
+#[derive(Clone)]
+pub struct CmaEsConfig {
+    //use full covariance up to this dimension
+    pub full_limit : usize,
+    pub sigma : f64,
+    //population size, 0 for default 4 + 3 ln(n)
+    pub lambda : usize,
+    //restart when best cost did not improve for this many generations, 0 for default
+    pub stagnation : usize,
+    pub tol_sigma : f64,
+    //population grows by this factor on every restart (IPOP)
+    pub restart_factor : usize
+}
+
+impl Default for CmaEsConfig {
+    fn default() -> Self {
+        Self {
+            full_limit : 200,
+            sigma : 0.3,
+            lambda : 0,
+            stagnation : 0,
+            tol_sigma : 1e-9,
+            restart_factor : 2
+        }
+    }
+}
+
+//minimizes cost with ask / tell interface
+#[derive(Clone)]
+pub struct CmaEs {
+    pub config : CmaEsConfig,
+    pub dim : usize,
+    pub start : Vec<f64>,
+    pub mean : Vec<f64>,
+    pub sigma : f64,
+    pub lambda : usize,
+    pub mu : usize,
+    pub weights : Vec<f64>,
+    pub mueff : f64,
+    pub cc : f64,
+    pub cs : f64,
+    pub c1 : f64,
+    pub cmu : f64,
+    pub damps : f64,
+    pub chi_n : f64,
+    pub pc : Vec<f64>,
+    pub ps : Vec<f64>,
+    pub cov : Covariance,
+    pub generation : usize,
+    //generations since last restart, evolution path normalization depends on run age
+    pub run_generation : usize,
+    pub evaluations : usize,
+    pub restarts : usize,
+    pub best : Vec<f64>,
+    pub best_cost : f64,
+    //best cost of current run and generation it was reached
+    pub run_best_cost : f64,
+    pub run_best_gen : usize,
+    samples_y : Vec<Vec<f64>>
+}
+
+impl CmaEs {
+    pub fn new(start : &[f32], config : CmaEsConfig) -> Self {
+        let start : Vec<f64> = start.iter().map(|v| *v as f64).collect();
+        let lambda = config.lambda;
+        let mut res = Self {
+            dim : start.len(),
+            mean : start.clone(),
+            best : start.clone(),
+            start,
+            sigma : config.sigma,
+            config,
+            lambda : 0,
+            mu : 0,
+            weights : vec![],
+            mueff : 0.0,
+            cc : 0.0,
+            cs : 0.0,
+            c1 : 0.0,
+            cmu : 0.0,
+            damps : 0.0,
+            chi_n : 0.0,
+            pc : vec![],
+            ps : vec![],
+            cov : Covariance::Diagonal { c : vec![] },
+            generation : 0,
+            run_generation : 0,
+            evaluations : 0,
+            restarts : 0,
+            best_cost : f64::INFINITY,
+            run_best_cost : f64::INFINITY,
+            run_best_gen : 0,
+            samples_y : vec![]
+        };
+        res.init_run(lambda);
+        res
+    }
+
+    //resets strategy state for new run with given population size, 0 for default
+    pub fn init_run(&mut self, lambda : usize) {
+        let n = self.dim as f64;
+        self.lambda = if lambda == 0 {
+            4 + (3.0 * n.ln()).floor() as usize
+        } else {
+            lambda
+        };
+        self.mu = self.lambda / 2;
+        let raw : Vec<f64> = (0..self.mu).map(|i| (self.mu as f64 + 0.5).ln() - ((i + 1) as f64).ln()).collect();
+        let sum : f64 = raw.iter().sum();
+        self.weights = raw.iter().map(|w| w / sum).collect();
+        self.mueff = 1.0 / self.weights.iter().map(|w| w * w).sum::<f64>();
+
+        let mueff = self.mueff;
+        self.cc = (4.0 + mueff / n) / (n + 4.0 + 2.0 * mueff / n);
+        self.cs = (mueff + 2.0) / (n + mueff + 5.0);
+        self.c1 = 2.0 / ((n + 1.3).powi(2) + mueff);
+        self.cmu = (1.0 - self.c1).min(2.0 * (mueff - 2.0 + 1.0 / mueff) / ((n + 2.0).powi(2) + mueff));
+        self.damps = 1.0 + 2.0 * (((mueff - 1.0) / (n + 1.0)).sqrt() - 1.0).max(0.0) + self.cs;
+        self.chi_n = n.sqrt() * (1.0 - 1.0 / (4.0 * n) + 1.0 / (21.0 * n * n));
+
+        self.cov = if self.dim <= self.config.full_limit {
+            let mut eye = vec![0.0; self.dim * self.dim];
+            for i in 0..self.dim {
+                eye[i * self.dim + i] = 1.0;
+            }
+            Covariance::Full { c : eye.clone(), b : eye, d : vec![1.0; self.dim], eigen_eval : 0 }
+        } else {
+            //sep-CMA learning rates
+            let k = (n + 2.0) / 3.0;
+            self.c1 *= k;
+            self.cmu = (self.cmu * k).min(1.0 - self.c1);
+            Covariance::Diagonal { c : vec![1.0; self.dim] }
+        };
+
+        self.pc = vec![0.0; self.dim];
+        self.ps = vec![0.0; self.dim];
+        self.sigma = self.config.sigma;
+        self.run_best_cost = f64::INFINITY;
+        self.run_best_gen = self.generation;
+        self.run_generation = 0;
+    }
+
+    pub fn is_full(&self) -> bool {
+        matches!(self.cov, Covariance::Full { .. })
+    }
+
+    pub fn ask<R : Rng + ?Sized>(&mut self, rng : &mut R) -> Vec<Vec<f32>> {
+        self.samples_y.clear();
+        let mut res = vec![];
+        for _ in 0..self.lambda {
+            let z : Vec<f64> = (0..self.dim).map(|_| gaussian(rng) as f64).collect();
+            let y = match &self.cov {
+                Covariance::Full { b, d, .. } => {
+                    let dz : Vec<f64> = z.iter().zip(d.iter()).map(|(z, d)| z * d).collect();
+                    (0..self.dim).map(|i| {
+                        let row = &b[i * self.dim..(i + 1) * self.dim];
+                        row.iter().zip(dz.iter()).map(|(b, v)| b * v).sum()
+                    }).collect()
+                }
+                Covariance::Diagonal { c } => z.iter().zip(c.iter()).map(|(z, c)| z * c.sqrt()).collect::<Vec<f64>>()
+            };
+            res.push(self.mean.iter().zip(y.iter()).map(|(m, y)| (m + self.sigma * y) as f32).collect());
+            self.samples_y.push(y);
+        }
+        res
+    }
+
+    //C^-1/2 * v
+    fn inv_sqrt_mul(&self, v : &[f64]) -> Vec<f64> {
+        match &self.cov {
+            Covariance::Full { b, d, .. } => {
+                let n = self.dim;
+                let mut bt_v = vec![0.0; n];
+                for (j, val) in bt_v.iter_mut().enumerate() {
+                    let mut sum = 0.0;
+                    for i in 0..n {
+                        sum += b[i * n + j] * v[i];
+                    }
+                    *val = sum / d[j];
+                }
+                (0..n).map(|i| b[i * n..(i + 1) * n].iter().zip(bt_v.iter()).map(|(b, v)| b * v).sum()).collect()
+            }
+            Covariance::Diagonal { c } => v.iter().zip(c.iter()).map(|(v, c)| v / c.sqrt()).collect()
+        }
+    }
+
+    //cost of every sample from last ask, lower is better
+    pub fn tell(&mut self, costs : &[f64], samples : &[Vec<f32>]) {
+        assert_eq!(costs.len(), self.samples_y.len(), "tell needs cost for every sample");
+        let n = self.dim;
+        self.generation += 1;
+        self.run_generation += 1;
+        self.evaluations += costs.len();
+
+        let mut order : Vec<usize> = (0..costs.len()).collect();
+        order.sort_by(|a, b| costs[*a].total_cmp(&costs[*b]));
+
+        let best_idx = order[0];
+        if costs[best_idx] < self.best_cost {
+            self.best_cost = costs[best_idx];
+            self.best = samples[best_idx].iter().map(|v| *v as f64).collect();
+        }
+        if costs[best_idx] < self.run_best_cost {
+            self.run_best_cost = costs[best_idx];
+            self.run_best_gen = self.generation;
+        }
+
+        let mut y_w = vec![0.0; n];
+        for (w, idx) in self.weights.iter().zip(order.iter()) {
+            for (acc, y) in y_w.iter_mut().zip(self.samples_y[*idx].iter()) {
+                *acc += w * y;
+            }
+        }
+        for (m, y) in self.mean.iter_mut().zip(y_w.iter()) {
+            *m += self.sigma * y;
+        }
+
+        let cs_k = (self.cs * (2.0 - self.cs) * self.mueff).sqrt();
+        let c_inv_y = self.inv_sqrt_mul(&y_w);
+        for (ps, v) in self.ps.iter_mut().zip(c_inv_y.iter()) {
+            *ps = (1.0 - self.cs) * *ps + cs_k * v;
+        }
+        let ps_norm = self.ps.iter().map(|v| v * v).sum::<f64>().sqrt();
+        let ps_k = (1.0 - (1.0 - self.cs).powi(2 * self.run_generation as i32)).sqrt();
+        let hsig = ps_norm / ps_k / self.chi_n < 1.4 + 2.0 / (n as f64 + 1.0);
+        let hsig_k = if hsig { 1.0 } else { 0.0 };
+
+        let cc_k = (self.cc * (2.0 - self.cc) * self.mueff).sqrt();
+        for (pc, y) in self.pc.iter_mut().zip(y_w.iter()) {
+            *pc = (1.0 - self.cc) * *pc + hsig_k * cc_k * y;
+        }
+
+        let old_k = 1.0 - self.c1 - self.cmu + (1.0 - hsig_k) * self.c1 * self.cc * (2.0 - self.cc);
+        let selected : Vec<(f64, &Vec<f64>)> = self.weights.iter().zip(order.iter())
+            .map(|(w, idx)| (*w, &self.samples_y[*idx]))
+            .collect();
+        match &mut self.cov {
+            Covariance::Full { c, .. } => {
+                for i in 0..n {
+                    for j in 0..=i {
+                        let mut rank_mu = 0.0;
+                        for (w, y) in selected.iter() {
+                            rank_mu += w * y[i] * y[j];
+                        }
+                        let val = old_k * c[i * n + j] + self.c1 * self.pc[i] * self.pc[j] + self.cmu * rank_mu;
+                        c[i * n + j] = val;
+                        c[j * n + i] = val;
+                    }
+                }
+            }
+            Covariance::Diagonal { c } => {
+                for i in 0..n {
+                    let mut rank_mu = 0.0;
+                    for (w, y) in selected.iter() {
+                        rank_mu += w * y[i] * y[i];
+                    }
+                    c[i] = old_k * c[i] + self.c1 * self.pc[i] * self.pc[i] + self.cmu * rank_mu;
+                }
+            }
+        }
+
+        self.sigma *= ((self.cs / self.damps) * (ps_norm / self.chi_n - 1.0)).exp();
+        self.update_eigen(false);
+
+        if self.should_restart() {
+            self.restart();
+        }
+    }
+
+    fn update_eigen(&mut self, force : bool) {
+        let n = self.dim;
+        let lazy_gap = (self.lambda as f64 / (self.c1 + self.cmu) / n as f64 / 10.0) as usize;
+        let evaluations = self.evaluations;
+        if let Covariance::Full { c, b, d, eigen_eval } = &mut self.cov {
+            if !force && evaluations - *eigen_eval <= lazy_gap {
+                return;
+            }
+            *eigen_eval = evaluations;
+            let (vals, vecs) = jacobi_eigen(c, n);
+            *b = vecs;
+            *d = vals.iter().map(|v| v.max(1e-20).sqrt()).collect();
+        }
+    }
+
+    fn max_std(&self) -> f64 {
+        let max_var = match &self.cov {
+            Covariance::Full { d, .. } => d.iter().map(|v| v * v).fold(0.0, f64::max),
+            Covariance::Diagonal { c } => c.iter().copied().fold(0.0, f64::max)
+        };
+        self.sigma * max_var.sqrt()
+    }
+
+    fn should_restart(&self) -> bool {
+        let stagnation = if self.config.stagnation == 0 {
+            10 + (30 * self.dim) / self.lambda
+        } else {
+            self.config.stagnation
+        };
+        self.max_std() < self.config.tol_sigma
+            || !self.sigma.is_finite()
+            || self.generation - self.run_best_gen > stagnation
+    }
+
+    //IPOP restart from best solution with larger population
+    pub fn restart(&mut self) {
+        self.restarts += 1;
+        self.mean = self.best.clone();
+        let lambda = self.lambda * self.config.restart_factor.max(1);
+        self.init_run(lambda);
+    }
+}
+
+//eigenvalues and column eigenvectors (row major n x n) of symmetric matrix, cyclic Jacobi
+pub fn jacobi_eigen(m : &[f64], n : usize) -> (Vec<f64>, Vec<f64>) {
+    let mut a = m.to_vec();
+    let mut v = vec![0.0; n * n];
+    for i in 0..n {
+        v[i * n + i] = 1.0;
+    }
+
+    for _ in 0..100 {
+        let mut off = 0.0;
+        for i in 0..n {
+            for j in 0..i {
+                off += a[i * n + j] * a[i * n + j];
+            }
+        }
+        if off < 1e-22 {
+            break;
+        }
+
+        for p in 0..n {
+            for q in p + 1..n {
+                let apq = a[p * n + q];
+                if apq.abs() < 1e-300 {
+                    continue;
+                }
+                let theta = (a[q * n + q] - a[p * n + p]) / (2.0 * apq);
+                let t = theta.signum() / (theta.abs() + (theta * theta + 1.0).sqrt());
+                let t = if theta == 0.0 { 1.0 } else { t };
+                let c = 1.0 / (t * t + 1.0).sqrt();
+                let s = t * c;
+
+                for k in 0..n {
+                    let akp = a[k * n + p];
+                    let akq = a[k * n + q];
+                    a[k * n + p] = c * akp - s * akq;
+                    a[k * n + q] = s * akp + c * akq;
+                }
+                for k in 0..n {
+                    let apk = a[p * n + k];
+                    let aqk = a[q * n + k];
+                    a[p * n + k] = c * apk - s * aqk;
+                    a[q * n + k] = s * apk + c * aqk;
+                }
+                for k in 0..n {
+                    let vkp = v[k * n + p];
+                    let vkq = v[k * n + q];
+                    v[k * n + p] = c * vkp - s * vkq;
+                    v[k * n + q] = s * vkp + c * vkq;
+                }
+            }
+        }
+    }
+
+    ((0..n).map(|i| a[i * n + i]).collect(), v)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::SeedableRng;
+    use rand::rngs::StdRng;
+
+    //rotated ill conditioned ellipsoid, needs full covariance adaptation
+    fn ellipsoid(x : &[f32]) -> f64 {
+        let n = x.len();
+        let mut res = 0.0;
+        for i in 0..n {
+            let rotated = x[i] as f64 + x[(i + 1) % n] as f64;
+            res += 10f64.powf(3.0 * i as f64 / (n - 1) as f64) * rotated * rotated;
+        }
+        res
+    }
+
+    fn run(config : CmaEsConfig, generations : usize) -> CmaEs {
+        let mut rng = StdRng::seed_from_u64(5);
+        let mut es = CmaEs::new(&[1.0; 8], config);
+        for _ in 0..generations {
+            let samples = es.ask(&mut rng);
+            let costs : Vec<f64> = samples.iter().map(|s| ellipsoid(s)).collect();
+            es.tell(&costs, &samples);
+        }
+        es
+    }
+
+    #[test]
+    fn test_jacobi() {
+        let m = [4.0, 1.0, 0.0, 1.0, 3.0, 1.0, 0.0, 1.0, 2.0];
+        let (vals, vecs) = jacobi_eigen(&m, 3);
+        for k in 0..3 {
+            for i in 0..3 {
+                let mv : f64 = (0..3).map(|j| m[i * 3 + j] * vecs[j * 3 + k]).sum();
+                assert!((mv - vals[k] * vecs[i * 3 + k]).abs() < 1e-9);
+            }
+        }
+    }
+
+    #[test]
+    fn test_full_and_sep() {
+        let full = run(CmaEsConfig::default(), 400);
+        assert!(full.is_full());
+        assert!(full.best_cost < 1e-6, "full {}", full.best_cost);
+
+        let sep = run(CmaEsConfig { full_limit : 0, ..CmaEsConfig::default() }, 400);
+        assert!(!sep.is_full());
+        assert!(sep.best_cost < 1e-2, "sep {}", sep.best_cost);
+    }
+
+    #[test]
+    fn test_restart_on_stagnation() {
+        let mut rng = StdRng::seed_from_u64(1);
+        let mut es = CmaEs::new(&[0.0; 4], CmaEsConfig { stagnation : 5, ..CmaEsConfig::default() });
+        let lambda = es.lambda;
+        for _ in 0..7 {
+            let samples = es.ask(&mut rng);
+            es.tell(&vec![1.0; samples.len()], &samples);
+        }
+        assert_eq!(es.restarts, 1);
+        assert_eq!(es.lambda, lambda * 2);
+        //restart happened in generation 7, run age starts again
+        assert_eq!(es.generation, 7);
+        assert_eq!(es.run_generation, 0);
+        let samples = es.ask(&mut rng);
+        es.tell(&vec![1.0; samples.len()], &samples);
+        assert_eq!(es.run_generation, 1);
+    }
+}
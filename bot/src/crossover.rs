@@ -0,0 +1,181 @@
+use std::fmt::{Display, Formatter};
+use rand::Rng;
+use crate::net::{layer_descriptor, Conv2d, Dense, Layer, SimpleNetwork};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CrossoverKind {
+    //every weight from random parent
+    Uniform,
+    //whole Conv2d output filters and Dense rows, other parameters per weight
+    Filter,
+    //whole node parameters from random parent
+    Layer,
+    //child = a + alpha * (b - a) with random alpha
+    Blend
+}
+
+impl CrossoverKind {
+    pub const ALL : [CrossoverKind; 4] = [CrossoverKind::Uniform, CrossoverKind::Filter, CrossoverKind::Layer, CrossoverKind::Blend];
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum CrossoverError {
+    NodeCount(usize, usize),
+    LayerType(usize),
+    ParamShape(usize)
+}
+
+impl Display for CrossoverError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CrossoverError::NodeCount(a, b) => write!(f, "networks have different node count: {} vs {}", a, b),
+            CrossoverError::LayerType(idx) => write!(f, "node {} has different layer type", idx),
+            CrossoverError::ParamShape(idx) => write!(f, "node {} has different parameter shapes", idx)
+        }
+    }
+}
+
+impl std::error::Error for CrossoverError {}
+
+pub fn check_same_architecture(a : &SimpleNetwork, b : &SimpleNetwork) -> Result<(), CrossoverError> {
+    if a.nodes.len() != b.nodes.len() {
+        return Err(CrossoverError::NodeCount(a.nodes.len(), b.nodes.len()));
+    }
+    for (idx, (na, nb)) in a.nodes.iter().zip(b.nodes.iter()).enumerate() {
+        if na.layer.as_ref().as_any().type_id() != nb.layer.as_ref().as_any().type_id() {
+            return Err(CrossoverError::LayerType(idx));
+        }
+        //equal buffer lengths are not enough, Conv2d 8 -> 6 and 6 -> 8 have same weight count
+        let pa = na.layer.params();
+        let pb = nb.layer.params();
+        if pa.len() != pb.len() || pa.iter().zip(pb.iter()).any(|(x, y)| x.len() != y.len())
+            || layer_descriptor(na.layer.as_ref()) != layer_descriptor(nb.layer.as_ref()) {
+            return Err(CrossoverError::ParamShape(idx));
+        }
+    }
+    Ok(())
+}
+
+//size of one output unit per parameter group, whole units are taken from one parent
+fn filter_sizes(layer : &dyn Layer) -> Vec<usize> {
+    if let Some(conv) = layer.as_any().downcast_ref::<Conv2d>() {
+        vec![conv.w * conv.h * conv.in_c]
+    } else if let Some(dense) = layer.as_any().downcast_ref::<Dense>() {
+        vec![dense.in_c, 1]
+    } else {
+        layer.params().iter().map(|_| 1).collect()
+    }
+}
+
+pub fn crossover<R : Rng + ?Sized>(
+    a : &SimpleNetwork,
+    b : &SimpleNetwork,
+    kind : CrossoverKind,
+    rng : &mut R) -> Result<SimpleNetwork, CrossoverError> {
+
+    check_same_architecture(a, b)?;
+    let mut child = a.clone();
+    let alpha : f32 = rng.gen();
+
+    for (node, other) in child.nodes.iter_mut().zip(b.nodes.iter()) {
+        let sizes = filter_sizes(other.layer.as_ref());
+        let take_layer = rng.gen_bool(0.5);
+        let other_params = other.layer.params();
+
+        //Dense bias follows its weight row, so unit choices are shared between groups
+        let mut unit_choice : Vec<bool> = vec![];
+        for (group, (dst, src)) in node.layer.params_mut().into_iter().zip(other_params.iter()).enumerate() {
+            match kind {
+                CrossoverKind::Uniform => {
+                    for (d, s) in dst.iter_mut().zip(src.iter()) {
+                        if rng.gen_bool(0.5) {
+                            *d = *s;
+                        }
+                    }
+                }
+                CrossoverKind::Filter => {
+                    let units = dst.len() / sizes[group];
+                    if group == 0 || unit_choice.len() != units {
+                        unit_choice = (0..units).map(|_| rng.gen_bool(0.5)).collect();
+                    }
+                    for (unit, (d, s)) in dst.chunks_mut(sizes[group]).zip(src.chunks(sizes[group])).enumerate() {
+                        if unit_choice[unit] {
+                            d.copy_from_slice(s);
+                        }
+                    }
+                }
+                CrossoverKind::Layer => {
+                    if take_layer {
+                        dst.copy_from_slice(src);
+                    }
+                }
+                CrossoverKind::Blend => {
+                    for (d, s) in dst.iter_mut().zip(src.iter()) {
+                        *d += alpha * (s - *d);
+                    }
+                }
+            }
+        }
+    }
+
+    Ok(child)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::net::{Node, Padding};
+    use rand::SeedableRng;
+    use rand::rngs::StdRng;
+
+    fn filled(net : &SimpleNetwork, val : f32) -> SimpleNetwork {
+        let mut res = net.clone();
+        for p in res.params_mut() {
+            p.fill(val);
+        }
+        res
+    }
+
+    #[test]
+    fn test_filter_crossover_keeps_kernels() {
+        let base = SimpleNetwork::simple_maker(3, 4, 6, 4, 1, &mut StdRng::seed_from_u64(1));
+        let a = filled(&base, 0.0);
+        let b = filled(&base, 1.0);
+        let mut rng = StdRng::seed_from_u64(3);
+        let child = crossover(&a, &b, CrossoverKind::Filter, &mut rng).unwrap();
+
+        let conv = child.nodes[1].layer.as_any().downcast_ref::<Conv2d>().unwrap();
+        let kernel = conv.w * conv.h * conv.in_c;
+        for filter in conv.weights().chunks(kernel) {
+            assert!(filter.iter().all(|v| *v == filter[0]));
+        }
+
+        let blend = crossover(&a, &b, CrossoverKind::Blend, &mut rng).unwrap();
+        let vals = blend.params();
+        assert!(vals.iter().all(|p| p.iter().all(|v| *v == vals[0][0])));
+    }
+
+    #[test]
+    fn test_mismatched_architecture() {
+        let a = SimpleNetwork::simple_maker(3, 4, 6, 4, 1, &mut StdRng::seed_from_u64(1));
+        let b = SimpleNetwork::simple_maker(3, 4, 8, 4, 1, &mut StdRng::seed_from_u64(2));
+        let c = SimpleNetwork::simple_maker(3, 4, 6, 4, 2, &mut StdRng::seed_from_u64(3));
+        let mut rng = StdRng::seed_from_u64(0);
+
+        assert_eq!(crossover(&a, &b, CrossoverKind::Uniform, &mut rng).err(), Some(CrossoverError::ParamShape(1)));
+        assert_eq!(crossover(&a, &c, CrossoverKind::Uniform, &mut rng).err(), Some(CrossoverError::NodeCount(8, 11)));
+    }
+
+    #[test]
+    fn test_same_size_different_shape() {
+        let conv = |in_c : usize, out_c : usize| SimpleNetwork {
+            nodes : vec![Node::new(Padding::new(1, 1)), Node::new(Conv2d::from_weights(3, 3, in_c, out_c, vec![0.0; 9 * in_c * out_c]))]
+        };
+        assert_eq!(check_same_architecture(&conv(8, 6), &conv(6, 8)), Err(CrossoverError::ParamShape(1)));
+        assert_eq!(check_same_architecture(&conv(8, 6), &conv(8, 6)), Ok(()));
+
+        let mut padded = conv(8, 6);
+        padded.nodes[0] = Node::new(Padding::new(2, 2));
+        assert_eq!(check_same_architecture(&conv(8, 6), &padded), Err(CrossoverError::ParamShape(0)));
+    }
+}
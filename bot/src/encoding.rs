@@ -0,0 +1,143 @@
+use crate::analysis::{Territory, TERRITORY_CHANNELS};
+use crate::net::NetImage;
+use crate::{Action, BuildAction, Map, MoveAction, SpawnAction, TileOwner};
+
+pub const INPUT_CHANNELS : usize = 4;
+pub const ACTION_CHANNELS : usize = 4;
+pub const EXTENDED_INPUT_CHANNELS : usize = INPUT_CHANNELS + TERRITORY_CHANNELS;
+//optional output channel after action channels, its global average is the value head
+pub const VALUE_CHANNEL : usize = ACTION_CHANNELS;
+
+//+1 for side, -1 for opponent
+pub fn side_sign(side : &TileOwner) -> f32 {
+    match side {
+        TileOwner::Me => {1.0}
+        TileOwner::Enemy => {-1.0}
+        TileOwner::No => {panic!("Unsupported owner")}
+    }
+}
+
+//channels: units, scrap, owner, recycler; units are seen from side,
+//owner stays absolute (1 for Me, -1 for Enemy) as existing networks were trained with it
+pub fn fill_input(map : &Map, side : &TileOwner, input : &mut NetImage) {
+    let unit_k = side_sign(side);
+
+    for y in 0..map.h {
+        for x in 0..map.w {
+            let tile = &map.data[y * map.w + x];
+            *input.get_mut(x, y, 0) = tile.units as f32 * unit_k;
+            *input.get_mut(x, y, 1) = tile.scrap_amount as f32;
+
+            let owner = match tile.owner {
+                TileOwner::Me => {1.0}
+                TileOwner::Enemy => {-1.0}
+                TileOwner::No => {0.0}
+            };
+            *input.get_mut(x, y, 2) = owner;
+
+            *input.get_mut(x, y, 3) = match tile.recycler {
+                true => {1.0}
+                false => {0.0}
+            };
+        }
+    }
+}
+
+//fill_input channels followed by territory channels
+pub fn fill_extended_input(map : &Map, side : &TileOwner, input : &mut NetImage) {
+    fill_input(map, side, input);
+    Territory::new(map).fill_channels(side, input, INPUT_CHANNELS);
+}
+
+//channels: spawned units, build flag, moved units along x, moved units along y
+//moves are stored on source tile as amount times direction to target
+pub fn encode_actions(map : &Map, actions : &[Action], target : &mut NetImage) {
+    target.data.fill(0.0);
+    for a in actions {
+        match a {
+            Action::Spawn(sp) => {
+                if sp.x >= map.w || sp.y >= map.h {
+                    continue;
+                }
+                *target.get_mut(sp.x, sp.y, 0) += sp.amount as f32;
+            }
+            Action::Build(b) => {
+                if b.x >= map.w || b.y >= map.h {
+                    continue;
+                }
+                *target.get_mut(b.x, b.y, 1) = 1.0;
+            }
+            Action::Move(mv) => {
+                if mv.fromX >= map.w || mv.fromY >= map.h {
+                    continue;
+                }
+                //f32::signum(0.0) is 1.0, so direction is taken on integers
+                let dx = (mv.toX as i64 - mv.fromX as i64).signum() as f32;
+                let dy = (mv.toY as i64 - mv.fromY as i64).signum() as f32;
+                *target.get_mut(mv.fromX, mv.fromY, 2) += dx * mv.amount as f32;
+                *target.get_mut(mv.fromX, mv.fromY, 3) += dy * mv.amount as f32;
+            }
+        }
+    }
+}
+
+//inverse of encode_actions, only legal looking actions for side are produced
+pub fn decode_actions(map : &Map, side : &TileOwner, out : &NetImage) -> Vec<Action> {
+    let mut res = vec![];
+    let unit_k = side_sign(side) as i32;
+
+    for y in 0..map.h {
+        for x in 0..map.w {
+            let tile = &map.data[y * map.w + x];
+            if tile.owner != *side || tile.scrap_amount == 0 {
+                continue;
+            }
+            let units = tile.units * unit_k;
+
+            if out.get(x, y, 1) > 0.5 && units == 0 && !tile.recycler {
+                res.push(Action::Build(BuildAction { x, y }));
+                continue;
+            }
+
+            let spawn = out.get(x, y, 0).round();
+            if spawn >= 1.0 && !tile.recycler {
+                res.push(Action::Spawn(SpawnAction { amount : spawn as u32, x, y }));
+            }
+
+            let dx = out.get(x, y, 2);
+            let dy = out.get(x, y, 3);
+            let amount = (dx.abs().max(dy.abs()).round() as i32).min(units);
+            if amount > 0 {
+                let (to_x, to_y) = if dx.abs() >= dy.abs() {
+                    if dx > 0.0 { (map.w - 1, y) } else { (0, y) }
+                } else if dy > 0.0 {
+                    (x, map.h - 1)
+                } else {
+                    (x, 0)
+                };
+                res.push(Action::Move(MoveAction {
+                    amount : amount as u32,
+                    fromX : x,
+                    fromY : y,
+                    toX : to_x,
+                    toY : to_y
+                }));
+            }
+        }
+    }
+    res
+}
+
+//tanh of VALUE_CHANNEL average, None for networks with action channels only
+pub fn decode_value(out : &NetImage) -> Option<f32> {
+    if out.c <= VALUE_CHANNEL {
+        return None;
+    }
+    let mut sum = 0.0;
+    for y in 0..out.h {
+        for x in 0..out.w {
+            sum += out.get(x, y, VALUE_CHANNEL);
+        }
+    }
+    Some((sum / (out.w * out.h) as f32).tanh())
+}
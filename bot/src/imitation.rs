@@ -0,0 +1,251 @@
+use std::fmt::{Display, Formatter};
+use std::path::{Path, PathBuf};
+use crate::encoding::{encode_actions, fill_input, ACTION_CHANNELS, INPUT_CHANNELS};
+use crate::net::{Layer, NetImage};
+use crate::optim::Optimizer;
+use crate::{Action, ActionParseError, Map, TileOwner};
+
+//recorded game: first line is map in Map::load format,
+//every next line is one turn as "my actions|enemy actions"
+pub struct Replay {
+    pub map : String,
+    pub turns : Vec<(Vec<Action>, Vec<Action>)>
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum ReplayError {
+    Io(String),
+    Empty,
+    //turn index of line without '|'
+    Separator(usize),
+    Action(usize, ActionParseError)
+}
+
+impl Display for ReplayError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ReplayError::Io(e) => write!(f, "can not read replay: {}", e),
+            ReplayError::Empty => write!(f, "replay has no map line"),
+            ReplayError::Separator(turn) => write!(f, "turn {} has no '|' between sides", turn),
+            ReplayError::Action(turn, e) => write!(f, "turn {}: {}", turn, e)
+        }
+    }
+}
+
+impl std::error::Error for ReplayError {}
+
+impl Replay {
+    pub fn load(data : &str) -> Result<Self, ReplayError> {
+        let mut lines = data.lines().filter(|l| !l.trim().is_empty());
+        let map = lines.next().ok_or(ReplayError::Empty)?.trim().to_string();
+
+        let mut turns = vec![];
+        for (turn, line) in lines.enumerate() {
+            let (my, enemy) = line.split_once('|').ok_or(ReplayError::Separator(turn))?;
+            let parse = |data : &str| Action::parse_list(data).map_err(|e| ReplayError::Action(turn, e));
+            turns.push((parse(my)?, parse(enemy)?));
+        }
+
+        Ok(Self {
+            map,
+            turns
+        })
+    }
+
+    pub fn load_file<P : AsRef<Path>>(path : P) -> Result<Self, ReplayError> {
+        let data = std::fs::read_to_string(path).map_err(|e| ReplayError::Io(e.to_string()))?;
+        Replay::load(&data)
+    }
+
+    //all *.txt replays from directory, files that fail to load are returned separately
+    pub fn load_dir<P : AsRef<Path>>(path : P) -> (Vec<Self>, Vec<(PathBuf, ReplayError)>) {
+        let mut res = vec![];
+        let mut failed = vec![];
+        if let Ok(entries) = std::fs::read_dir(path) {
+            for entry in entries.flatten() {
+                let path = entry.path();
+                if path.extension().map(|e| e == "txt").unwrap_or(false) {
+                    match Replay::load_file(&path) {
+                        Ok(replay) => {res.push(replay)}
+                        Err(e) => {failed.push((path, e))}
+                    }
+                }
+            }
+        }
+        (res, failed)
+    }
+
+    pub fn save(&self) -> String {
+        let mut res = self.map.clone();
+        res.push('\n');
+        for (my, enemy) in &self.turns {
+            res.push_str(&format!("{}|{}\n", Action::format_list(my), Action::format_list(enemy)));
+        }
+        res
+    }
+}
+
+pub struct Sample {
+    pub input : NetImage,
+    pub target : NetImage
+}
+
+//replays game with simulator and makes one sample per turn from side point of view
+pub fn replay_samples(replay : &Replay, side : &TileOwner) -> Vec<Sample> {
+    let mut map = Map::load(replay.map.clone());
+    let mut res = vec![];
+
+    for (my, enemy) in &replay.turns {
+        let actions = match side {
+            TileOwner::Me => my,
+            _ => enemy
+        };
+
+        let mut input = NetImage::new(map.w, map.h, INPUT_CHANNELS);
+        fill_input(&map, side, &mut input);
+        let mut target = NetImage::new(map.w, map.h, ACTION_CHANNELS);
+        encode_actions(&map, actions, &mut target);
+        res.push(Sample { input, target });
+
+        map.next_turn(my, enemy);
+    }
+    res
+}
+
+//samples from both sides of every replay
+pub fn collect_samples(replays : &[Replay]) -> Vec<Sample> {
+    let mut res = vec![];
+    for replay in replays {
+        res.extend(replay_samples(replay, &TileOwner::Me));
+        res.extend(replay_samples(replay, &TileOwner::Enemy));
+    }
+    res
+}
+
+//one pass of supervised training with mean squared error, returns mean loss per sample
+pub fn train_epoch(net : &mut dyn Layer, samples : &[Sample], optimizer : &mut dyn Optimizer, batch_size : usize) -> f32 {
+    let mut out = NetImage::new(0, 0, 0);
+    let mut grad = NetImage::new(0, 0, 0);
+    let mut inp_grad = NetImage::new(0, 0, 0);
+    let mut total = 0.0;
+
+    net.zero_grads();
+    for (idx, sample) in samples.iter().enumerate() {
+        if out.w != sample.input.w || out.h != sample.input.h {
+            out = net.allocate_output(&sample.input);
+            grad = NetImage::new(out.w, out.h, out.c);
+            inp_grad = NetImage::new(sample.input.w, sample.input.h, sample.input.c);
+        }
+
+        net.process(&sample.input, &mut out);
+        let k = 1.0 / (out.data.len() * batch_size) as f32;
+        for ((g, o), t) in grad.data.iter_mut().zip(out.data.iter()).zip(sample.target.data.iter()) {
+            let diff = o - t;
+            total += diff * diff / out.data.len() as f32;
+            *g = 2.0 * diff * k;
+        }
+        net.backward(&sample.input, &out, &grad, &mut inp_grad);
+
+        if (idx + 1) % batch_size == 0 || idx + 1 == samples.len() {
+            optimizer.step(net);
+        }
+    }
+
+    total / samples.len().max(1) as f32
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::SeedableRng;
+    use rand::rngs::StdRng;
+    use crate::net::SimpleNetwork;
+    use crate::optim::Adam;
+
+    const MAP : &str = "5 3;10 10;5 -1 0 0 0 0 0;5 -1 0 0 0 0 0;5 -1 0 0 0 0 0;5 -1 0 0 0 0 0;5 -1 0 0 0 0 0;\
+5 1 2 0 0 1 0;5 -1 0 0 0 0 0;5 -1 0 0 0 0 0;5 -1 0 0 0 0 0;5 0 2 0 0 0 0;\
+5 -1 0 0 0 0 0;5 -1 0 0 0 0 0;5 -1 0 0 0 0 0;5 -1 0 0 0 0 0;5 -1 0 0 0 0 0";
+
+    fn test_replay() -> Replay {
+        let mut data = format!("{}\n", MAP);
+        data.push_str("MOVE 1 0 1 4 1;SPAWN 1 0 1|MOVE 2 4 1 0 1\n");
+        data.push_str("WAIT|MOVE 1 3 1 0 1;MESSAGE hi\n");
+        Replay::load(&data).unwrap()
+    }
+
+    #[test]
+    fn test_replay_roundtrip() {
+        let replay = test_replay();
+        assert_eq!(replay.turns.len(), 2);
+        assert_eq!(replay.turns[1].0.len(), 0);
+
+        let loaded = Replay::load(&replay.save()).unwrap();
+        assert_eq!(loaded.turns, replay.turns);
+    }
+
+    #[test]
+    fn test_replay_errors() {
+        assert_eq!(Replay::load("\n\n").err(), Some(ReplayError::Empty));
+        let data = format!("{}\nWAIT|WAIT\nMOVE 1 0 1 4 1\n", MAP);
+        assert_eq!(Replay::load(&data).err(), Some(ReplayError::Separator(1)));
+        let data = format!("{}\nMOVE 1 0 x 4 1|WAIT\n", MAP);
+        assert_eq!(Replay::load(&data).err(), Some(ReplayError::Action(0, ActionParseError(String::from("MOVE 1 0 x 4 1")))));
+        assert_eq!(Action::parse_list("SPAWN 1 2").err(), Some(ActionParseError(String::from("SPAWN 1 2"))));
+
+        //bad file is reported, good one is still loaded
+        let dir = std::env::temp_dir().join(format!("replays_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("good.txt"), test_replay().save()).unwrap();
+        std::fs::write(dir.join("bad.txt"), format!("{}\nBUILD|WAIT\n", MAP)).unwrap();
+        let (replays, failed) = Replay::load_dir(&dir);
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(replays.len(), 1);
+        assert_eq!(failed.len(), 1);
+        assert!(failed[0].0.ends_with("bad.txt"));
+    }
+
+    #[test]
+    fn test_fill_input_sides() {
+        let map = Map::load(MAP.to_string());
+        let mut input = NetImage::new(map.w, map.h, INPUT_CHANNELS);
+        fill_input(&map, &TileOwner::Enemy, &mut input);
+        //units flip with side, owner keeps baseline absolute encoding
+        assert_eq!((input.get(0, 1, 0), input.get(4, 1, 0)), (-2.0, 2.0));
+        assert_eq!((input.get(0, 1, 2), input.get(4, 1, 2)), (1.0, -1.0));
+    }
+
+    #[test]
+    fn test_encode_directions() {
+        let map = Map::load(MAP.to_string());
+        let mut target = NetImage::new(map.w, map.h, ACTION_CHANNELS);
+        let actions = Action::parse_list("MOVE 1 1 1 1 0;MOVE 2 2 1 2 2;MOVE 3 3 1 3 1;SPAWN 1 9 9;BUILD 5 0").unwrap();
+        encode_actions(&map, &actions, &mut target);
+        assert_eq!((target.get(1, 1, 2), target.get(1, 1, 3)), (0.0, -1.0));
+        assert_eq!((target.get(2, 1, 2), target.get(2, 1, 3)), (0.0, 2.0));
+        assert_eq!((target.get(3, 1, 2), target.get(3, 1, 3)), (0.0, 0.0));
+        //out of map spawn and build are skipped
+        assert_eq!(target.data.iter().map(|v| v.abs()).sum::<f32>(), 3.0);
+    }
+
+    #[test]
+    fn test_samples_and_training() {
+        let replay = test_replay();
+        let samples = collect_samples(&[replay]);
+        assert_eq!(samples.len(), 4);
+        //first turn, my unit moved right and one spawned on (0, 1)
+        assert_eq!(samples[0].target.get(0, 1, 0), 1.0);
+        assert_eq!(samples[0].target.get(0, 1, 2), 1.0);
+        assert_eq!(samples[0].target.get(0, 1, 3), 0.0);
+        //enemy point of view sees its own units as positive
+        assert_eq!(samples[2].input.get(4, 1, 0), 2.0);
+
+        let mut net = SimpleNetwork::simple_maker(3, INPUT_CHANNELS, 8, ACTION_CHANNELS, 1, &mut StdRng::seed_from_u64(0));
+        let mut adam = Adam::new(1e-2);
+        let first = train_epoch(&mut net, &samples, &mut adam, 2);
+        let mut last = first;
+        for _ in 0..100 {
+            last = train_epoch(&mut net, &samples, &mut adam, 2);
+        }
+        assert!(last < first * 0.5, "loss {} -> {}", first, last);
+    }
+}
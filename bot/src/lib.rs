@@ -1,6 +1,17 @@
 pub mod net;
+pub mod optim;
+pub mod encoding;
+pub mod imitation;
+pub mod mutation;
+pub mod crossover;
+pub mod cmaes;
+pub mod topology;
+pub mod rng;
+pub mod analysis;
+pub mod bitboard;
+pub mod zobrist;
+pub mod search;
 
-use std::collections::VecDeque;
 use std::fmt::Debug;
 use std::{path::Path, io::Read};
 use std::str::FromStr;
@@ -16,7 +27,7 @@ pub enum TileOwner {
     No
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct Tile {
     pub scrap_amount : i32,
     pub recycler : bool,
@@ -37,6 +48,7 @@ impl Default for Tile {
     }
 }
 
+#[derive(Clone, Debug, PartialEq, Eq)]
 pub struct MoveAction {
     pub amount : u32,
     pub fromX : usize,
@@ -45,23 +57,96 @@ pub struct MoveAction {
     pub toY : usize
 }
 
+#[derive(Clone, Debug, PartialEq, Eq)]
 pub struct SpawnAction {
     pub amount : u32,
     pub x : usize,
     pub y : usize
 }
 
+#[derive(Clone, Debug, PartialEq, Eq)]
 pub struct BuildAction {
     pub x : usize,
     pub y : usize
 }
 
+#[derive(Clone, Debug, PartialEq, Eq)]
 pub enum Action {
     Move(MoveAction),
     Spawn(SpawnAction),
     Build(BuildAction)
 }
 
+impl std::fmt::Display for Action {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Action::Move(mv) => write!(f, "MOVE {} {} {} {} {}", mv.amount, mv.fromX, mv.fromY, mv.toX, mv.toY),
+            Action::Spawn(sp) => write!(f, "SPAWN {} {} {}", sp.amount, sp.x, sp.y),
+            Action::Build(b) => write!(f, "BUILD {} {}", b.x, b.y)
+        }
+    }
+}
+
+//command of action list with missing or bad arguments
+#[derive(Debug, PartialEq, Eq)]
+pub struct ActionParseError(pub String);
+
+impl std::fmt::Display for ActionParseError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "bad command {:?}", self.0)
+    }
+}
+
+impl std::error::Error for ActionParseError {}
+
+impl Action {
+    //parses game output like "MOVE 1 2 3 4 5;SPAWN 1 2 3;BUILD 4 5;WAIT",
+    //WAIT and MESSAGE are skipped
+    pub fn parse_list(data : &str) -> Result<Vec<Action>, ActionParseError> {
+        let mut res = vec![];
+        for cmd in data.split(';') {
+            let parts : Vec<&str> = cmd.split_whitespace().collect();
+            if parts.is_empty() {
+                continue;
+            }
+            let bad = || ActionParseError(cmd.trim().to_string());
+            let arg = |idx : usize| -> Result<usize, ActionParseError> {
+                parts.get(idx).and_then(|p| p.parse().ok()).ok_or_else(bad)
+            };
+            let amount = || -> Result<u32, ActionParseError> {
+                parts.get(1).and_then(|p| p.parse().ok()).ok_or_else(bad)
+            };
+            match parts[0] {
+                "MOVE" => res.push(Action::Move(MoveAction {
+                    amount : amount()?,
+                    fromX : arg(2)?,
+                    fromY : arg(3)?,
+                    toX : arg(4)?,
+                    toY : arg(5)?
+                })),
+                "SPAWN" => res.push(Action::Spawn(SpawnAction {
+                    amount : amount()?,
+                    x : arg(2)?,
+                    y : arg(3)?
+                })),
+                "BUILD" => res.push(Action::Build(BuildAction {
+                    x : arg(1)?,
+                    y : arg(2)?
+                })),
+                _ => {}
+            }
+        }
+        Ok(res)
+    }
+
+    pub fn format_list(actions : &[Action]) -> String {
+        if actions.is_empty() {
+            return String::from("WAIT");
+        }
+        actions.iter().map(|a| a.to_string()).collect::<Vec<String>>().join(";")
+    }
+}
+
 #[derive(Default, Clone, PartialEq, Eq, Debug)]
 pub struct TVec2<T : Default + Clone + PartialEq + Eq + Debug> {
     pub x : T,
@@ -86,19 +171,38 @@ impl TVec2<usize> {
     }
 }
 
+//next step rules, all in find_path_reference and mirrored by cached find_path:
+//- walls are grass and recyclers
+//- unreachable target is replaced by reachable tile with smallest manhattan distance to it,
+//  ties go to shorter path from source, then to smaller y and x
+//- unit steps to neighbour on shortest path, if several are possible the one with smaller
+//  euclidean distance to original target wins, then the first in NEIGHBOURS order
+//- unit on wall or already at target stays
+//distance rows are computed on first use, cached per tile and dropped when walls change;
+//up to ALL_PAIRS_TILES rows of sources and targets are kept (all pairs), on larger maps
+//only rows of targets are kept and source is searched once only for unreachable targets
 pub struct Pathfinder {
-    pub data : Vec<i32>,
     pub walls : Vec<bool>,
-    pub prev_point : Vec<TVec2<usize>>,
     pub w : usize,
-    pub h : usize
+    pub h : usize,
+    //rows of path lengths from one tile, UNREACHABLE when there is no path
+    dist : Vec<u16>,
+    //row index in dist per tile, NO_ROW when not computed
+    slot : Vec<u32>,
+    //use uncached search, kept for tests and benchmarks
+    pub reference : bool,
+    queue : Vec<u16>,
+    //uncached source row on large maps
+    source_row : Vec<u16>
 }
 
-struct GrowPoint {
-    pub p : TVec2<usize>,
-    pub prev_p : TVec2<usize>,
-    pub val : i32
-}
+pub const UNREACHABLE : u16 = u16::MAX;
+//24x12 is largest CodinGame map, w * h * w * h distances are 166KB there
+pub const ALL_PAIRS_TILES : usize = 24 * 12;
+const NO_ROW : u32 = u32::MAX;
+
+//left, up, right, down
+const NEIGHBOURS : [(isize, isize); 4] = [(-1, 0), (0, -1), (1, 0), (0, 1)];
 
 impl Pathfinder {
 
@@ -107,128 +211,359 @@ impl Pathfinder {
         y * self.w + x
     }
 
-    pub fn find_path(&mut self, src : &TVec2<usize>, dst : &TVec2<usize>) -> TVec2<usize> {
-
-        self.data.fill(0);
+    //drops cached rows if any wall changed
+    pub fn update_walls<I : IntoIterator<Item = bool>>(&mut self, walls : I) {
+        let mut changed = false;
+        for (dst, wall) in self.walls.iter_mut().zip(walls) {
+            changed |= *dst != wall;
+            *dst = wall;
+        }
+        if changed {
+            self.invalidate();
+        }
+    }
 
-        let mut raise_points = VecDeque::new();
-        raise_points.push_back(GrowPoint { p : src.clone(), prev_p : src.clone(), val : 1 });
+    pub fn invalidate(&mut self) {
+        self.slot.fill(NO_ROW);
+        self.dist.clear();
+    }
 
-        let d_w = self.w - 1;
-        let d_h = self.h - 1;
+    pub fn all_pairs(&self) -> bool {
+        self.w * self.h <= ALL_PAIRS_TILES
+    }
 
-        let mut nearest_point = src.clone();
-        let mut nearest_dist = 100000000.0_f32;
+    //number of cached distance rows
+    pub fn cached_rows(&self) -> usize {
+        self.dist.len() / (self.w * self.h)
+    }
 
-        while raise_points.len() > 0 && self.data[dst.y * self.w + dst.x] == 0 {
-            let gp = raise_points.pop_front().unwrap();
-            let idx = gp.p.y * self.w + gp.p.x;
-            
-            if self.data[idx] == 0 && self.walls[idx] == false {
-                self.data[idx] = gp.val;
-                self.prev_point[idx] = gp.prev_p.clone();
+    fn neighbour(&self, idx : usize, (dx, dy) : (isize, isize)) -> Option<usize> {
+        let x = (idx % self.w) as isize + dx;
+        let y = (idx / self.w) as isize + dy;
+        if x < 0 || y < 0 || x >= self.w as isize || y >= self.h as isize {
+            None
+        } else {
+            Some(y as usize * self.w + x as usize)
+        }
+    }
 
-                let dist = gp.p.dist(dst);
-                if dist < nearest_dist {
-                    nearest_dist = dist;
-                    nearest_point = gp.p.clone();
+    //BFS distances from tile to every other tile
+    fn fill_distances(&self, from : usize, row : &mut [u16], queue : &mut Vec<u16>) {
+        row.fill(UNREACHABLE);
+        queue.clear();
+        if self.walls[from] {
+            return;
+        }
+        row[from] = 0;
+        queue.push(from as u16);
+
+        let mut head = 0;
+        while head < queue.len() {
+            let idx = queue[head] as usize;
+            head += 1;
+            for delta in NEIGHBOURS {
+                if let Some(next) = self.neighbour(idx, delta) {
+                    if !self.walls[next] && row[next] == UNREACHABLE {
+                        row[next] = row[idx] + 1;
+                        queue.push(next as u16);
+                    }
                 }
+            }
+        }
+    }
 
-                if gp.p.x > 0 {
-                    raise_points.push_back(
-                        GrowPoint {
-                            p : TVec2 {x : gp.p.x - 1, y : gp.p.y},
-                            prev_p : gp.p.clone(),
-                            val : gp.val + 1
-                        }
-                    )
-                }
+    fn ensure_row(&mut self, from : usize) {
+        if self.slot[from] == NO_ROW {
+            let n = self.w * self.h;
+            let start = self.dist.len();
+            let mut dist = std::mem::take(&mut self.dist);
+            let mut queue = std::mem::take(&mut self.queue);
+            dist.resize(start + n, UNREACHABLE);
+            self.fill_distances(from, &mut dist[start..], &mut queue);
+            self.dist = dist;
+            self.queue = queue;
+            self.slot[from] = (start / n) as u32;
+        }
+    }
 
-                if gp.p.y > 0 {
-                    raise_points.push_back(
-                        GrowPoint {
-                            p : TVec2 {x : gp.p.x, y : gp.p.y - 1},
-                            prev_p : gp.p.clone(),
-                            val : gp.val + 1
-                        }
-                    )
-                }
+    fn row(&self, from : usize) -> &[u16] {
+        let n = self.w * self.h;
+        let start = self.slot[from] as usize * n;
+        &self.dist[start..start + n]
+    }
 
-                if gp.p.x < d_w {
-                    raise_points.push_back(
-                        GrowPoint {
-                            p : TVec2 {x : gp.p.x + 1, y : gp.p.y},
-                            prev_p : gp.p.clone(),
-                            val : gp.val + 1
-                        }
-                    )
-                }
+    //target when reachable from source, found with cached row of target only
+    fn reachable_target(&mut self, src : usize, target : &TVec2<usize>) -> Option<usize> {
+        if target.x >= self.w || target.y >= self.h {
+            return None;
+        }
+        let idx = self.get_idx(target.x, target.y);
+        self.ensure_row(idx);
+        (self.row(idx)[src] != UNREACHABLE).then_some(idx)
+    }
 
-                if gp.p.y < d_h {
-                    raise_points.push_back(
-                        GrowPoint {
-                            p : TVec2 {x : gp.p.x, y : gp.p.y + 1},
-                            prev_p : gp.p.clone(),
-                            val : gp.val + 1
-                        }
-                    )
+    fn manhattan(&self, idx : usize, target : &TVec2<usize>) -> usize {
+        (idx % self.w).abs_diff(target.x) + (idx / self.w).abs_diff(target.y)
+    }
+
+    //target itself when reachable, otherwise closest reachable tile by the rules above
+    fn effective_target(&self, from_row : &[u16], target : &TVec2<usize>) -> Option<usize> {
+        if target.x < self.w && target.y < self.h && from_row[self.get_idx(target.x, target.y)] != UNREACHABLE {
+            return Some(self.get_idx(target.x, target.y));
+        }
+        (0..from_row.len())
+            .filter(|idx| from_row[*idx] != UNREACHABLE)
+            .min_by_key(|idx| (self.manhattan(*idx, target), from_row[*idx], *idx))
+    }
+
+    //neighbour of source one step closer to goal, ties by euclidean distance to target, then NEIGHBOURS order
+    fn choose_step(&self, src : usize, goal_row : &[u16], target : &TVec2<usize>) -> usize {
+        let mut best = src;
+        let mut best_dist = f32::MAX;
+        for delta in NEIGHBOURS {
+            if let Some(next) = self.neighbour(src, delta) {
+                if goal_row[next] == UNREACHABLE || goal_row[next] + 1 != goal_row[src] {
+                    continue;
+                }
+                let dist = TVec2::new(next % self.w, next / self.w).dist(target);
+                if dist < best_dist {
+                    best_dist = dist;
+                    best = next;
                 }
             }
         }
+        best
+    }
 
-        //move back
-        
-        let mut tmp_point = nearest_point;
-        let mut cur_val = self.data[tmp_point.y * self.w + tmp_point.x];
-
-        while cur_val > 2 {
-            tmp_point = self.prev_point[tmp_point.y * self.w + tmp_point.x].clone();
-            cur_val = self.data[tmp_point.y * self.w + tmp_point.x];
+    pub fn find_path(&mut self, src : &TVec2<usize>, dst : &TVec2<usize>) -> TVec2<usize> {
+        if self.reference {
+            return self.find_path_reference(src, dst);
         }
-        
-        tmp_point
+
+        let src_idx = self.get_idx(src.x, src.y);
+        let goal = if self.all_pairs() {
+            self.ensure_row(src_idx);
+            self.effective_target(self.row(src_idx), dst)
+        } else {
+            match self.reachable_target(src_idx, dst) {
+                Some(goal) => Some(goal),
+                None => {
+                    let mut row = std::mem::take(&mut self.source_row);
+                    let mut queue = std::mem::take(&mut self.queue);
+                    row.resize(self.w * self.h, UNREACHABLE);
+                    self.fill_distances(src_idx, &mut row, &mut queue);
+                    let goal = self.effective_target(&row, dst);
+                    self.source_row = row;
+                    self.queue = queue;
+                    goal
+                }
+            }
+        };
+        let goal = match goal {
+            Some(goal) if goal != src_idx => goal,
+            _ => return src.clone()
+        };
+        //distances are symmetric, so row of goal gives distance to goal from every tile
+        self.ensure_row(goal);
+        let step = self.choose_step(src_idx, self.row(goal), dst);
+        TVec2::new(step % self.w, step / self.w)
     }
 
+    //same rules with fresh BFS on every call
+    pub fn find_path_reference(&self, src : &TVec2<usize>, dst : &TVec2<usize>) -> TVec2<usize> {
+        let n = self.w * self.h;
+        let mut queue = vec![];
+        let src_idx = self.get_idx(src.x, src.y);
+        let mut from_row = vec![UNREACHABLE; n];
+        self.fill_distances(src_idx, &mut from_row, &mut queue);
+
+        let goal = match self.effective_target(&from_row, dst) {
+            Some(goal) if goal != src_idx => goal,
+            _ => return src.clone()
+        };
+        let mut goal_row = vec![UNREACHABLE; n];
+        self.fill_distances(goal, &mut goal_row, &mut queue);
+        let step = self.choose_step(src_idx, &goal_row, dst);
+        TVec2::new(step % self.w, step / self.w)
+    }
+
+    //distances and queue are u16, so maps are limited to 65534 tiles
     pub fn new(w : usize, h : usize) -> Self {
+        assert!(w * h < UNREACHABLE as usize, "Map is too large for pathfinder");
         Self {
             w,
             h,
-            data : vec![0; w * h],
             walls : vec![false; w * h],
-            prev_point : vec![TVec2::default(); w * h]
+            dist : vec![],
+            slot : vec![NO_ROW; w * h],
+            reference : false,
+            queue : Vec::with_capacity(w * h),
+            source_row : vec![]
         }
     }
 }
 
-pub struct Map {
-    pub data : Vec<Tile>,
+//buffers reused between turns, not part of game state, one per thread is enough for search
+pub struct TurnScratch {
+    pub pathfinder : Pathfinder,
     pub recycle_me : Vec<bool>,
     pub recycle_enemy : Vec<bool>,
+    //tiles changed by last turn with values from before it
+    pub changed : Vec<(usize, Tile)>,
+    pub touched : Vec<bool>
+}
+
+impl TurnScratch {
+    pub fn new(w : usize, h : usize) -> Self {
+        Self {
+            pathfinder : Pathfinder::new(w, h),
+            recycle_me : vec![false; w * h],
+            recycle_enemy : vec![false; w * h],
+            changed : Vec::with_capacity(w * h),
+            touched : vec![false; w * h]
+        }
+    }
+}
+
+//values of tiles changed by one turn from before it
+#[derive(Default, Clone)]
+pub struct TurnUndo {
+    pub tiles : Vec<(usize, Tile)>,
+    pub my_scrap : i32,
+    pub enemy_scrap : i32,
+    pub board_hash : u64
+}
+
+pub struct Map {
+    pub data : Vec<Tile>,
     pub w : usize,
     pub h : usize,
     pub my_scrap : i32,
     pub enemy_scrap : i32,
-    pub pathfinder : Pathfinder
+    //created on first next_turn, search should use next_turn_with and shared scratch
+    pub scratch : Option<TurnScratch>,
+    //zobrist hash of tiles, kept up to date by turns, call rehash after editing data directly
+    pub board_hash : u64,
+    changes : Option<ChangeLog>
 }
 
+//changed tiles with their values from turn start, touched flag per tile
+type ChangeLog = (Vec<(usize, Tile)>, Vec<bool>);
+
+//copies game state only
+impl Clone for Map {
+    fn clone(&self) -> Self {
+        Self {
+            data : self.data.clone(),
+            w : self.w,
+            h : self.h,
+            my_scrap : self.my_scrap,
+            enemy_scrap : self.enemy_scrap,
+            scratch : None,
+            board_hash : self.board_hash,
+            changes : None
+        }
+    }
+}
 
 impl Map {
 
+    pub fn new(data : Vec<Tile>, w : usize, h : usize, my_scrap : i32, enemy_scrap : i32) -> Self {
+        Self {
+            board_hash : zobrist::board_hash(&data),
+            data,
+            w,
+            h,
+            my_scrap,
+            enemy_scrap,
+            scratch : None,
+            changes : None
+        }
+    }
+
+    //board plus both scrap balances
+    pub fn hash(&self) -> u64 {
+        self.board_hash ^ zobrist::balance_key(self.my_scrap, self.enemy_scrap)
+    }
+
+    pub fn rehash(&mut self) {
+        self.board_hash = zobrist::board_hash(&self.data);
+    }
+
+    pub fn scratch_mut(&mut self) -> &mut TurnScratch {
+        let (w, h) = (self.w, self.h);
+        self.scratch.get_or_insert_with(|| TurnScratch::new(w, h))
+    }
+
+    //tile for writing during turn, value from turn start is logged on first write
+    #[inline(always)]
+    fn tile_mut(&mut self, idx : usize) -> &mut Tile {
+        if let Some((changed, touched)) = self.changes.as_mut() {
+            if !touched[idx] {
+                touched[idx] = true;
+                changed.push((idx, self.data[idx].clone()));
+            }
+        }
+        &mut self.data[idx]
+    }
+
+    pub fn tile_count(&self, owner : &TileOwner) -> usize {
+        self.data.iter().filter(|t| t.owner == *owner && t.scrap_amount > 0).count()
+    }
+
     pub fn next_turn(&mut self, my_actions : &Vec<Action>, enemy_actions : &Vec<Action>) {
+        self.scratch_mut();
+        let mut scratch = self.scratch.take().unwrap();
+        self.next_turn_with(my_actions, enemy_actions, &mut scratch);
+        self.scratch = Some(scratch);
+    }
+
+    pub fn next_turn_with(&mut self, my_actions : &[Action], enemy_actions : &[Action], scratch : &mut TurnScratch) {
+        let mut changed = std::mem::take(&mut scratch.changed);
+        changed.clear();
+        self.changes = Some((changed, std::mem::take(&mut scratch.touched)));
+
         //build
         self.build(my_actions, enemy_actions);
-        self.move_spawn(my_actions, enemy_actions);
+        self.move_spawn(my_actions, enemy_actions, &mut scratch.pathfinder);
         self.tile_process();
-        self.recycler_process();
+        self.recycler_process(scratch);
 
         //perturn scrap increase
         self.my_scrap += 10;
         self.enemy_scrap += 10;
+
+        let (changed, mut touched) = self.changes.take().unwrap();
+        for (idx, old) in changed.iter() {
+            touched[*idx] = false;
+            self.board_hash ^= zobrist::tile_key(*idx, old) ^ zobrist::tile_key(*idx, &self.data[*idx]);
+        }
+        scratch.changed = changed;
+        scratch.touched = touched;
+    }
+
+    //next_turn_with which keeps changed tiles, so undo_turn can restore state in place
+    pub fn apply_turn(&mut self, my_actions : &[Action], enemy_actions : &[Action], scratch : &mut TurnScratch, undo : &mut TurnUndo) {
+        undo.my_scrap = self.my_scrap;
+        undo.enemy_scrap = self.enemy_scrap;
+        undo.board_hash = self.board_hash;
+        self.next_turn_with(my_actions, enemy_actions, scratch);
+        undo.tiles.clone_from(&scratch.changed);
     }
 
-    fn recycler_process(&mut self) {
-        self.recycle_me.fill(false);
-        self.recycle_enemy.fill(false);
+    pub fn undo_turn(&mut self, undo : &TurnUndo) {
+        for (idx, tile) in undo.tiles.iter() {
+            self.data[*idx] = tile.clone();
+        }
+        self.my_scrap = undo.my_scrap;
+        self.enemy_scrap = undo.enemy_scrap;
+        self.board_hash = undo.board_hash;
+    }
+
+    fn recycler_process(&mut self, scratch : &mut TurnScratch) {
+        let TurnScratch { recycle_me, recycle_enemy, .. } = scratch;
+        recycle_me.fill(false);
+        recycle_enemy.fill(false);
 
         let dw = self.w - 1;
         let dh = self.h - 1;
@@ -238,32 +573,32 @@ impl Map {
                 let idx = y * self.w + x;
                 if self.data[idx].recycler {
                     if self.data[idx].owner == TileOwner::Me {
-                        self.recycle_me[idx] = true;
+                        recycle_me[idx] = true;
                         if x > 0 {
-                            self.recycle_me[idx - 1] = true;
+                            recycle_me[idx - 1] = true;
                         }
                         if x < dw {
-                            self.recycle_me[idx + 1] = true;
+                            recycle_me[idx + 1] = true;
                         }
                         if y > 0 {
-                            self.recycle_me[idx - self.w] = true;
+                            recycle_me[idx - self.w] = true;
                         }
                         if y < dh {
-                            self.recycle_me[idx + self.w] = true;
+                            recycle_me[idx + self.w] = true;
                         }
                     } else {
-                        self.recycle_enemy[idx] = true;
+                        recycle_enemy[idx] = true;
                         if x > 0 {
-                            self.recycle_enemy[idx - 1] = true;
+                            recycle_enemy[idx - 1] = true;
                         }
                         if x < dw {
-                            self.recycle_enemy[idx + 1] = true;
+                            recycle_enemy[idx + 1] = true;
                         }
                         if y > 0 {
-                            self.recycle_enemy[idx - self.w] = true;
+                            recycle_enemy[idx - self.w] = true;
                         }
                         if y < dh {
-                            self.recycle_enemy[idx + self.w] = true;
+                            recycle_enemy[idx + self.w] = true;
                         }
                     }
                 }
@@ -271,24 +606,25 @@ impl Map {
         }
 
         for idx in 0..self.data.len() {
-            let mut tile = &mut self.data[idx];
-            let r_me = self.recycle_me[idx];
-            let r_enemy = self.recycle_enemy[idx];
+            let r_me = recycle_me[idx];
+            let r_enemy = recycle_enemy[idx];
             if r_me || r_enemy {
-                if tile.scrap_amount > 0 {
+                if self.data[idx].scrap_amount > 0 {
+                    let tile = self.tile_mut(idx);
                     tile.scrap_amount -= 1;
+                    let grass = tile.scrap_amount == 0;
+                    if grass {
+                        tile.owner = TileOwner::No;
+                        tile.units = 0;
+                        tile.delta_units = 0;
+                        tile.recycler = false;
+                    }
                     if r_me {
                         self.my_scrap += 1;
                     }
                     if r_enemy {
                         self.enemy_scrap += 1;
                     }
-                    if tile.scrap_amount == 0 {
-                        tile.owner = TileOwner::No;
-                        tile.units = 0;
-                        tile.delta_units = 0;
-                        tile.recycler = false;
-                    }
                 }
 
             }
@@ -298,7 +634,15 @@ impl Map {
 
     fn tile_process(&mut self) {
         for idx in 0..self.data.len() {
-            let mut tile = &mut self.data[idx];
+            //untouched tiles keep units and owner, skipping them keeps undo log short
+            let tile = &self.data[idx];
+            let settled = tile.units == 0
+                || (tile.units > 0 && tile.owner == TileOwner::Me)
+                || (tile.units < 0 && tile.owner == TileOwner::Enemy);
+            if tile.delta_units == 0 && settled {
+                continue;
+            }
+            let tile = self.tile_mut(idx);
             tile.units += tile.delta_units;
             tile.delta_units = 0;
 
@@ -310,15 +654,15 @@ impl Map {
         }
     }
 
-    fn build(&mut self, my_actions : &Vec<Action>, enemy_actions : &Vec<Action>) {
+    fn build(&mut self, my_actions : &[Action], enemy_actions : &[Action]) {
         for a in my_actions.iter() {
             if self.my_scrap < 10 {
                 break;
             }
             if let Action::Build(build) = a {
-                let mut tile = &mut self.data[(build.y * self.w + build.x) as usize];
-                if tile.owner == TileOwner::Me && tile.units == 0 {
-                    tile.recycler = true;
+                let idx = build.y * self.w + build.x;
+                if self.data[idx].owner == TileOwner::Me && self.data[idx].units == 0 {
+                    self.tile_mut(idx).recycler = true;
                     self.my_scrap -= 10;
                 }
             }
@@ -329,42 +673,37 @@ impl Map {
                 break;
             }
             if let Action::Build(build) = a {
-                let mut tile = &mut self.data[(build.y * self.w + build.x) as usize];
-                if tile.owner == TileOwner::Enemy && tile.units == 0 {
-                    tile.recycler = true;
+                let idx = build.y * self.w + build.x;
+                if self.data[idx].owner == TileOwner::Enemy && self.data[idx].units == 0 {
+                    self.tile_mut(idx).recycler = true;
                     self.enemy_scrap -= 10;
                 }
             }
         }
     }
 
-    fn move_spawn(&mut self, my_actions : &Vec<Action>, enemy_actions : &Vec<Action>) {
+    fn move_spawn(&mut self, my_actions : &[Action], enemy_actions : &[Action], pathfinder : &mut Pathfinder) {
         //setup walls
-        for idx in 0..self.data.len() {
-            let tile = &self.data[idx];
-            let wall = tile.recycler || tile.scrap_amount == 0;
-            self.pathfinder.walls[idx] = wall;
-        }
+        pathfinder.update_walls(self.data.iter().map(|tile| tile.recycler || tile.scrap_amount == 0));
 
         //move
         for a in my_actions.iter() {
             if let Action::Move(mv) = a {
-                let idx = (mv.fromX * self.w + mv.fromY) as usize;
+                let idx = (mv.fromY * self.w + mv.fromX) as usize;
 
                 if self.data[idx].owner == TileOwner::Me && self.data[idx].units > 0 {
                     let move_amount = self.data[idx].units.min(mv.amount as i32);
-                    let dst = self.pathfinder.find_path(
+                    let dst = pathfinder.find_path(
                         &TVec2::new(mv.fromX as usize, mv.fromY as usize),
                         &TVec2::new(mv.toX as usize, mv.toY as usize));
-                    self.data[dst.y * self.w as usize + dst.x].delta_units += move_amount;
-                    self.data[idx].units -= move_amount;
+                    self.tile_mut(dst.y * self.w as usize + dst.x).delta_units += move_amount;
+                    self.tile_mut(idx).units -= move_amount;
                 }
             } else if let Action::Spawn(sp) = a {
                 if sp.amount > 0 {
                     let cost = sp.amount as i32 * 10;
                     if self.my_scrap >= cost {
-                        let tile = &mut self.data[(sp.x * self.w + sp.y) as usize];
-                        tile.delta_units += sp.amount as i32;
+                        self.tile_mut(sp.y * self.w + sp.x).delta_units += sp.amount as i32;
                         self.my_scrap -= cost;
                     }
                 }
@@ -373,22 +712,21 @@ impl Map {
 
         for a in enemy_actions.iter() {
             if let Action::Move(mv) = a {
-                let idx = (mv.fromX * self.w + mv.fromY) as usize;
+                let idx = (mv.fromY * self.w + mv.fromX) as usize;
 
                 if self.data[idx].owner == TileOwner::Enemy && self.data[idx].units < 0 {
                     let move_amount = (-self.data[idx].units).min(mv.amount as i32);
-                    let dst = self.pathfinder.find_path(
+                    let dst = pathfinder.find_path(
                         &TVec2::new(mv.fromX as usize, mv.fromY as usize),
                         &TVec2::new(mv.toX as usize, mv.toY as usize));
-                    self.data[dst.y * self.w as usize + dst.x].delta_units -= move_amount;
-                    self.data[idx].units += move_amount;
+                    self.tile_mut(dst.y * self.w as usize + dst.x).delta_units -= move_amount;
+                    self.tile_mut(idx).units += move_amount;
                 }
             } else if let Action::Spawn(sp) = a {
                 if sp.amount > 0 {
                     let cost = sp.amount as i32 * 10;
                     if self.enemy_scrap >= cost {
-                        let tile = &mut self.data[(sp.x * self.w + sp.y) as usize];
-                        tile.delta_units -= sp.amount as i32;
+                        self.tile_mut(sp.y * self.w + sp.x).delta_units -= sp.amount as i32;
                         self.enemy_scrap -= cost;
                     }
                 }
@@ -445,18 +783,7 @@ impl Map {
 
         }
 
-        let data_len = data.len();
-
-        Self {
-            data,
-            recycle_me : vec![false; data_len],
-            recycle_enemy : vec![false; data_len],
-            w : width as usize,
-            h : height as usize,
-            my_scrap,
-            enemy_scrap,
-            pathfinder : Pathfinder::new(width as usize, height as usize)
-        }
+        Map::new(data, width as usize, height as usize, my_scrap, enemy_scrap)
     }
 
     pub fn load_file<P : AsRef<Path>>(path : P) -> Self {
@@ -483,4 +810,174 @@ mod tests {
 
         assert_eq!(res, TVec2::new(0, 1));
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_cached_pathfinder_matches_reference() {
+        use rand::{Rng, SeedableRng};
+        let mut rng = rand::rngs::StdRng::seed_from_u64(3);
+        let mut pathfinder = Pathfinder::new(13, 7);
+
+        for _ in 0..4 {
+            let walls : Vec<bool> = (0..13 * 7).map(|_| rng.gen_bool(0.3)).collect();
+            pathfinder.update_walls(walls);
+            for src in 0..13 * 7 {
+                for dst in 0..13 * 7 {
+                    let src = TVec2::new(src % 13, src / 13);
+                    let dst = TVec2::new(dst % 13, dst / 13);
+                    assert_eq!(pathfinder.find_path(&src, &dst), pathfinder.find_path_reference(&src, &dst));
+                }
+            }
+        }
+    }
+
+    //above ALL_PAIRS_TILES only target rows are cached
+    #[test]
+    fn test_large_map_pathfinder() {
+        use rand::{Rng, SeedableRng};
+        let mut rng = rand::rngs::StdRng::seed_from_u64(4);
+        let (w, h) = (60, 40);
+        let mut pathfinder = Pathfinder::new(w, h);
+        assert!(!pathfinder.all_pairs());
+        assert_eq!(pathfinder.cached_rows(), 0);
+
+        let walls : Vec<bool> = (0..w * h).map(|_| rng.gen_bool(0.3)).collect();
+        pathfinder.update_walls(walls);
+        let targets : Vec<TVec2<usize>> = (0..5).map(|_| TVec2::new(rng.gen_range(0..w + 2), rng.gen_range(0..h))).collect();
+        for _ in 0..400 {
+            let src = TVec2::new(rng.gen_range(0..w), rng.gen_range(0..h));
+            let dst = &targets[rng.gen_range(0..targets.len())];
+            assert_eq!(pathfinder.find_path(&src, dst), pathfinder.find_path_reference(&src, dst));
+        }
+        //rows of reachable targets plus goals replacing unreachable ones
+        assert!(pathfinder.cached_rows() < 100, "{} rows", pathfinder.cached_rows());
+    }
+
+    //'#' is grass or recycler
+    fn pathfinder_from(rows : &[&str]) -> Pathfinder {
+        let mut pathfinder = Pathfinder::new(rows[0].len(), rows.len());
+        pathfinder.update_walls(rows.iter().flat_map(|row| row.chars().map(|c| c == '#')));
+        pathfinder
+    }
+
+    fn step(pathfinder : &mut Pathfinder, src : (usize, usize), dst : (usize, usize)) -> (usize, usize) {
+        let step = pathfinder.find_path(&TVec2::new(src.0, src.1), &TVec2::new(dst.0, dst.1));
+        assert_eq!(step, pathfinder.find_path_reference(&TVec2::new(src.0, src.1), &TVec2::new(dst.0, dst.1)));
+        (step.x, step.y)
+    }
+
+    #[test]
+    fn test_blocked_target() {
+        let mut pathfinder = pathfinder_from(&[
+            ".....",
+            "..#..",
+            "....."]);
+        //(1, 1) is as close as other sides of wall but has shortest path
+        assert_eq!(step(&mut pathfinder, (0, 1), (2, 1)), (1, 1));
+        assert_eq!(step(&mut pathfinder, (1, 1), (2, 1)), (1, 1));
+        assert_eq!(step(&mut pathfinder, (4, 0), (2, 1)), (3, 0));
+
+        let mut pathfinder = pathfinder_from(&[
+            "...",
+            ".#.",
+            "..."]);
+        //(1, 0) and (0, 1) tie on distance and path length, smaller y wins
+        assert_eq!(step(&mut pathfinder, (0, 0), (1, 1)), (1, 0));
+        assert_eq!(step(&mut pathfinder, (2, 2), (1, 1)), (2, 1));
+    }
+
+    #[test]
+    fn test_equal_length_paths() {
+        let mut pathfinder = pathfinder_from(&[
+            ".....",
+            ".....",
+            ".....",
+            ".....",
+            "....."]);
+        //closer to straight line wins
+        assert_eq!(step(&mut pathfinder, (0, 0), (3, 1)), (1, 0));
+        assert_eq!(step(&mut pathfinder, (0, 0), (1, 3)), (0, 1));
+        //exact diagonal uses left, up, right, down order
+        assert_eq!(step(&mut pathfinder, (0, 0), (2, 2)), (1, 0));
+        assert_eq!(step(&mut pathfinder, (4, 4), (2, 2)), (3, 4));
+        assert_eq!(step(&mut pathfinder, (2, 2), (2, 2)), (2, 2));
+
+        let mut pathfinder = pathfinder_from(&[
+            ".....",
+            "..#..",
+            "....."]);
+        //detours above and below are equally long
+        assert_eq!(step(&mut pathfinder, (0, 1), (4, 1)), (1, 1));
+        assert_eq!(step(&mut pathfinder, (1, 1), (4, 1)), (1, 0));
+        assert_eq!(step(&mut pathfinder, (3, 1), (0, 1)), (3, 0));
+    }
+
+    #[test]
+    fn test_grass_islands() {
+        let mut pathfinder = pathfinder_from(&[
+            "..#..",
+            "..#..",
+            "..#.."]);
+        //target on other island, unit heads to closest shore tile
+        assert_eq!(step(&mut pathfinder, (0, 2), (4, 0)), (1, 2));
+        assert_eq!(step(&mut pathfinder, (1, 0), (4, 0)), (1, 0));
+        assert_eq!(step(&mut pathfinder, (4, 2), (0, 1)), (3, 2));
+
+        let mut pathfinder = pathfinder_from(&[
+            ".#...",
+            "#....",
+            "....."]);
+        //sealed unit and unit standing on grass do not move
+        assert_eq!(step(&mut pathfinder, (0, 0), (4, 2)), (0, 0));
+        assert_eq!(step(&mut pathfinder, (1, 0), (4, 2)), (1, 0));
+        assert_eq!(step(&mut pathfinder, (4, 2), (0, 0)), (3, 2));
+    }
+
+    #[test]
+    fn test_clone_and_undo_turn() {
+        use rand::Rng;
+        let mut rnd = crate::rng::stream_rng(5, 0);
+        let mut map = Map::load_file("../start_map.txt");
+        let mut scratch = TurnScratch::new(map.w, map.h);
+        let mut undo = TurnUndo::default();
+
+        for _ in 0..60 {
+            let mut actions = [vec![], vec![]];
+            for (idx, tile) in map.data.iter().enumerate() {
+                let side = match tile.owner {
+                    TileOwner::Me => {0}
+                    TileOwner::Enemy => {1}
+                    TileOwner::No => {continue}
+                };
+                let (x, y) = (idx % map.w, idx / map.w);
+                actions[side].push(Action::Move(MoveAction {
+                    amount : 1 + tile.units.unsigned_abs() / 2,
+                    fromX : x,
+                    fromY : y,
+                    toX : rnd.gen_range(0..map.w),
+                    toY : rnd.gen_range(0..map.h)
+                }));
+                if rnd.gen_bool(0.1) {
+                    actions[side].push(Action::Spawn(SpawnAction { amount : 1, x, y }));
+                }
+                if rnd.gen_bool(0.03) {
+                    actions[side].push(Action::Build(BuildAction { x, y }));
+                }
+            }
+
+            let before = map.clone();
+            let mut expected = map.clone();
+            expected.next_turn_with(&actions[0], &actions[1], &mut scratch);
+
+            map.apply_turn(&actions[0], &actions[1], &mut scratch, &mut undo);
+            assert_eq!(map.data, expected.data);
+            assert_eq!((map.my_scrap, map.enemy_scrap), (expected.my_scrap, expected.enemy_scrap));
+
+            map.undo_turn(&undo);
+            assert_eq!(map.data, before.data);
+            assert_eq!((map.my_scrap, map.enemy_scrap), (before.my_scrap, before.enemy_scrap));
+
+            map.next_turn(&actions[0], &actions[1]);
+            assert_eq!(map.data, expected.data);
+        }
+    }
+}
@@ -0,0 +1,179 @@
+use rand::Rng;
+use crate::net::Layer;
+
+//standard normal sample with Box-Muller
+pub fn gaussian<R : Rng + ?Sized>(rng : &mut R) -> f32 {
+    let u1 : f32 = rng.gen_range(f32::EPSILON..1.0);
+    let u2 : f32 = rng.gen();
+    (-2.0 * u1.ln()).sqrt() * (2.0 * std::f32::consts::PI * u2).cos()
+}
+
+#[derive(Clone, Debug)]
+pub struct MutationConfig {
+    //sigma for parameter groups without own value in layer_sigma
+    pub sigma : f32,
+    //sigma per parameter group (layers with parameters in param_ranges order), overrides sigma
+    pub layer_sigma : Vec<f32>,
+    //probability of gaussian perturbation per weight
+    pub rate : f32,
+    //probability to redraw weight uniformly from [-reset_range, reset_range]
+    pub reset_rate : f32,
+    pub reset_range : f32,
+    //log-normal self adaptation of genome sigmas, disabled when tau is 0
+    pub tau : f32,
+    pub min_sigma : f32,
+    pub max_sigma : f32
+}
+
+impl Default for MutationConfig {
+    fn default() -> Self {
+        Self {
+            sigma : 0.1,
+            layer_sigma : vec![],
+            rate : 0.1,
+            reset_rate : 0.001,
+            reset_range : 1.0,
+            tau : 0.0,
+            min_sigma : 1e-4,
+            max_sigma : 1.0
+        }
+    }
+}
+
+impl MutationConfig {
+    pub fn initial_sigma(&self, group : usize) -> f32 {
+        self.layer_sigma.get(group).copied().unwrap_or(self.sigma)
+    }
+}
+
+//parameter group of every buffer in Layer::params order, all buffers of one sub layer share group
+pub fn buffer_groups(layer : &dyn Layer) -> Vec<usize> {
+    let ranges : Vec<_> = layer.param_ranges().into_iter().filter(|r| !r.is_empty()).collect();
+    let mut group = 0;
+    let mut offset = 0;
+    layer.params().iter().map(|buf| {
+        while group + 1 < ranges.len() && offset >= ranges[group].end {
+            group += 1;
+        }
+        offset += buf.len();
+        group
+    }).collect()
+}
+
+pub fn group_count(layer : &dyn Layer) -> usize {
+    layer.param_ranges().iter().filter(|r| !r.is_empty()).count()
+}
+
+//mutation step sizes that travel with genome, one per parameter group
+#[derive(Clone, Debug, Default)]
+pub struct MutationState {
+    pub sigmas : Vec<f32>
+}
+
+impl MutationState {
+    //resets sigmas to initial values when they do not match layer groups
+    pub fn prepare(&mut self, layer : &dyn Layer, config : &MutationConfig) {
+        let groups = group_count(layer);
+        if self.sigmas.len() != groups {
+            self.sigmas = (0..groups).map(|g| config.initial_sigma(g)).collect();
+        }
+    }
+
+    pub fn adapt<R : Rng + ?Sized>(&mut self, config : &MutationConfig, rng : &mut R) {
+        if config.tau == 0.0 {
+            return;
+        }
+        //shared factor plus per group factor as in classic ES self adaptation
+        let common = gaussian(rng) * config.tau / 2.0_f32.sqrt();
+        for sigma in self.sigmas.iter_mut() {
+            let own = gaussian(rng) * config.tau;
+            *sigma = (*sigma * (common + own).exp()).clamp(config.min_sigma, config.max_sigma);
+        }
+    }
+}
+
+//state must be prepared for layer
+pub fn gaussian_mutation<R : Rng + ?Sized>(layer : &mut dyn Layer, state : &MutationState, rate : f32, rng : &mut R) {
+    let groups = buffer_groups(layer);
+    assert_eq!(state.sigmas.len(), group_count(layer), "MutationState does not match layer groups, call prepare first");
+    for (group, params) in groups.into_iter().zip(layer.params_mut()) {
+        let sigma = state.sigmas[group];
+        for val in params.iter_mut() {
+            if rng.gen::<f32>() < rate {
+                *val += gaussian(rng) * sigma;
+            }
+        }
+    }
+}
+
+pub fn reset_mutation<R : Rng + ?Sized>(layer : &mut dyn Layer, rate : f32, range : f32, rng : &mut R) {
+    for params in layer.params_mut() {
+        for val in params.iter_mut() {
+            if rng.gen::<f32>() < rate {
+                *val = rng.gen_range(-range..=range);
+            }
+        }
+    }
+}
+
+//full mutation of genome: sigma adaptation, gaussian perturbation, sparse reset
+pub fn mutate<R : Rng + ?Sized>(layer : &mut dyn Layer, state : &mut MutationState, config : &MutationConfig, rng : &mut R) {
+    state.prepare(layer, config);
+    state.adapt(config, rng);
+    gaussian_mutation(layer, state, config.rate, rng);
+    reset_mutation(layer, config.reset_rate, config.reset_range, rng);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::SeedableRng;
+    use rand::rngs::StdRng;
+    use crate::net::{load_params, save_params, Affine, Conv2d, Node, SimpleNetwork};
+
+    #[test]
+    fn test_seeded_mutation_is_reproducible() {
+        let config = MutationConfig {
+            tau : 0.3,
+            ..MutationConfig::default()
+        };
+        let mut a = SimpleNetwork::simple_maker(3, 4, 4, 4, 1, &mut StdRng::seed_from_u64(2));
+        let mut b = SimpleNetwork::simple_maker(3, 4, 4, 4, 1, &mut StdRng::seed_from_u64(3));
+        load_params(&mut b, &save_params(&a)).unwrap();
+        let original = save_params(&a);
+
+        let mut state_a = MutationState::default();
+        let mut state_b = MutationState::default();
+        mutate(&mut a, &mut state_a, &config, &mut StdRng::seed_from_u64(7));
+        mutate(&mut b, &mut state_b, &config, &mut StdRng::seed_from_u64(7));
+
+        assert_eq!(save_params(&a), save_params(&b));
+        assert_ne!(save_params(&a), original);
+        assert_eq!(state_a.sigmas, state_b.sigmas);
+        assert_eq!(state_a.sigmas.len(), group_count(&a));
+        assert!(state_a.sigmas.iter().any(|s| *s != config.sigma));
+    }
+
+    #[test]
+    fn test_layer_sigma() {
+        let config = MutationConfig {
+            layer_sigma : vec![0.0, 0.5],
+            rate : 1.0,
+            reset_rate : 0.0,
+            ..MutationConfig::default()
+        };
+        //scale and bias of Affine are one layer, so they share second sigma
+        let mut net = SimpleNetwork { nodes : vec![] };
+        net.push(Node::new(Conv2d::new(3, 3, 4, 4, &mut StdRng::seed_from_u64(4))));
+        net.push(Node::new(Affine::new(4)));
+        assert_eq!(buffer_groups(&net), vec![0, 1, 1]);
+        let before : Vec<Vec<f32>> = net.params().iter().map(|p| p.to_vec()).collect();
+        let mut state = MutationState::default();
+        mutate(&mut net, &mut state, &config, &mut StdRng::seed_from_u64(1));
+
+        assert_eq!(state.sigmas, vec![0.0, 0.5]);
+        assert_eq!(net.params()[0], &before[0][..]);
+        assert_ne!(net.params()[1], &before[1][..]);
+        assert_ne!(net.params()[2], &before[2][..]);
+    }
+}
@@ -1,6 +1,10 @@
+use std::any::Any;
+use std::fmt::{Display, Formatter};
+use std::ops::Range;
 use rand::Rng;
 
 
+#[derive(Clone)]
 pub struct NetImage {
     pub data : Vec<f32>,
     pub w : usize,
@@ -45,21 +49,279 @@ impl NetImage {
     }
 }
 
-pub trait Layer {
+pub trait AsAny {
+    fn as_any(&self) -> &dyn Any;
+    fn as_any_mut(&mut self) -> &mut dyn Any;
+}
+
+//only for layers, so Box<dyn Layer> does not get its own as_any
+impl<T : Layer + 'static> AsAny for T {
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn as_any_mut(&mut self) -> &mut dyn Any {
+        self
+    }
+}
+
+pub trait CloneLayer {
+    fn clone_layer(&self) -> Box<dyn Layer>;
+}
+
+impl<T : Layer + Clone + 'static> CloneLayer for T {
+    fn clone_layer(&self) -> Box<dyn Layer> {
+        Box::new(self.clone())
+    }
+}
+
+impl Clone for Box<dyn Layer> {
+    fn clone(&self) -> Self {
+        self.as_ref().clone_layer()
+    }
+}
+
+//Send and Sync so agents can be evaluated on worker threads
+pub trait Layer : AsAny + CloneLayer + Send + Sync {
     fn process(&mut self, inp : &NetImage, dst : &mut NetImage);
     fn allocate_output(&mut self, inp : &NetImage) -> NetImage;
+
+    //learnable parameter buffers in fixed order, used by mutation and serialization
+    fn params(&self) -> Vec<&[f32]> {
+        vec![]
+    }
+
+    fn params_mut(&mut self) -> Vec<&mut [f32]> {
+        vec![]
+    }
+
+    fn param_count(&self) -> usize {
+        self.params().iter().map(|p| p.len()).sum()
+    }
+
+    //flat view of all parameters, dst length must equal param_count
+    fn export_params(&self, dst : &mut [f32]) {
+        assert_eq!(dst.len(), self.param_count(), "Flat parameter size mismatch");
+        let mut offset = 0;
+        for buf in self.params() {
+            dst[offset..offset + buf.len()].copy_from_slice(buf);
+            offset += buf.len();
+        }
+    }
+
+    fn import_params(&mut self, src : &[f32]) {
+        assert_eq!(src.len(), self.param_count(), "Flat parameter size mismatch");
+        let mut offset = 0;
+        for buf in self.params_mut() {
+            let len = buf.len();
+            buf.copy_from_slice(&src[offset..offset + len]);
+            offset += len;
+        }
+    }
+
+    fn params_vec(&self) -> Vec<f32> {
+        let mut res = vec![0.0; self.param_count()];
+        self.export_params(&mut res);
+        res
+    }
+
+    //range of flat parameters owned by every sub layer, single range for plain layers
+    #[allow(clippy::single_range_in_vec_init)]
+    fn param_ranges(&self) -> Vec<Range<usize>> {
+        vec![0..self.param_count()]
+    }
+
+    //writes gradient wrt input into inp_grad and accumulates parameter gradients,
+    //inp and out must be the images of the last process call
+    fn backward(&mut self, _inp : &NetImage, _out : &NetImage, _out_grad : &NetImage, _inp_grad : &mut NetImage) {
+        panic!("backward is not implemented for this layer");
+    }
+
+    //parameter buffers paired with their accumulated gradients, same order as params
+    fn params_grads_mut(&mut self) -> Vec<(&mut [f32], &mut [f32])> {
+        vec![]
+    }
+
+    fn zero_grads(&mut self) {
+        for (_, grad) in self.params_grads_mut() {
+            grad.fill(0.0);
+        }
+    }
+}
+
+//all parameter buffers of layer, one per line
+pub fn save_params(layer : &dyn Layer) -> String {
+    let mut res = String::new();
+    for buf in layer.params() {
+        let vals : Vec<String> = buf.iter().map(|v| v.to_string()).collect();
+        res.push_str(&vals.join(" "));
+        res.push('\n');
+    }
+    res
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum ParamsError {
+    //buffer index without its line
+    MissingLine(usize),
+    //buffer index, token that is not a float
+    BadValue(usize, String),
+    //buffer index, expected and found value count
+    Length(usize, usize, usize),
+    //network data without "params" line
+    MissingParams,
+    //layer name that has no descriptor
+    UnknownLayer(String),
+    //layer line with missing or unparsable arguments
+    BadArgument(String)
+}
+
+impl Display for ParamsError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ParamsError::MissingLine(idx) => write!(f, "parameter buffer {} has no line", idx),
+            ParamsError::BadValue(idx, val) => write!(f, "parameter buffer {} has bad value {:?}", idx, val),
+            ParamsError::Length(idx, expected, found) => write!(f, "parameter buffer {} needs {} values, found {}", idx, expected, found),
+            ParamsError::MissingParams => write!(f, "network has no params section"),
+            ParamsError::UnknownLayer(name) => write!(f, "unknown layer {:?}", name),
+            ParamsError::BadArgument(line) => write!(f, "bad layer arguments in {:?}", line)
+        }
+    }
+}
+
+impl std::error::Error for ParamsError {}
+
+//any whitespace separates values, layer is left unchanged on error
+pub fn load_params(layer : &mut dyn Layer, data : &str) -> Result<(), ParamsError> {
+    let mut lines = data.lines();
+    let mut parsed = vec![];
+    for (idx, buf) in layer.params().iter().enumerate() {
+        let line = lines.next().ok_or(ParamsError::MissingLine(idx))?;
+        let vals = line.split_whitespace()
+            .map(|v| v.parse::<f32>().map_err(|_| ParamsError::BadValue(idx, v.to_string())))
+            .collect::<Result<Vec<f32>, _>>()?;
+        if vals.len() != buf.len() {
+            return Err(ParamsError::Length(idx, buf.len(), vals.len()));
+        }
+        parsed.push(vals);
+    }
+    for (buf, vals) in layer.params_mut().into_iter().zip(parsed) {
+        buf.copy_from_slice(&vals);
+    }
+    Ok(())
+}
+
+//layer type with its shape arguments, same text as architecture line, None for layers that can not be saved
+pub fn layer_descriptor(layer : &dyn Layer) -> Option<String> {
+    let layer = layer.as_any();
+    let res = if let Some(l) = layer.downcast_ref::<Padding>() {
+        format!("padding {} {}", l.pad_w, l.pad_h)
+    } else if let Some(l) = layer.downcast_ref::<Conv2d>() {
+        format!("conv2d {} {} {} {}", l.w, l.h, l.in_c, l.out_c)
+    } else if let Some(l) = layer.downcast_ref::<PReLU>() {
+        format!("prelu {}", l.k.len())
+    } else if let Some(l) = layer.downcast_ref::<Dense>() {
+        format!("dense {} {}", l.in_c, l.out_c)
+    } else if let Some(l) = layer.downcast_ref::<Affine>() {
+        format!("affine {}", l.scale.len())
+    } else if let Some(l) = layer.downcast_ref::<LeakyReLU>() {
+        format!("leaky_relu {}", l.slope)
+    } else if let Some(l) = layer.downcast_ref::<Broadcast>() {
+        format!("broadcast {} {}", l.w, l.h)
+    } else if let Some(l) = layer.downcast_ref::<GlobalContext>() {
+        format!("global_context {{\n{}}}", save_architecture(&l.branch))
+    } else if layer.is::<ReLU>() {
+        String::from("relu")
+    } else if layer.is::<Tanh>() {
+        String::from("tanh")
+    } else if layer.is::<Sigmoid>() {
+        String::from("sigmoid")
+    } else if layer.is::<Softmax>() {
+        String::from("softmax")
+    } else if layer.is::<GlobalAvgPool>() {
+        String::from("global_avg_pool")
+    } else if layer.is::<GlobalMaxPool>() {
+        String::from("global_max_pool")
+    } else {
+        return None;
+    };
+    Some(res)
+}
+
+//one line per node, GlobalContext branch is nested between "global_context {" and "}"
+pub fn save_architecture(net : &SimpleNetwork) -> String {
+    let mut res = String::new();
+    for node in &net.nodes {
+        res.push_str(&layer_descriptor(node.layer.as_ref()).expect("Layer can not be saved"));
+        res.push('\n');
+    }
+    res
+}
+
+fn parse_architecture<'a, I : Iterator<Item = &'a str>>(lines : &mut I) -> Result<SimpleNetwork, ParamsError> {
+    let mut res = SimpleNetwork { nodes : vec![] };
+    while let Some(line) = lines.next() {
+        let mut parts = line.split_whitespace();
+        let name = parts.next().unwrap_or("");
+        let args : Vec<&str> = parts.collect();
+        let bad = || ParamsError::BadArgument(line.to_string());
+        let num = |idx : usize| -> Result<usize, ParamsError> { args.get(idx).and_then(|a| a.parse().ok()).ok_or_else(bad) };
+        let node = match name {
+            "}" => break,
+            "padding" => Node::new(Padding::new(num(0)?, num(1)?)),
+            "conv2d" => {
+                let (in_c, out_c, kw, kh) = (num(0)?, num(1)?, num(2)?, num(3)?);
+                Node::new(Conv2d::from_weights(in_c, out_c, kw, kh, vec![0.0; in_c * out_c * kw * kh]))
+            }
+            "prelu" => Node::new(PReLU { k : vec![0.0; num(0)?], k_grad : vec![0.0; num(0)?] }),
+            "dense" => Node::new(Dense::from_params(num(0)?, num(1)?, vec![0.0; num(0)? * num(1)?], vec![0.0; num(1)?])),
+            "affine" => Node::new(Affine::new(num(0)?)),
+            "leaky_relu" => Node::new(LeakyReLU::new(args.first().and_then(|a| a.parse().ok()).ok_or_else(bad)?)),
+            "broadcast" => Node::new(Broadcast::new(num(0)?, num(1)?)),
+            "global_context" => Node::new(GlobalContext::new(parse_architecture(lines)?)),
+            "relu" => Node::new(ReLU::new()),
+            "tanh" => Node::new(Tanh::new()),
+            "sigmoid" => Node::new(Sigmoid::new()),
+            "softmax" => Node::new(Softmax::new()),
+            "global_avg_pool" => Node::new(GlobalAvgPool::new()),
+            "global_max_pool" => Node::new(GlobalMaxPool::new()),
+            _ => return Err(ParamsError::UnknownLayer(name.to_string()))
+        };
+        res.push(node);
+    }
+    Ok(res)
 }
 
+//network with zeroed parameters
+pub fn load_architecture(data : &str) -> Result<SimpleNetwork, ParamsError> {
+    parse_architecture(&mut data.lines().filter(|l| !l.trim().is_empty()))
+}
+
+//architecture, "params" line and parameters, floats are written in exact round trip form
+pub fn save_network(net : &SimpleNetwork) -> String {
+    format!("{}params\n{}", save_architecture(net), save_params(net))
+}
+
+pub fn load_network(data : &str) -> Result<SimpleNetwork, ParamsError> {
+    let (arch, params) = data.split_once("params\n").ok_or(ParamsError::MissingParams)?;
+    let mut net = load_architecture(arch)?;
+    load_params(&mut net, params)?;
+    Ok(net)
+}
+
+#[derive(Clone)]
 pub struct Node {
     pub layer : Box<dyn Layer>,
-    pub cache : Option<NetImage>
+    pub cache : Option<NetImage>,
+    pub grad : Option<NetImage>
 }
 
 impl Node {
     pub fn new<T : Layer + 'static>(layer : T) -> Node {
         Node {
             layer : Box::new(layer),
-            cache : None
+            cache : None,
+            grad : None
         }
     }
 }
@@ -70,18 +332,20 @@ impl Node {
     }
 }
 
+#[derive(Clone)]
 pub struct PReLU {
-    pub k : Vec<f32>
+    pub k : Vec<f32>,
+    pub k_grad : Vec<f32>
 }
 
 impl PReLU {
-    pub fn new(c : usize) -> Self {
-        let mut rnd = rand::thread_rng();
+    pub fn new<R : Rng + ?Sized>(c : usize, rng : &mut R) -> Self {
         let mut k = vec![0.0; c];
         for idx in 0..c {
-            k[idx] = rnd.gen_range(-1.0..=1.0);
+            k[idx] = rng.gen_range(-1.0..=1.0);
         }
         Self {
+            k_grad : vec![0.0; c],
             k
         }
     }
@@ -107,8 +371,292 @@ impl Layer for PReLU {
     fn allocate_output(&mut self, inp: &NetImage) -> NetImage {
         NetImage::new(inp.w, inp.h, self.k.len())
     }
+
+    fn params(&self) -> Vec<&[f32]> {
+        vec![&self.k]
+    }
+
+    fn params_mut(&mut self) -> Vec<&mut [f32]> {
+        vec![&mut self.k]
+    }
+
+    fn backward(&mut self, inp : &NetImage, _out : &NetImage, out_grad : &NetImage, inp_grad : &mut NetImage) {
+        let c_count = self.k.len();
+        for ((idx, val), grad) in inp.data.iter().enumerate().zip(out_grad.data.iter()) {
+            let c = idx % c_count;
+            if *val >= 0.0 {
+                inp_grad.data[idx] = *grad;
+            } else {
+                inp_grad.data[idx] = grad * self.k[c];
+                self.k_grad[c] += grad * val;
+            }
+        }
+    }
+
+    fn params_grads_mut(&mut self) -> Vec<(&mut [f32], &mut [f32])> {
+        vec![(&mut self.k, &mut self.k_grad)]
+    }
+}
+
+#[derive(Clone)]
+pub struct ReLU {}
+
+impl ReLU {
+    pub fn new() -> Self {
+        Self {}
+    }
+}
+
+impl Default for ReLU {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Layer for ReLU {
+    fn process(&mut self, inp: &NetImage, dst: &mut NetImage) {
+        for (out, val) in dst.data.iter_mut().zip(inp.data.iter()) {
+            *out = val.max(0.0);
+        }
+    }
+
+    fn allocate_output(&mut self, inp: &NetImage) -> NetImage {
+        NetImage::new(inp.w, inp.h, inp.c)
+    }
+
+    fn backward(&mut self, inp : &NetImage, _out : &NetImage, out_grad : &NetImage, inp_grad : &mut NetImage) {
+        for ((dst, val), grad) in inp_grad.data.iter_mut().zip(inp.data.iter()).zip(out_grad.data.iter()) {
+            *dst = if *val > 0.0 { *grad } else { 0.0 };
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct LeakyReLU {
+    pub slope : f32
+}
+
+impl LeakyReLU {
+    pub fn new(slope : f32) -> Self {
+        Self {
+            slope
+        }
+    }
+}
+
+impl Layer for LeakyReLU {
+    fn process(&mut self, inp: &NetImage, dst: &mut NetImage) {
+        for (out, val) in dst.data.iter_mut().zip(inp.data.iter()) {
+            *out = if *val >= 0.0 {
+                *val
+            } else {
+                *val * self.slope
+            };
+        }
+    }
+
+    fn allocate_output(&mut self, inp: &NetImage) -> NetImage {
+        NetImage::new(inp.w, inp.h, inp.c)
+    }
+
+    fn backward(&mut self, inp : &NetImage, _out : &NetImage, out_grad : &NetImage, inp_grad : &mut NetImage) {
+        for ((dst, val), grad) in inp_grad.data.iter_mut().zip(inp.data.iter()).zip(out_grad.data.iter()) {
+            *dst = if *val >= 0.0 { *grad } else { grad * self.slope };
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct Tanh {}
+
+impl Tanh {
+    pub fn new() -> Self {
+        Self {}
+    }
+}
+
+impl Default for Tanh {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Layer for Tanh {
+    fn process(&mut self, inp: &NetImage, dst: &mut NetImage) {
+        for (out, val) in dst.data.iter_mut().zip(inp.data.iter()) {
+            *out = val.tanh();
+        }
+    }
+
+    fn allocate_output(&mut self, inp: &NetImage) -> NetImage {
+        NetImage::new(inp.w, inp.h, inp.c)
+    }
+
+    fn backward(&mut self, _inp : &NetImage, out : &NetImage, out_grad : &NetImage, inp_grad : &mut NetImage) {
+        for ((dst, y), grad) in inp_grad.data.iter_mut().zip(out.data.iter()).zip(out_grad.data.iter()) {
+            *dst = grad * (1.0 - y * y);
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct Sigmoid {}
+
+impl Sigmoid {
+    pub fn new() -> Self {
+        Self {}
+    }
+}
+
+impl Default for Sigmoid {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Layer for Sigmoid {
+    fn process(&mut self, inp: &NetImage, dst: &mut NetImage) {
+        for (out, val) in dst.data.iter_mut().zip(inp.data.iter()) {
+            *out = 1.0 / (1.0 + (-val).exp());
+        }
+    }
+
+    fn allocate_output(&mut self, inp: &NetImage) -> NetImage {
+        NetImage::new(inp.w, inp.h, inp.c)
+    }
+
+    fn backward(&mut self, _inp : &NetImage, out : &NetImage, out_grad : &NetImage, inp_grad : &mut NetImage) {
+        for ((dst, y), grad) in inp_grad.data.iter_mut().zip(out.data.iter()).zip(out_grad.data.iter()) {
+            *dst = grad * y * (1.0 - y);
+        }
+    }
+}
+
+//softmax over channels of every tile, turns output channels into action distribution
+#[derive(Clone)]
+pub struct Softmax {}
+
+impl Softmax {
+    pub fn new() -> Self {
+        Self {}
+    }
+}
+
+impl Default for Softmax {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Layer for Softmax {
+    fn process(&mut self, inp: &NetImage, dst: &mut NetImage) {
+        for y in 0..inp.h {
+            for x in 0..inp.w {
+                let in_c = inp.get_channel_slice(x, y);
+                let out_c = dst.get_channel_slice_mut(x, y);
+                let max = in_c.iter().fold(f32::NEG_INFINITY, |a, b| a.max(*b));
+                let mut sum = 0.0;
+                for (out, val) in out_c.iter_mut().zip(in_c.iter()) {
+                    *out = (val - max).exp();
+                    sum += *out;
+                }
+                for out in out_c.iter_mut() {
+                    *out /= sum;
+                }
+            }
+        }
+    }
+
+    fn allocate_output(&mut self, inp: &NetImage) -> NetImage {
+        NetImage::new(inp.w, inp.h, inp.c)
+    }
+
+    //per tile jacobian is diag(y) - y y^T
+    fn backward(&mut self, inp : &NetImage, out : &NetImage, out_grad : &NetImage, inp_grad : &mut NetImage) {
+        for y in 0..inp.h {
+            for x in 0..inp.w {
+                let out_c = out.get_channel_slice(x, y);
+                let grad_c = out_grad.get_channel_slice(x, y);
+                let dot : f32 = out_c.iter().zip(grad_c.iter()).map(|(a, b)| a * b).sum();
+                for ((dst, val), grad) in inp_grad.get_channel_slice_mut(x, y).iter_mut().zip(out_c.iter()).zip(grad_c.iter()) {
+                    *dst = val * (grad - dot);
+                }
+            }
+        }
+    }
+}
+
+//per channel scale and bias, batchnorm in inference mode folds into it
+#[derive(Clone)]
+pub struct Affine {
+    pub scale : Vec<f32>,
+    pub bias : Vec<f32>,
+    pub scale_grad : Vec<f32>,
+    pub bias_grad : Vec<f32>
+}
+
+impl Affine {
+    pub fn new(c : usize) -> Self {
+        Self {
+            scale : vec![1.0; c],
+            bias : vec![0.0; c],
+            scale_grad : vec![0.0; c],
+            bias_grad : vec![0.0; c]
+        }
+    }
+
+    pub fn from_batchnorm(mean : &[f32], var : &[f32], gamma : &[f32], beta : &[f32], eps : f32) -> Self {
+        let mut res = Affine::new(mean.len());
+        for c in 0..mean.len() {
+            res.scale[c] = gamma[c] / (var[c] + eps).sqrt();
+            res.bias[c] = beta[c] - mean[c] * res.scale[c];
+        }
+        res
+    }
+}
+
+impl Layer for Affine {
+    fn process(&mut self, inp: &NetImage, dst: &mut NetImage) {
+        for y in 0..inp.h {
+            for x in 0..inp.w {
+                let in_c = inp.get_channel_slice(x, y);
+                let out_c = dst.get_channel_slice_mut(x, y);
+                for c in 0..out_c.len() {
+                    out_c[c] = in_c[c] * self.scale[c] + self.bias[c];
+                }
+            }
+        }
+    }
+
+    fn allocate_output(&mut self, inp: &NetImage) -> NetImage {
+        assert_eq!(inp.c, self.scale.len(), "Affine channel count mismatch");
+        NetImage::new(inp.w, inp.h, inp.c)
+    }
+
+    fn params(&self) -> Vec<&[f32]> {
+        vec![&self.scale, &self.bias]
+    }
+
+    fn params_mut(&mut self) -> Vec<&mut [f32]> {
+        vec![&mut self.scale, &mut self.bias]
+    }
+
+    fn backward(&mut self, inp : &NetImage, _out : &NetImage, out_grad : &NetImage, inp_grad : &mut NetImage) {
+        let c_count = self.scale.len();
+        for (idx, (val, grad)) in inp.data.iter().zip(out_grad.data.iter()).enumerate() {
+            let c = idx % c_count;
+            inp_grad.data[idx] = grad * self.scale[c];
+            self.scale_grad[c] += grad * val;
+            self.bias_grad[c] += grad;
+        }
+    }
+
+    fn params_grads_mut(&mut self) -> Vec<(&mut [f32], &mut [f32])> {
+        vec![(&mut self.scale, &mut self.scale_grad), (&mut self.bias, &mut self.bias_grad)]
+    }
 }
 
+#[derive(Clone)]
 pub struct SimpleNetwork {
     pub nodes : Vec<Node>
 }
@@ -123,33 +671,34 @@ impl SimpleNetwork {
         self.nodes.push(node);
     }
 
-    pub fn central_conv2d(w : usize, h : usize, in_c : usize, out_c : usize) -> Self {
+    pub fn central_conv2d<R : Rng + ?Sized>(w : usize, h : usize, in_c : usize, out_c : usize, rng : &mut R) -> Self {
         let pad = Node::new(Padding::new(w / 2, h / 2));
-        let conv = Node::new(Conv2d::new(w, h, in_c, out_c));
+        let conv = Node::new(Conv2d::new(w, h, in_c, out_c, rng));
         SimpleNetwork {
             nodes : vec![pad, conv]
         }
     }
 
-    pub fn simple_maker(
+    pub fn simple_maker<R : Rng + ?Sized>(
         conv_size : usize,
         in_c : usize,
         inner_c : usize,
         out_c : usize,
-        layers : usize) -> Self {
+        layers : usize,
+        rng : &mut R) -> Self {
 
         let mut res = SimpleNetwork {
             nodes : vec![]
         };
 
-        res.extend(SimpleNetwork::central_conv2d(conv_size, conv_size, in_c, inner_c));
-        res.push(Node::new(PReLU::new(inner_c)));
+        res.extend(SimpleNetwork::central_conv2d(conv_size, conv_size, in_c, inner_c, rng));
+        res.push(Node::new(PReLU::new(inner_c, rng)));
         for idx in 0..layers {
-            res.extend(SimpleNetwork::central_conv2d(conv_size, conv_size, inner_c, inner_c));
-            res.push(Node::new(PReLU::new(inner_c)));
+            res.extend(SimpleNetwork::central_conv2d(conv_size, conv_size, inner_c, inner_c, rng));
+            res.push(Node::new(PReLU::new(inner_c, rng)));
         }
 
-        res.extend(SimpleNetwork::central_conv2d(conv_size, conv_size, inner_c, out_c));
+        res.extend(SimpleNetwork::central_conv2d(conv_size, conv_size, inner_c, out_c, rng));
 
         res
     }
@@ -180,8 +729,53 @@ impl Layer for SimpleNetwork {
         }
         self.nodes.last_mut().unwrap().cache.take().unwrap()
     }
+
+    fn params(&self) -> Vec<&[f32]> {
+        self.nodes.iter().flat_map(|n| n.layer.params()).collect()
+    }
+
+    fn params_mut(&mut self) -> Vec<&mut [f32]> {
+        self.nodes.iter_mut().flat_map(|n| n.layer.params_mut()).collect()
+    }
+
+    //one range per node, empty for nodes without parameters
+    fn param_ranges(&self) -> Vec<Range<usize>> {
+        let mut offset = 0;
+        self.nodes.iter().map(|n| {
+            let count = n.layer.param_count();
+            offset += count;
+            offset - count..offset
+        }).collect()
+    }
+
+    fn backward(&mut self, inp : &NetImage, out : &NetImage, out_grad : &NetImage, inp_grad : &mut NetImage) {
+        let last = self.nodes.len() - 1;
+        for idx in (0..=last).rev() {
+            let (before, rest) = self.nodes.split_at_mut(idx);
+            let node = &mut rest[0];
+            let (node_out, node_out_grad) = if idx == last {
+                (out, out_grad)
+            } else {
+                (node.cache.as_ref().unwrap(), node.grad.as_ref().unwrap())
+            };
+
+            if idx == 0 {
+                node.layer.backward(inp, node_out, node_out_grad, inp_grad);
+            } else {
+                let prev = &mut before[idx - 1];
+                let prev_out = prev.cache.as_ref().unwrap();
+                let prev_grad = prev.grad.get_or_insert_with(|| NetImage::new(prev_out.w, prev_out.h, prev_out.c));
+                node.layer.backward(prev_out, node_out, node_out_grad, prev_grad);
+            }
+        }
+    }
+
+    fn params_grads_mut(&mut self) -> Vec<(&mut [f32], &mut [f32])> {
+        self.nodes.iter_mut().flat_map(|n| n.layer.params_grads_mut()).collect()
+    }
 }
 
+#[derive(Clone)]
 pub struct Padding {
     pub pad_w : usize,
     pub pad_h : usize
@@ -210,22 +804,37 @@ impl Layer for Padding {
     fn allocate_output(&mut self, inp: &NetImage) -> NetImage {
         NetImage::new(inp.w + self.pad_w * 2, inp.h + self.pad_h * 2, inp.c)
     }
+
+    fn backward(&mut self, inp : &NetImage, _out : &NetImage, out_grad : &NetImage, inp_grad : &mut NetImage) {
+        for y in 0..inp.h {
+            for x in 0..inp.w {
+                inp_grad.get_channel_slice_mut(x, y)
+                    .copy_from_slice(out_grad.get_channel_slice(x + self.pad_w, y + self.pad_h));
+            }
+        }
+    }
 }
 
+#[derive(Clone)]
 pub struct Conv2d {
-    pub weights : Vec<f32>,
+    //private so every write goes through weights_mut or params_mut, which drop packed copy
+    weights : Vec<f32>,
     pub w : usize,
     pub h : usize,
     pub in_c : usize,
-    pub out_c : usize
+    pub out_c : usize,
+    pub weights_grad : Vec<f32>,
+    //weights transposed to [dy][dx * in_c + in][out], rebuilt on first process call after a change
+    packed : Vec<f32>,
+    packed_valid : bool,
+    acc : Vec<f32>
 }
 
 impl Conv2d {
-    fn new(w : usize, h : usize, in_c : usize, out_c : usize) -> Self {
-        let mut rnd = rand::thread_rng();
+    pub fn new<R : Rng + ?Sized>(w : usize, h : usize, in_c : usize, out_c : usize, rng : &mut R) -> Self {
         let mut weights = vec![0.0; w * h * in_c * out_c];
         for idx in 0..weights.len() {
-            weights[idx] = rnd.gen_range(-1.0..=1.0);
+            weights[idx] = rng.gen_range(-1.0..=1.0);
         }
 
         Self {
@@ -233,28 +842,144 @@ impl Conv2d {
             w,
             h,
             in_c,
-            out_c
+            out_c,
+            weights_grad : vec![0.0; w * h * in_c * out_c],
+            packed : vec![],
+            packed_valid : false,
+            acc : vec![]
         }
     }
-}
 
-impl Layer for Conv2d {
-    fn process(&mut self, inp: &NetImage, dst: &mut NetImage) {
-        for y in 0..dst.h {
-            for x in 0..dst.w {
-                for c in 0..self.out_c {
-                    let mut sum = 0.0_f32;
-                    for dy in 0..self.h {
-                        for dx in 0..self.w {
-                            let inp_vals = inp.get_channel_slice(x + dx, y + dy);
-                            let w_idx = ((c * self.h + dy) * self.w + dx) * self.in_c;
-                            for in_c in 0..self.in_c {
-                                sum += inp_vals[in_c] * self.weights[w_idx + in_c];
-                            }
-                        }
-                    }
-                    *dst.get_mut(x, y, c) = sum;
-                }
+    pub fn from_weights(w : usize, h : usize, in_c : usize, out_c : usize, weights : Vec<f32>) -> Self {
+        assert_eq!(weights.len(), w * h * in_c * out_c);
+        Self {
+            weights,
+            w,
+            h,
+            in_c,
+            out_c,
+            weights_grad : vec![0.0; w * h * in_c * out_c],
+            packed : vec![],
+            packed_valid : false,
+            acc : vec![]
+        }
+    }
+
+    //layout [out][dy][dx][in]
+    pub fn weights(&self) -> &[f32] {
+        &self.weights
+    }
+
+    pub fn weights_mut(&mut self) -> &mut [f32] {
+        self.packed_valid = false;
+        &mut self.weights
+    }
+
+    fn pack_weights(&mut self) {
+        if self.packed_valid {
+            return;
+        }
+        self.packed_valid = true;
+        let row_len = self.w * self.in_c;
+        self.packed.resize(self.weights.len(), 0.0);
+        for c in 0..self.out_c {
+            for dy in 0..self.h {
+                let w_row = &self.weights[(c * self.h + dy) * row_len..(c * self.h + dy + 1) * row_len];
+                for (k, val) in w_row.iter().enumerate() {
+                    self.packed[(dy * row_len + k) * self.out_c + c] = *val;
+                }
+            }
+        }
+    }
+
+    //straightforward version, kept as reference for tests and benchmarks
+    pub fn process_reference(&self, inp: &NetImage, dst: &mut NetImage) {
+        for y in 0..dst.h {
+            for x in 0..dst.w {
+                for c in 0..self.out_c {
+                    let mut sum = 0.0_f32;
+                    for dy in 0..self.h {
+                        for dx in 0..self.w {
+                            let inp_vals = inp.get_channel_slice(x + dx, y + dy);
+                            let w_idx = ((c * self.h + dy) * self.w + dx) * self.in_c;
+                            for in_c in 0..self.in_c {
+                                sum += inp_vals[in_c] * self.weights[w_idx + in_c];
+                            }
+                        }
+                    }
+                    *dst.get_mut(x, y, c) = sum;
+                }
+            }
+        }
+    }
+}
+
+//dot product with independent lanes, so compiler can vectorize it
+#[inline(always)]
+fn dot(a : &[f32], b : &[f32]) -> f32 {
+    let mut lanes = [0.0_f32; 8];
+    let a_chunks = a.chunks_exact(8);
+    let b_chunks = b.chunks_exact(8);
+    let mut tail = 0.0;
+    for (x, y) in a_chunks.remainder().iter().zip(b_chunks.remainder().iter()) {
+        tail += x * y;
+    }
+    for (x, y) in a_chunks.zip(b_chunks) {
+        for i in 0..8 {
+            lanes[i] += x[i] * y[i];
+        }
+    }
+    lanes.iter().sum::<f32>() + tail
+}
+
+impl Conv2d {
+    //few output channels: one dot product per kernel row
+    fn process_dot(&self, inp: &NetImage, dst: &mut NetImage) {
+        let row_len = self.w * self.in_c;
+        for y in 0..dst.h {
+            for x in 0..dst.w {
+                let out = dst.get_channel_slice_mut(x, y);
+                for (c, out_val) in out.iter_mut().enumerate() {
+                    let mut sum = 0.0;
+                    for dy in 0..self.h {
+                        let start = (y + dy) * inp.y_stride + x * inp.x_stride;
+                        let w_start = (c * self.h + dy) * row_len;
+                        sum += dot(&inp.data[start..start + row_len], &self.weights[w_start..w_start + row_len]);
+                    }
+                    *out_val = sum;
+                }
+            }
+        }
+    }
+}
+
+impl Layer for Conv2d {
+    //pixels of one input row are contiguous, so every kernel row is a single slice
+    //of w * in_c values, each scaled packed row is accumulated into out_c outputs
+    fn process(&mut self, inp: &NetImage, dst: &mut NetImage) {
+        if self.out_c < 8 {
+            self.process_dot(inp, dst);
+            return;
+        }
+        self.pack_weights();
+        self.acc.resize(self.out_c, 0.0);
+
+        let row_len = self.w * self.in_c;
+        let out_c = self.out_c;
+        for y in 0..dst.h {
+            for x in 0..dst.w {
+                self.acc.fill(0.0);
+                for dy in 0..self.h {
+                    let start = (y + dy) * inp.y_stride + x * inp.x_stride;
+                    let inp_row = &inp.data[start..start + row_len];
+                    let packed_row = &self.packed[dy * row_len * out_c..(dy + 1) * row_len * out_c];
+                    for (val, w_col) in inp_row.iter().zip(packed_row.chunks_exact(out_c)) {
+                        for (acc, w) in self.acc.iter_mut().zip(w_col.iter()) {
+                            *acc += val * w;
+                        }
+                    }
+                }
+                dst.get_channel_slice_mut(x, y).copy_from_slice(&self.acc);
             }
         }
     }
@@ -262,4 +987,937 @@ impl Layer for Conv2d {
     fn allocate_output(&mut self, inp: &NetImage) -> NetImage {
         NetImage::new(inp.w - self.w + 1, inp.h - self.h + 1, self.out_c)
     }
-}
\ No newline at end of file
+
+    fn params(&self) -> Vec<&[f32]> {
+        vec![&self.weights]
+    }
+
+    fn params_mut(&mut self) -> Vec<&mut [f32]> {
+        self.packed_valid = false;
+        vec![&mut self.weights]
+    }
+
+    fn backward(&mut self, inp : &NetImage, out : &NetImage, out_grad : &NetImage, inp_grad : &mut NetImage) {
+        inp_grad.data.fill(0.0);
+        let row_len = self.w * self.in_c;
+        for y in 0..out.h {
+            for x in 0..out.w {
+                let grads = out_grad.get_channel_slice(x, y);
+                for dy in 0..self.h {
+                    let start = (y + dy) * inp.y_stride + x * inp.x_stride;
+                    let inp_row = &inp.data[start..start + row_len];
+                    let inp_grad_row = &mut inp_grad.data[start..start + row_len];
+                    for (c, g) in grads.iter().enumerate() {
+                        if *g == 0.0 {
+                            continue;
+                        }
+                        let w_start = (c * self.h + dy) * row_len;
+                        let w_row = &self.weights[w_start..w_start + row_len];
+                        let w_grad_row = &mut self.weights_grad[w_start..w_start + row_len];
+                        for k in 0..row_len {
+                            inp_grad_row[k] += g * w_row[k];
+                            w_grad_row[k] += g * inp_row[k];
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    fn params_grads_mut(&mut self) -> Vec<(&mut [f32], &mut [f32])> {
+        self.packed_valid = false;
+        vec![(&mut self.weights, &mut self.weights_grad)]
+    }
+}
+#[derive(Clone)]
+pub struct Dense {
+    pub weights : Vec<f32>,
+    pub bias : Vec<f32>,
+    pub in_c : usize,
+    pub out_c : usize,
+    pub weights_grad : Vec<f32>,
+    pub bias_grad : Vec<f32>
+}
+
+impl Dense {
+    pub fn new<R : Rng + ?Sized>(in_c : usize, out_c : usize, rng : &mut R) -> Self {
+        let mut weights = vec![0.0; in_c * out_c];
+        for val in weights.iter_mut() {
+            *val = rng.gen_range(-1.0..=1.0);
+        }
+        let mut bias = vec![0.0; out_c];
+        for val in bias.iter_mut() {
+            *val = rng.gen_range(-1.0..=1.0);
+        }
+
+        Self::from_params(in_c, out_c, weights, bias)
+    }
+
+    pub fn from_params(in_c : usize, out_c : usize, weights : Vec<f32>, bias : Vec<f32>) -> Self {
+        assert_eq!(weights.len(), in_c * out_c, "Dense weight count mismatch");
+        assert_eq!(bias.len(), out_c, "Dense bias count mismatch");
+        Self {
+            weights_grad : vec![0.0; weights.len()],
+            bias_grad : vec![0.0; out_c],
+            weights,
+            bias,
+            in_c,
+            out_c
+        }
+    }
+}
+
+impl Layer for Dense {
+    //input is flattened, so 1x1 images from global pooling are the usual input
+    fn process(&mut self, inp: &NetImage, dst: &mut NetImage) {
+        for o in 0..self.out_c {
+            let row = &self.weights[o * self.in_c..(o + 1) * self.in_c];
+            let mut sum = self.bias[o];
+            for (w, v) in row.iter().zip(inp.data.iter()) {
+                sum += w * v;
+            }
+            dst.data[o] = sum;
+        }
+    }
+
+    fn allocate_output(&mut self, inp: &NetImage) -> NetImage {
+        assert_eq!(inp.data.len(), self.in_c, "Dense input size mismatch");
+        NetImage::new(1, 1, self.out_c)
+    }
+
+    fn params(&self) -> Vec<&[f32]> {
+        vec![&self.weights, &self.bias]
+    }
+
+    fn params_mut(&mut self) -> Vec<&mut [f32]> {
+        vec![&mut self.weights, &mut self.bias]
+    }
+
+    fn backward(&mut self, inp : &NetImage, _out : &NetImage, out_grad : &NetImage, inp_grad : &mut NetImage) {
+        inp_grad.data.fill(0.0);
+        for (o, grad) in out_grad.data.iter().enumerate() {
+            let row = &self.weights[o * self.in_c..(o + 1) * self.in_c];
+            let row_grad = &mut self.weights_grad[o * self.in_c..(o + 1) * self.in_c];
+            for ((w, w_grad), (val, dst)) in row.iter().zip(row_grad.iter_mut()).zip(inp.data.iter().zip(inp_grad.data.iter_mut())) {
+                *dst += grad * w;
+                *w_grad += grad * val;
+            }
+            self.bias_grad[o] += grad;
+        }
+    }
+
+    fn params_grads_mut(&mut self) -> Vec<(&mut [f32], &mut [f32])> {
+        vec![(&mut self.weights, &mut self.weights_grad), (&mut self.bias, &mut self.bias_grad)]
+    }
+}
+
+#[derive(Clone)]
+pub struct GlobalAvgPool {}
+
+impl GlobalAvgPool {
+    pub fn new() -> Self {
+        Self {}
+    }
+}
+
+impl Default for GlobalAvgPool {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Layer for GlobalAvgPool {
+    fn process(&mut self, inp: &NetImage, dst: &mut NetImage) {
+        dst.data.fill(0.0);
+        for y in 0..inp.h {
+            for x in 0..inp.w {
+                for (acc, val) in dst.data.iter_mut().zip(inp.get_channel_slice(x, y)) {
+                    *acc += val;
+                }
+            }
+        }
+        let k = 1.0 / (inp.w * inp.h) as f32;
+        for val in dst.data.iter_mut() {
+            *val *= k;
+        }
+    }
+
+    fn allocate_output(&mut self, inp: &NetImage) -> NetImage {
+        NetImage::new(1, 1, inp.c)
+    }
+
+    fn backward(&mut self, inp : &NetImage, _out : &NetImage, out_grad : &NetImage, inp_grad : &mut NetImage) {
+        let k = 1.0 / (inp.w * inp.h) as f32;
+        for y in 0..inp.h {
+            for x in 0..inp.w {
+                for (dst, grad) in inp_grad.get_channel_slice_mut(x, y).iter_mut().zip(out_grad.data.iter()) {
+                    *dst = grad * k;
+                }
+            }
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct GlobalMaxPool {}
+
+impl GlobalMaxPool {
+    pub fn new() -> Self {
+        Self {}
+    }
+}
+
+impl Default for GlobalMaxPool {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Layer for GlobalMaxPool {
+    fn process(&mut self, inp: &NetImage, dst: &mut NetImage) {
+        dst.data.fill(f32::NEG_INFINITY);
+        for y in 0..inp.h {
+            for x in 0..inp.w {
+                for (acc, val) in dst.data.iter_mut().zip(inp.get_channel_slice(x, y)) {
+                    *acc = acc.max(*val);
+                }
+            }
+        }
+    }
+
+    fn allocate_output(&mut self, inp: &NetImage) -> NetImage {
+        NetImage::new(1, 1, inp.c)
+    }
+
+    //whole gradient goes to first tile holding the maximum
+    fn backward(&mut self, inp : &NetImage, out : &NetImage, out_grad : &NetImage, inp_grad : &mut NetImage) {
+        inp_grad.data.fill(0.0);
+        for c in 0..inp.c {
+            let tile = (0..inp.w * inp.h).find(|t| inp.get(t % inp.w, t / inp.w, c) == out.data[c]);
+            if let Some(t) = tile {
+                *inp_grad.get_mut(t % inp.w, t / inp.w, c) = out_grad.data[c];
+            }
+        }
+    }
+}
+
+//tiles 1x1 vector over w x h grid
+#[derive(Clone)]
+pub struct Broadcast {
+    pub w : usize,
+    pub h : usize
+}
+
+impl Broadcast {
+    pub fn new(w : usize, h : usize) -> Self {
+        Self {
+            w,
+            h
+        }
+    }
+}
+
+impl Layer for Broadcast {
+    fn process(&mut self, inp: &NetImage, dst: &mut NetImage) {
+        for y in 0..dst.h {
+            for x in 0..dst.w {
+                dst.get_channel_slice_mut(x, y).copy_from_slice(&inp.data);
+            }
+        }
+    }
+
+    fn allocate_output(&mut self, inp: &NetImage) -> NetImage {
+        NetImage::new(self.w, self.h, inp.data.len())
+    }
+
+    fn backward(&mut self, _inp : &NetImage, out : &NetImage, out_grad : &NetImage, inp_grad : &mut NetImage) {
+        inp_grad.data.fill(0.0);
+        for y in 0..out.h {
+            for x in 0..out.w {
+                for (dst, grad) in inp_grad.data.iter_mut().zip(out_grad.get_channel_slice(x, y)) {
+                    *dst += grad;
+                }
+            }
+        }
+    }
+}
+
+//runs global branch (pooling + dense) and appends its output to every tile of input
+#[derive(Clone)]
+pub struct GlobalContext {
+    pub branch : SimpleNetwork,
+    pub broadcast : Broadcast,
+    pub branch_out : NetImage,
+    pub tiled : NetImage,
+    //backward buffers, allocated on first backward call after allocate_output
+    tiled_grad : Option<NetImage>,
+    branch_out_grad : Option<NetImage>,
+    branch_inp_grad : Option<NetImage>
+}
+
+impl GlobalContext {
+    pub fn new(branch : SimpleNetwork) -> Self {
+        Self {
+            branch,
+            broadcast : Broadcast::new(1, 1),
+            branch_out : NetImage::new(1, 1, 1),
+            tiled : NetImage::new(1, 1, 1),
+            tiled_grad : None,
+            branch_out_grad : None,
+            branch_inp_grad : None
+        }
+    }
+}
+
+impl Layer for GlobalContext {
+    fn process(&mut self, inp: &NetImage, dst: &mut NetImage) {
+        self.branch.process(inp, &mut self.branch_out);
+        self.broadcast.process(&self.branch_out, &mut self.tiled);
+
+        for y in 0..inp.h {
+            for x in 0..inp.w {
+                let out_c = dst.get_channel_slice_mut(x, y);
+                out_c[..inp.c].copy_from_slice(inp.get_channel_slice(x, y));
+                out_c[inp.c..].copy_from_slice(self.tiled.get_channel_slice(x, y));
+            }
+        }
+    }
+
+    fn allocate_output(&mut self, inp: &NetImage) -> NetImage {
+        self.branch_out = self.branch.allocate_output(inp);
+        self.broadcast = Broadcast::new(inp.w, inp.h);
+        self.tiled = self.broadcast.allocate_output(&self.branch_out);
+        self.tiled_grad = None;
+        self.branch_out_grad = None;
+        self.branch_inp_grad = None;
+        NetImage::new(inp.w, inp.h, inp.c + self.tiled.c)
+    }
+
+    fn params(&self) -> Vec<&[f32]> {
+        self.branch.params()
+    }
+
+    fn params_mut(&mut self) -> Vec<&mut [f32]> {
+        self.branch.params_mut()
+    }
+
+    //input gets gradient of passed through channels plus gradient of global branch
+    fn backward(&mut self, inp : &NetImage, _out : &NetImage, out_grad : &NetImage, inp_grad : &mut NetImage) {
+        let tiled = &self.tiled;
+        let tiled_grad = self.tiled_grad.get_or_insert_with(|| NetImage::new(tiled.w, tiled.h, tiled.c));
+        for y in 0..inp.h {
+            for x in 0..inp.w {
+                let grad_c = out_grad.get_channel_slice(x, y);
+                inp_grad.get_channel_slice_mut(x, y).copy_from_slice(&grad_c[..inp.c]);
+                tiled_grad.get_channel_slice_mut(x, y).copy_from_slice(&grad_c[inp.c..]);
+            }
+        }
+
+        let branch_out = &self.branch_out;
+        let branch_out_grad = self.branch_out_grad.get_or_insert_with(|| NetImage::new(branch_out.w, branch_out.h, branch_out.c));
+        self.broadcast.backward(branch_out, tiled, tiled_grad, branch_out_grad);
+        let branch_inp_grad = self.branch_inp_grad.get_or_insert_with(|| NetImage::new(inp.w, inp.h, inp.c));
+        self.branch.backward(inp, branch_out, branch_out_grad, branch_inp_grad);
+        for (dst, grad) in inp_grad.data.iter_mut().zip(branch_inp_grad.data.iter()) {
+            *dst += grad;
+        }
+    }
+
+    fn params_grads_mut(&mut self) -> Vec<(&mut [f32], &mut [f32])> {
+        self.branch.params_grads_mut()
+    }
+}
+
+impl SimpleNetwork {
+    //avg pool -> dense -> PReLU, used as GlobalContext branch
+    pub fn global_branch<R : Rng + ?Sized>(in_c : usize, out_c : usize, rng : &mut R) -> Self {
+        SimpleNetwork {
+            nodes : vec![
+                Node::new(GlobalAvgPool::new()),
+                Node::new(Dense::new(in_c, out_c, rng)),
+                Node::new(PReLU::new(out_c, rng))
+            ]
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Source {
+    Input,
+    Node(usize)
+}
+
+#[derive(Clone)]
+pub enum GraphOp {
+    Layer(Box<dyn Layer>),
+    Add,
+    Concat
+}
+
+#[derive(Clone)]
+pub struct GraphNode {
+    pub name : String,
+    pub inputs : Vec<Source>,
+    pub op : GraphOp,
+    pub cache : Option<NetImage>
+}
+
+//network as DAG, nodes are stored in topological order
+//first output is written into dst, all outputs can be read with get_output
+#[derive(Clone)]
+pub struct GraphNetwork {
+    pub nodes : Vec<GraphNode>,
+    pub outputs : Vec<usize>
+}
+
+impl Default for GraphNetwork {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl GraphNetwork {
+    pub fn new() -> Self {
+        GraphNetwork {
+            nodes : vec![],
+            outputs : vec![]
+        }
+    }
+
+    pub fn find(&self, name : &str) -> Option<Source> {
+        if name == "input" {
+            return Some(Source::Input);
+        }
+        self.nodes.iter().position(|n| n.name == name).map(Source::Node)
+    }
+
+    fn push_node(&mut self, name : &str, inputs : &[&str], op : GraphOp) -> Source {
+        assert!(self.find(name).is_none(), "Node {} already exists", name);
+        let inputs = inputs.iter()
+            .map(|inp| self.find(inp).unwrap_or_else(|| panic!("Unknown node {}", inp)))
+            .collect();
+        self.nodes.push(GraphNode {
+            name : name.to_string(),
+            inputs,
+            op,
+            cache : None
+        });
+        Source::Node(self.nodes.len() - 1)
+    }
+
+    pub fn layer<T : Layer + 'static>(&mut self, name : &str, input : &str, layer : T) -> Source {
+        self.push_node(name, &[input], GraphOp::Layer(Box::new(layer)))
+    }
+
+    pub fn add(&mut self, name : &str, inputs : &[&str]) -> Source {
+        self.push_node(name, inputs, GraphOp::Add)
+    }
+
+    pub fn concat(&mut self, name : &str, inputs : &[&str]) -> Source {
+        self.push_node(name, inputs, GraphOp::Concat)
+    }
+
+    pub fn set_outputs(&mut self, names : &[&str]) {
+        self.outputs = names.iter()
+            .map(|name| match self.find(name) {
+                Some(Source::Node(idx)) => idx,
+                _ => panic!("Unknown output node {}", name)
+            })
+            .collect();
+    }
+
+    pub fn get_output(&self, name : &str) -> &NetImage {
+        match self.find(name) {
+            Some(Source::Node(idx)) => self.nodes[idx].cache.as_ref().unwrap(),
+            _ => panic!("Unknown output node {}", name)
+        }
+    }
+
+    fn source<'a>(inp : &'a NetImage, done : &'a [GraphNode], src : Source) -> &'a NetImage {
+        match src {
+            Source::Input => inp,
+            Source::Node(idx) => done[idx].cache.as_ref().unwrap()
+        }
+    }
+
+    //residual tower: central conv + PReLU blocks with skip connections
+    //and separate policy and value heads
+    pub fn residual_maker<R : Rng + ?Sized>(
+        conv_size : usize,
+        in_c : usize,
+        inner_c : usize,
+        out_c : usize,
+        blocks : usize,
+        rng : &mut R) -> Self {
+
+        let mut net = GraphNetwork::new();
+        net.layer("stem_pad", "input", Padding::new(conv_size / 2, conv_size / 2));
+        net.layer("stem_conv", "stem_pad", Conv2d::new(conv_size, conv_size, in_c, inner_c, rng));
+        net.layer("block0", "stem_conv", PReLU::new(inner_c, rng));
+
+        for idx in 0..blocks {
+            let prev = format!("block{}", idx);
+            let pad = format!("pad{}", idx);
+            let conv = format!("conv{}", idx);
+            let act = format!("act{}", idx);
+            net.layer(&pad, &prev, Padding::new(conv_size / 2, conv_size / 2));
+            net.layer(&conv, &pad, Conv2d::new(conv_size, conv_size, inner_c, inner_c, rng));
+            net.layer(&act, &conv, PReLU::new(inner_c, rng));
+            net.add(&format!("block{}", idx + 1), &[&prev, &act]);
+        }
+
+        let last = format!("block{}", blocks);
+        net.concat("features", &[&last, "input"]);
+        net.layer("policy_pad", "features", Padding::new(conv_size / 2, conv_size / 2));
+        net.layer("policy", "policy_pad", Conv2d::new(conv_size, conv_size, inner_c + in_c, out_c, rng));
+        net.layer("value_pool", &last, GlobalAvgPool::new());
+        net.layer("value", "value_pool", Dense::new(inner_c, 1, rng));
+        net.set_outputs(&["policy", "value"]);
+        net
+    }
+}
+
+impl Layer for GraphNetwork {
+    fn process(&mut self, inp: &NetImage, dst: &mut NetImage) {
+        for idx in 0..self.nodes.len() {
+            let (done, rest) = self.nodes.split_at_mut(idx);
+            let node = &mut rest[0];
+            let mut cache = node.cache.take().unwrap();
+
+            match &mut node.op {
+                GraphOp::Layer(layer) => {
+                    layer.process(Self::source(inp, done, node.inputs[0]), &mut cache);
+                }
+                GraphOp::Add => {
+                    cache.data.copy_from_slice(&Self::source(inp, done, node.inputs[0]).data);
+                    for src in &node.inputs[1..] {
+                        let other = Self::source(inp, done, *src);
+                        for (acc, val) in cache.data.iter_mut().zip(other.data.iter()) {
+                            *acc += val;
+                        }
+                    }
+                }
+                GraphOp::Concat => {
+                    let mut offset = 0;
+                    for src in &node.inputs {
+                        let other = Self::source(inp, done, *src);
+                        for y in 0..cache.h {
+                            for x in 0..cache.w {
+                                cache.get_channel_slice_mut(x, y)[offset..offset + other.c]
+                                    .copy_from_slice(other.get_channel_slice(x, y));
+                            }
+                        }
+                        offset += other.c;
+                    }
+                }
+            }
+
+            node.cache = Some(cache);
+        }
+
+        let first = self.nodes[self.outputs[0]].cache.as_ref().unwrap();
+        dst.data.copy_from_slice(&first.data);
+    }
+
+    fn allocate_output(&mut self, inp: &NetImage) -> NetImage {
+        for idx in 0..self.nodes.len() {
+            let (done, rest) = self.nodes.split_at_mut(idx);
+            let node = &mut rest[0];
+            let first = Self::source(inp, done, node.inputs[0]);
+
+            let out = match &mut node.op {
+                GraphOp::Layer(layer) => layer.allocate_output(first),
+                GraphOp::Add => {
+                    for src in &node.inputs {
+                        let other = Self::source(inp, done, *src);
+                        assert!(other.w == first.w && other.h == first.h && other.c == first.c,
+                            "Add shape mismatch in node {}", node.name);
+                    }
+                    NetImage::new(first.w, first.h, first.c)
+                }
+                GraphOp::Concat => {
+                    let mut c = 0;
+                    for src in &node.inputs {
+                        let other = Self::source(inp, done, *src);
+                        assert!(other.w == first.w && other.h == first.h,
+                            "Concat shape mismatch in node {}", node.name);
+                        c += other.c;
+                    }
+                    NetImage::new(first.w, first.h, c)
+                }
+            };
+            node.cache = Some(out);
+        }
+
+        let first = self.nodes[self.outputs[0]].cache.as_ref().unwrap();
+        NetImage::new(first.w, first.h, first.c)
+    }
+
+    fn params(&self) -> Vec<&[f32]> {
+        let mut res = vec![];
+        for node in &self.nodes {
+            if let GraphOp::Layer(layer) = &node.op {
+                res.extend(layer.params());
+            }
+        }
+        res
+    }
+
+    fn params_mut(&mut self) -> Vec<&mut [f32]> {
+        let mut res = vec![];
+        for node in &mut self.nodes {
+            if let GraphOp::Layer(layer) = &mut node.op {
+                res.extend(layer.params_mut());
+            }
+        }
+        res
+    }
+
+    //one range per graph node, empty for merge nodes and layers without parameters
+    fn param_ranges(&self) -> Vec<Range<usize>> {
+        let mut offset = 0;
+        self.nodes.iter().map(|n| {
+            let count = match &n.op {
+                GraphOp::Layer(layer) => layer.param_count(),
+                _ => 0
+            };
+            offset += count;
+            offset - count..offset
+        }).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::SeedableRng;
+    use rand::rngs::StdRng;
+
+    fn rng(seed : u64) -> StdRng {
+        StdRng::seed_from_u64(seed)
+    }
+
+    fn ramp_image(w : usize, h : usize, c : usize) -> NetImage {
+        let mut img = NetImage::new(w, h, c);
+        for (idx, val) in img.data.iter_mut().enumerate() {
+            *val = idx as f32;
+        }
+        img
+    }
+
+    #[test]
+    fn test_global_pool() {
+        let inp = ramp_image(3, 2, 2);
+        let mut avg = GlobalAvgPool::new();
+        let mut avg_out = avg.allocate_output(&inp);
+        avg.process(&inp, &mut avg_out);
+        assert_eq!(avg_out.data, vec![5.0, 6.0]);
+
+        let mut max = GlobalMaxPool::new();
+        let mut max_out = max.allocate_output(&inp);
+        max.process(&inp, &mut max_out);
+        assert_eq!(max_out.data, vec![10.0, 11.0]);
+    }
+
+    #[test]
+    fn test_global_context() {
+        let inp = ramp_image(4, 3, 2);
+        let mut layer = GlobalContext::new(SimpleNetwork::global_branch(2, 3, &mut rng(0)));
+        let mut out = layer.allocate_output(&inp);
+        layer.process(&inp, &mut out);
+
+        assert_eq!((out.w, out.h, out.c), (4, 3, 5));
+        assert_eq!(&out.get_channel_slice(2, 1)[..2], inp.get_channel_slice(2, 1));
+        assert_eq!(&out.get_channel_slice(0, 0)[2..], &out.get_channel_slice(3, 2)[2..]);
+    }
+
+    #[test]
+    fn test_conv2d_matches_reference() {
+        let mut rnd = rng(1);
+        let mut inp = NetImage::new(16, 9, 5);
+        for val in inp.data.iter_mut() {
+            *val = if rnd.gen_bool(0.3) { 0.0 } else { rnd.gen_range(-2.0..=2.0) };
+        }
+
+        for (w, h, out_c) in [(1, 1, 7), (3, 3, 7), (5, 3, 16), (3, 5, 9)] {
+            let mut conv = Conv2d::new(w, h, 5, out_c, &mut rnd);
+            let mut fast = conv.allocate_output(&inp);
+            let mut reference = conv.allocate_output(&inp);
+            conv.process(&inp, &mut fast);
+            conv.process_reference(&inp, &mut reference);
+
+            for (a, b) in fast.data.iter().zip(reference.data.iter()) {
+                assert!((a - b).abs() <= 1e-4 * (1.0 + b.abs()), "{} != {}", a, b);
+            }
+        }
+    }
+
+    //packed weights are cached, changes through params_mut must still be seen
+    #[test]
+    fn test_conv2d_repacks_after_change() {
+        let mut rnd = rng(2);
+        let inp = random_image(6, 5, 3, &mut rnd);
+        let mut conv = Conv2d::new(3, 3, 3, 8, &mut rnd);
+        let mut fast = conv.allocate_output(&inp);
+        let mut reference = conv.allocate_output(&inp);
+        conv.process(&inp, &mut fast);
+
+        for buf in conv.params_mut() {
+            for val in buf.iter_mut() {
+                *val = rnd.gen_range(-1.0..=1.0);
+            }
+        }
+        conv.process(&inp, &mut fast);
+        conv.process_reference(&inp, &mut reference);
+        for (a, b) in fast.data.iter().zip(reference.data.iter()) {
+            assert!((a - b).abs() <= 1e-4 * (1.0 + b.abs()), "{} != {}", a, b);
+        }
+
+        conv.weights_mut()[0] += 1.0;
+        conv.process(&inp, &mut fast);
+        conv.process_reference(&inp, &mut reference);
+        for (a, b) in fast.data.iter().zip(reference.data.iter()) {
+            assert!((a - b).abs() <= 1e-4 * (1.0 + b.abs()), "{} != {}", a, b);
+        }
+    }
+
+    fn random_image(w : usize, h : usize, c : usize, rnd : &mut StdRng) -> NetImage {
+        let mut img = NetImage::new(w, h, c);
+        for val in img.data.iter_mut() {
+            *val = rnd.gen_range(-1.0..=1.0);
+        }
+        img
+    }
+
+    //loss = sum(out * weights), so out_grad equals weights
+    fn weighted_loss(net : &mut SimpleNetwork, inp : &NetImage, weights : &NetImage) -> f64 {
+        let mut out = net.allocate_output(inp);
+        net.process(inp, &mut out);
+        out.data.iter().zip(weights.data.iter()).map(|(a, b)| *a as f64 * *b as f64).sum()
+    }
+
+    //forward and backward differences agree unless perturbation crosses a kink of PReLU,
+    //ReLU or max pool, None in that case; smooth layers only bend them apart by O(eps)
+    //smaller steps are tried before giving up, kinks are usually close to the center only by chance
+    fn numeric_grad<F : FnMut(f32) -> f64>(mut loss : F) -> Option<f32> {
+        let center = loss(0.0);
+        for eps in [1e-2, 3e-3, 1e-3] {
+            let forward = (loss(eps) - center) / eps as f64;
+            let backward = (center - loss(-eps)) / eps as f64;
+            if (forward - backward).abs() <= 5e-3 * (1.0 + forward.abs()) {
+                return Some(((forward + backward) / 2.0) as f32);
+            }
+        }
+        None
+    }
+
+    //returns whether the gradient was actually compared
+    fn check_grad(analytic : f32, numeric : Option<f32>) -> bool {
+        if let Some(numeric) = numeric {
+            assert!((numeric - analytic).abs() <= 1e-2 * (1.0 + analytic.abs()),
+                "numeric {} analytic {}", numeric, analytic);
+        }
+        numeric.is_some()
+    }
+
+    //skipped kinks must stay rare, otherwise the check passes without comparing anything
+    fn assert_mostly_checked(checked : usize, total : usize) {
+        assert!(checked * 10 >= total * 8, "only {} of {} gradients checked", checked, total);
+    }
+
+    //compares backward of net against finite differences on random parameters and inputs
+    fn check_gradients(net : &mut SimpleNetwork, inp : &NetImage, rnd : &mut StdRng) {
+        let mut out = net.allocate_output(inp);
+        let loss_weights = random_image(out.w, out.h, out.c, rnd);
+
+        net.process(inp, &mut out);
+        let mut inp_grad = NetImage::new(inp.w, inp.h, inp.c);
+        net.zero_grads();
+        net.backward(inp, &out, &loss_weights, &mut inp_grad);
+
+        let analytic : Vec<Vec<f32>> = net.params_grads_mut().into_iter().map(|(_, g)| g.to_vec()).collect();
+        assert_eq!(analytic.len(), net.params().len());
+        let mut checked = 0;
+        for (buf_idx, buf_grad) in analytic.iter().enumerate() {
+            for _ in 0..5 {
+                let idx = rnd.gen_range(0..buf_grad.len());
+                let orig = net.params()[buf_idx][idx];
+                let numeric = numeric_grad(|delta| {
+                    net.params_mut()[buf_idx][idx] = orig + delta;
+                    weighted_loss(net, inp, &loss_weights)
+                });
+                net.params_mut()[buf_idx][idx] = orig;
+                checked += check_grad(buf_grad[idx], numeric) as usize;
+            }
+        }
+        assert_mostly_checked(checked, analytic.len() * 5);
+
+        let mut checked = 0;
+        for _ in 0..10 {
+            let idx = rnd.gen_range(0..inp.data.len());
+            let numeric = numeric_grad(|delta| {
+                let mut shifted = NetImage::new(inp.w, inp.h, inp.c);
+                shifted.data.copy_from_slice(&inp.data);
+                shifted.data[idx] += delta;
+                weighted_loss(net, &shifted, &loss_weights)
+            });
+            checked += check_grad(inp_grad.data[idx], numeric) as usize;
+        }
+        assert_mostly_checked(checked, 10);
+    }
+
+    #[test]
+    fn test_gradient_check() {
+        let mut rnd = rng(2);
+        let inp = random_image(6, 5, 3, &mut rnd);
+        let mut net = SimpleNetwork::simple_maker(3, 3, 4, 2, 1, &mut rnd);
+        check_gradients(&mut net, &inp, &mut rnd);
+    }
+
+    #[test]
+    fn test_gradient_check_all_layers() {
+        let mut rnd = rng(3);
+        let inp = random_image(5, 4, 3, &mut rnd);
+        let mut affine = Affine::new(4);
+        for val in affine.scale.iter_mut().chain(affine.bias.iter_mut()) {
+            *val = rnd.gen_range(-1.0..=1.0);
+        }
+        let mut net = SimpleNetwork::central_conv2d(3, 3, 3, 4, &mut rnd);
+        net.push(Node::new(affine));
+        net.push(Node::new(LeakyReLU::new(0.125)));
+        net.push(Node::new(GlobalContext::new(SimpleNetwork {
+            nodes : vec![Node::new(GlobalAvgPool::new()), Node::new(Dense::new(4, 3, &mut rnd)), Node::new(Sigmoid::new())]
+        })));
+        net.extend(SimpleNetwork::central_conv2d(3, 3, 7, 4, &mut rnd));
+        net.push(Node::new(ReLU::new()));
+        net.push(Node::new(GlobalContext::new(SimpleNetwork {
+            nodes : vec![Node::new(GlobalMaxPool::new()), Node::new(Dense::new(4, 2, &mut rnd)), Node::new(Tanh::new())]
+        })));
+        net.extend(SimpleNetwork::central_conv2d(3, 3, 6, 3, &mut rnd));
+        net.push(Node::new(Softmax::new()));
+        check_gradients(&mut net, &inp, &mut rnd);
+    }
+
+    #[test]
+    fn test_softmax() {
+        let inp = ramp_image(3, 3, 4);
+        let mut layer = Softmax::new();
+        let mut out = layer.allocate_output(&inp);
+        layer.process(&inp, &mut out);
+
+        let sum : f32 = out.get_channel_slice(1, 2).iter().sum();
+        assert!((sum - 1.0).abs() < 1e-5);
+        assert!(out.get(1, 2, 3) > out.get(1, 2, 0));
+    }
+
+    #[test]
+    fn test_params_roundtrip() {
+        let mut src = SimpleNetwork::simple_maker(3, 4, 4, 2, 1, &mut rng(3));
+        src.push(Node::new(Affine::new(2)));
+        src.nodes.last_mut().unwrap().layer.params_mut()[1][1] = 0.5;
+        let data = save_params(&src);
+
+        let mut dst = SimpleNetwork::simple_maker(3, 4, 4, 2, 1, &mut rng(4));
+        dst.push(Node::new(Affine::new(2)));
+        load_params(&mut dst, &data).unwrap();
+
+        assert_eq!(src.params(), dst.params());
+    }
+
+    #[test]
+    fn test_load_params_errors() {
+        let mut layer = Affine::new(2);
+        assert_eq!(load_params(&mut layer, "1 2 \n 0.5  0.25\n"), Ok(()));
+        assert_eq!(layer.params(), vec![&[1.0, 2.0][..], &[0.5, 0.25][..]]);
+
+        assert_eq!(load_params(&mut layer, "1 2\n"), Err(ParamsError::MissingLine(1)));
+        assert_eq!(load_params(&mut layer, "1 x\n3 4\n"), Err(ParamsError::BadValue(0, String::from("x"))));
+        assert_eq!(load_params(&mut layer, "1 2\n3\n"), Err(ParamsError::Length(1, 2, 1)));
+        assert_eq!(layer.params(), vec![&[1.0, 2.0][..], &[0.5, 0.25][..]]);
+    }
+
+    #[test]
+    fn test_network_roundtrip() {
+        let mut src = SimpleNetwork::simple_maker(3, 4, 4, 2, 1, &mut rng(8));
+        src.push(Node::new(GlobalContext::new(SimpleNetwork::global_branch(2, 3, &mut rng(9)))));
+        src.push(Node::new(Affine::new(5)));
+        src.push(Node::new(LeakyReLU::new(0.125)));
+        src.nodes[1].layer.params_mut()[0][0] = 1.0 / 3.0;
+
+        let data = save_network(&src);
+        let mut dst = load_network(&data).unwrap();
+        assert_eq!(save_network(&dst), data);
+        assert_eq!(src.params_vec(), dst.params_vec());
+
+        let inp = ramp_image(4, 3, 4);
+        let mut a = src.allocate_output(&inp);
+        let mut b = dst.allocate_output(&inp);
+        src.process(&inp, &mut a);
+        dst.process(&inp, &mut b);
+        assert_eq!(a.data, b.data);
+    }
+
+    #[test]
+    fn test_network_load_errors() {
+        let data = save_network(&SimpleNetwork::simple_maker(2, 3, 2, 1, 0, &mut rng(4)));
+        let (arch, _) = data.split_once("params\n").unwrap();
+
+        assert_eq!(load_network(arch).err(), Some(ParamsError::MissingParams));
+        assert_eq!(load_network("conv3d 1 2\nparams\n").err(), Some(ParamsError::UnknownLayer(String::from("conv3d"))));
+        assert_eq!(load_network("conv2d 3 2 3\nparams\n").err(), Some(ParamsError::BadArgument(String::from("conv2d 3 2 3"))));
+        assert_eq!(load_network("leaky_relu x\nparams\n").err(), Some(ParamsError::BadArgument(String::from("leaky_relu x"))));
+        //truncated parameter section
+        let truncated = data.trim_end().rsplit_once('\n').unwrap().0;
+        assert!(matches!(load_network(truncated), Err(ParamsError::MissingLine(_))));
+    }
+
+    #[test]
+    fn test_flat_params() {
+        let mut net = SimpleNetwork::simple_maker(3, 4, 5, 2, 1, &mut rng(5));
+        let ranges = net.param_ranges();
+        assert_eq!(ranges.len(), net.nodes.len());
+        assert_eq!(ranges.last().unwrap().end, net.param_count());
+        //padding has no parameters, conv 3x3x4x5, PReLU 5
+        assert_eq!(ranges[0], 0..0);
+        assert_eq!(ranges[1], 0..180);
+        assert_eq!(ranges[2], 180..185);
+
+        let mut flat = net.params_vec();
+        flat[182] = 42.0;
+        net.import_params(&flat);
+        let prelu = net.nodes[2].layer.as_any().downcast_ref::<PReLU>().unwrap();
+        assert_eq!(prelu.k[2], 42.0);
+        assert_eq!(net.params_vec(), flat);
+    }
+
+    #[test]
+    fn test_graph_residual() {
+        let inp = ramp_image(5, 4, 3);
+
+        let mut net = GraphNetwork::new();
+        net.layer("act", "input", PReLU::new(3, &mut rng(6)));
+        net.add("sum", &["input", "act"]);
+        net.concat("cat", &["sum", "input"]);
+        net.set_outputs(&["cat", "sum"]);
+
+        let mut out = net.allocate_output(&inp);
+        net.process(&inp, &mut out);
+
+        assert_eq!((out.w, out.h, out.c), (5, 4, 6));
+        //input is non negative, so PReLU is identity
+        assert_eq!(out.get(2, 3, 1), inp.get(2, 3, 1) * 2.0);
+        assert_eq!(out.get(2, 3, 4), inp.get(2, 3, 1));
+        assert_eq!(net.get_output("sum").c, 3);
+    }
+
+    #[test]
+    fn test_residual_maker_heads() {
+        let inp = ramp_image(12, 6, 4);
+        let mut net = GraphNetwork::residual_maker(3, 4, 8, 4, 2, &mut rng(7));
+        let mut out = net.allocate_output(&inp);
+        net.process(&inp, &mut out);
+
+        assert_eq!((out.w, out.h, out.c), (12, 6, 4));
+        assert_eq!(net.get_output("value").data.len(), 1);
+    }
+}
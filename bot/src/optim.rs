@@ -0,0 +1,151 @@
+use crate::net::Layer;
+
+pub trait Optimizer {
+    //applies accumulated gradients of layer and zeroes them
+    fn step(&mut self, layer : &mut dyn Layer);
+}
+
+pub struct Sgd {
+    pub lr : f32,
+    pub momentum : f32,
+    pub velocity : Vec<Vec<f32>>
+}
+
+impl Sgd {
+    pub fn new(lr : f32, momentum : f32) -> Self {
+        Self {
+            lr,
+            momentum,
+            velocity : vec![]
+        }
+    }
+}
+
+impl Optimizer for Sgd {
+    fn step(&mut self, layer : &mut dyn Layer) {
+        let mut bufs = layer.params_grads_mut();
+        self.velocity.resize(bufs.len(), vec![]);
+        for ((params, grads), vel) in bufs.iter_mut().zip(self.velocity.iter_mut()) {
+            vel.resize(params.len(), 0.0);
+            for idx in 0..params.len() {
+                vel[idx] = self.momentum * vel[idx] - self.lr * grads[idx];
+                params[idx] += vel[idx];
+                grads[idx] = 0.0;
+            }
+        }
+    }
+}
+
+pub struct Adam {
+    pub lr : f32,
+    pub beta1 : f32,
+    pub beta2 : f32,
+    pub eps : f32,
+    pub t : i32,
+    pub m : Vec<Vec<f32>>,
+    pub v : Vec<Vec<f32>>
+}
+
+impl Adam {
+    pub fn new(lr : f32) -> Self {
+        Self {
+            lr,
+            beta1 : 0.9,
+            beta2 : 0.999,
+            eps : 1e-8,
+            t : 0,
+            m : vec![],
+            v : vec![]
+        }
+    }
+
+    //single Adam update of flat parameters with given gradient, used by optimizers
+    //that work without Layer gradients
+    pub fn update(&mut self, params : &mut [f32], grads : &[f32]) {
+        self.t += 1;
+        self.m.resize(1, vec![]);
+        self.v.resize(1, vec![]);
+        let (k1, k2) = self.bias_correction();
+        Self::apply(self.lr, self.beta1, self.beta2, self.eps, k1, k2,
+            params, grads, &mut self.m[0], &mut self.v[0]);
+    }
+
+    fn bias_correction(&self) -> (f32, f32) {
+        (1.0 - self.beta1.powi(self.t), 1.0 - self.beta2.powi(self.t))
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn apply(lr : f32, beta1 : f32, beta2 : f32, eps : f32, k1 : f32, k2 : f32,
+             params : &mut [f32], grads : &[f32], m : &mut Vec<f32>, v : &mut Vec<f32>) {
+        m.resize(params.len(), 0.0);
+        v.resize(params.len(), 0.0);
+        for idx in 0..params.len() {
+            let g = grads[idx];
+            m[idx] = beta1 * m[idx] + (1.0 - beta1) * g;
+            v[idx] = beta2 * v[idx] + (1.0 - beta2) * g * g;
+            let m_hat = m[idx] / k1;
+            let v_hat = v[idx] / k2;
+            params[idx] -= lr * m_hat / (v_hat.sqrt() + eps);
+        }
+    }
+}
+
+impl Optimizer for Adam {
+    fn step(&mut self, layer : &mut dyn Layer) {
+        self.t += 1;
+        let (k1, k2) = self.bias_correction();
+        let mut bufs = layer.params_grads_mut();
+        self.m.resize(bufs.len(), vec![]);
+        self.v.resize(bufs.len(), vec![]);
+        for (idx, (params, grads)) in bufs.iter_mut().enumerate() {
+            Self::apply(self.lr, self.beta1, self.beta2, self.eps, k1, k2,
+                params, grads, &mut self.m[idx], &mut self.v[idx]);
+            grads.fill(0.0);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::net::{NetImage, SimpleNetwork};
+    use crate::rng::stream_rng;
+
+    //fit small network to copy first input channel
+    fn train(opt : &mut dyn Optimizer) -> (f32, f32) {
+        let mut net = SimpleNetwork::central_conv2d(3, 3, 2, 1, &mut stream_rng(0, 0));
+        let mut inp = NetImage::new(5, 5, 2);
+        for (idx, val) in inp.data.iter_mut().enumerate() {
+            *val = ((idx * 7) % 5) as f32 - 2.0;
+        }
+        let mut out = net.allocate_output(&inp);
+        let mut grad = NetImage::new(out.w, out.h, out.c);
+        let mut inp_grad = NetImage::new(inp.w, inp.h, inp.c);
+
+        let mut losses = vec![];
+        for _ in 0..300 {
+            net.process(&inp, &mut out);
+            let mut loss = 0.0;
+            for y in 0..out.h {
+                for x in 0..out.w {
+                    let diff = out.get(x, y, 0) - inp.get(x, y, 0);
+                    loss += diff * diff;
+                    *grad.get_mut(x, y, 0) = 2.0 * diff;
+                }
+            }
+            losses.push(loss);
+            net.backward(&inp, &out, &grad, &mut inp_grad);
+            opt.step(&mut net);
+        }
+        (losses[0], *losses.last().unwrap())
+    }
+
+    #[test]
+    fn test_optimizers_reduce_loss() {
+        let (start, end) = train(&mut Sgd::new(1e-3, 0.9));
+        assert!(end < start * 0.01, "sgd {} -> {}", start, end);
+
+        let (start, end) = train(&mut Adam::new(5e-2));
+        assert!(end < start * 0.01, "adam {} -> {}", start, end);
+    }
+}
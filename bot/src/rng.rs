@@ -0,0 +1,41 @@
+use rand::SeedableRng;
+use rand::rngs::StdRng;
+
+//splitmix64 finalizer, spreads close seeds over whole range
+pub fn mix(seed : u64) -> u64 {
+    let mut z = seed.wrapping_add(0x9E37_79B9_7F4A_7C15);
+    z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+    z ^ (z >> 31)
+}
+
+//seed of independent stream derived from master seed, same pair always gives same seed
+pub fn stream_seed(seed : u64, stream : u64) -> u64 {
+    mix(mix(seed) ^ stream.wrapping_mul(0xD6E8_FEB8_6659_FD93))
+}
+
+pub fn stream_rng(seed : u64, stream : u64) -> StdRng {
+    StdRng::seed_from_u64(stream_seed(seed, stream))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::Rng;
+    use crate::net::{Layer, SimpleNetwork};
+
+    #[test]
+    fn test_streams() {
+        let a : Vec<u32> = stream_rng(42, 3).sample_iter(rand::distributions::Standard).take(8).collect();
+        let b : Vec<u32> = stream_rng(42, 3).sample_iter(rand::distributions::Standard).take(8).collect();
+        let c : Vec<u32> = stream_rng(42, 4).sample_iter(rand::distributions::Standard).take(8).collect();
+        let d : Vec<u32> = stream_rng(43, 3).sample_iter(rand::distributions::Standard).take(8).collect();
+        assert_eq!(a, b);
+        assert_ne!(a, c);
+        assert_ne!(a, d);
+
+        let na = SimpleNetwork::simple_maker(3, 4, 4, 4, 1, &mut stream_rng(1, 0));
+        let nb = SimpleNetwork::simple_maker(3, 4, 4, 4, 1, &mut stream_rng(1, 0));
+        assert_eq!(na.params_vec(), nb.params_vec());
+    }
+}
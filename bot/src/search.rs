@@ -0,0 +1,785 @@
+use std::time::{Duration, Instant};
+use rand::{Rng, SeedableRng};
+use rand::rngs::StdRng;
+use crate::analysis::{distances_from, is_walkable, side_distances, walkable_neighbours};
+use crate::encoding::{decode_actions, decode_value, encode_actions, fill_input, ACTION_CHANNELS, INPUT_CHANNELS};
+use crate::net::{Layer, NetImage, SimpleNetwork};
+use crate::rng::mix;
+use crate::{Action, BuildAction, Map, MoveAction, SpawnAction, TileOwner, TurnScratch, UNREACHABLE};
+
+pub fn opponent(side : &TileOwner) -> TileOwner {
+    match side {
+        TileOwner::Me => {TileOwner::Enemy}
+        TileOwner::Enemy => {TileOwner::Me}
+        TileOwner::No => {panic!("Unsupported owner")}
+    }
+}
+
+//candidate action set, rebuilt from current state every time it is played
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Policy {
+    //units go to closest tile not owned by side, spawns next to it
+    Expand,
+    //units and spawns go toward enemy tiles
+    Attack,
+    //stacks next to enemy units hold and get reinforced, others expand
+    Defend,
+    //recycler on owned tile with best yield, units expand
+    Recycle,
+    //random targets, same seed and state give same actions
+    Random(u64),
+    //decoded output of SearchBot network, policy_actions alone gives no actions
+    Network
+}
+
+fn unit_count(map : &Map, idx : usize, side : &TileOwner) -> u32 {
+    if map.data[idx].owner == *side { map.data[idx].units.unsigned_abs() } else { 0 }
+}
+
+//move of whole stack to neighbour one step closer to sources of dist
+fn step_towards(map : &Map, dist : &[u16], idx : usize, amount : u32) -> Option<Action> {
+    if dist[idx] == UNREACHABLE {
+        return None;
+    }
+    let next = walkable_neighbours(map, idx).filter(|n| dist[*n] < dist[idx]).min_by_key(|n| dist[*n])?;
+    Some(Action::Move(MoveAction {
+        amount,
+        fromX : idx % map.w,
+        fromY : idx / map.w,
+        toX : next % map.w,
+        toY : next / map.w
+    }))
+}
+
+//spawns all affordable units on owned tile closest to sources of dist
+fn spawn_near(map : &Map, side : &TileOwner, dist : &[u16], res : &mut Vec<Action>) {
+    let scrap = if *side == TileOwner::Me { map.my_scrap } else { map.enemy_scrap };
+    let amount = (scrap / 10).min(3);
+    let best = (0..map.data.len())
+        .filter(|idx| map.data[*idx].owner == *side && is_walkable(&map.data[*idx]) && dist[*idx] != UNREACHABLE)
+        .min_by_key(|idx| dist[*idx]);
+    if let (Some(idx), true) = (best, amount > 0) {
+        res.push(Action::Spawn(SpawnAction { amount : amount as u32, x : idx % map.w, y : idx / map.w }));
+    }
+}
+
+fn recycler_yield(map : &Map, idx : usize) -> i32 {
+    let center = map.data[idx].scrap_amount;
+    let x = idx % map.w;
+    let y = idx / map.w;
+    let neighbours = [
+        (x > 0).then(|| idx - 1),
+        (y > 0).then(|| idx - map.w),
+        (x + 1 < map.w).then(|| idx + 1),
+        (y + 1 < map.h).then(|| idx + map.w)
+    ];
+    center + neighbours.iter().flatten().map(|n| map.data[*n].scrap_amount.min(center)).sum::<i32>()
+}
+
+pub fn policy_actions(map : &Map, side : &TileOwner, policy : Policy) -> Vec<Action> {
+    let mut res = vec![];
+    let other = opponent(side);
+    let stacks : Vec<usize> = (0..map.data.len()).filter(|idx| unit_count(map, *idx, side) > 0).collect();
+
+    match policy {
+        Policy::Expand | Policy::Recycle => {
+            let free = distances_from(map, |tile| tile.owner != *side);
+            res.extend(stacks.iter().filter_map(|idx| step_towards(map, &free, *idx, unit_count(map, *idx, side))));
+            if policy == Policy::Recycle {
+                let best = (0..map.data.len())
+                    .filter(|idx| map.data[*idx].owner == *side && map.data[*idx].units == 0 && is_walkable(&map.data[*idx]))
+                    .max_by_key(|idx| recycler_yield(map, *idx));
+                if let Some(idx) = best {
+                    res.push(Action::Build(BuildAction { x : idx % map.w, y : idx / map.w }));
+                }
+            }
+            spawn_near(map, side, &free, &mut res);
+        }
+        Policy::Attack => {
+            let enemy = side_distances(map, &other);
+            res.extend(stacks.iter().filter_map(|idx| step_towards(map, &enemy, *idx, unit_count(map, *idx, side))));
+            spawn_near(map, side, &enemy, &mut res);
+        }
+        Policy::Defend => {
+            let threat = distances_from(map, |tile| tile.owner == other && tile.units != 0);
+            let free = distances_from(map, |tile| tile.owner != *side);
+            for idx in stacks.iter() {
+                if threat[*idx] > 1 {
+                    res.extend(step_towards(map, &free, *idx, unit_count(map, *idx, side)));
+                }
+            }
+            spawn_near(map, side, &threat, &mut res);
+        }
+        Policy::Random(seed) => {
+            let mut rnd = StdRng::seed_from_u64(mix(seed ^ map.board_hash));
+            for idx in stacks.iter() {
+                res.push(Action::Move(MoveAction {
+                    amount : unit_count(map, *idx, side),
+                    fromX : idx % map.w,
+                    fromY : idx / map.w,
+                    toX : rnd.gen_range(0..map.w),
+                    toY : rnd.gen_range(0..map.h)
+                }));
+            }
+            let owned : Vec<usize> = (0..map.data.len())
+                .filter(|idx| map.data[*idx].owner == *side && is_walkable(&map.data[*idx]))
+                .collect();
+            if !owned.is_empty() && rnd.gen_bool(0.5) {
+                let idx = owned[rnd.gen_range(0..owned.len())];
+                res.push(Action::Spawn(SpawnAction { amount : 1, x : idx % map.w, y : idx / map.w }));
+            }
+        }
+        Policy::Network => {}
+    }
+    res
+}
+
+//stay, +x, -x, +y, -y; per tile move priors are indexed by these
+pub const MOVE_DIRECTIONS : [(i32, i32); 5] = [(0, 0), (1, 0), (-1, 0), (0, 1), (0, -1)];
+
+//index in MOVE_DIRECTIONS of first step of move, larger axis wins like in decode_actions
+fn move_direction(mv : &MoveAction) -> usize {
+    let dx = mv.toX as i64 - mv.fromX as i64;
+    let dy = mv.toY as i64 - mv.fromY as i64;
+    if dx == 0 && dy == 0 {
+        0
+    } else if dx.abs() >= dy.abs() {
+        if dx > 0 { 1 } else { 2 }
+    } else if dy > 0 {
+        3
+    } else {
+        4
+    }
+}
+
+//network output as policy: action channels give per tile priors, VALUE_CHANNEL gives value if present
+#[derive(Clone)]
+pub struct NetworkPolicy {
+    pub network : SimpleNetwork,
+    pub input : NetImage,
+    pub output : NetImage,
+    encoded : NetImage
+}
+
+impl NetworkPolicy {
+    pub fn new(network : SimpleNetwork) -> Self {
+        Self {
+            network,
+            input : NetImage::new(1, 1, 1),
+            output : NetImage::new(1, 1, 1),
+            encoded : NetImage::new(1, 1, 1)
+        }
+    }
+
+    //fills output for map seen from side
+    pub fn run(&mut self, map : &Map, side : &TileOwner) {
+        if self.input.w != map.w || self.input.h != map.h {
+            self.input = NetImage::new(map.w, map.h, INPUT_CHANNELS);
+            self.output = self.network.allocate_output(&self.input);
+            self.encoded = NetImage::new(map.w, map.h, ACTION_CHANNELS);
+        }
+        fill_input(map, side, &mut self.input);
+        self.network.process(&self.input, &mut self.output);
+    }
+
+    pub fn actions(&mut self, map : &Map, side : &TileOwner) -> Vec<Action> {
+        self.run(map, side);
+        decode_actions(map, side, &self.output)
+    }
+
+    //value of last run from its side point of view
+    pub fn value(&self) -> Option<f32> {
+        decode_value(&self.output)
+    }
+
+    //softmax over MOVE_DIRECTIONS for every tile with side units, zeros elsewhere,
+    //logit is move channels projected on direction per unit, unwalkable targets get 0
+    pub fn tile_priors(&self, map : &Map, side : &TileOwner, temperature : f32) -> Vec<[f32; 5]> {
+        assert!(temperature > 0.0, "Prior temperature must be positive");
+        let mut res = vec![[0.0; 5]; map.data.len()];
+        for (idx, prior) in res.iter_mut().enumerate() {
+            let units = unit_count(map, idx, side);
+            if units == 0 {
+                continue;
+            }
+            let (x, y) = ((idx % map.w) as i32, (idx / map.w) as i32);
+            let (mx, my) = (self.output.get(x as usize, y as usize, 2), self.output.get(x as usize, y as usize, 3));
+            let mut logits = [f32::NEG_INFINITY; 5];
+            for (k, (dx, dy)) in MOVE_DIRECTIONS.iter().enumerate() {
+                let (nx, ny) = (x + dx, y + dy);
+                if nx < 0 || ny < 0 || nx >= map.w as i32 || ny >= map.h as i32 {
+                    continue;
+                }
+                if k > 0 && !is_walkable(&map.data[ny as usize * map.w + nx as usize]) {
+                    continue;
+                }
+                logits[k] = (*dx as f32 * mx + *dy as f32 * my) / units as f32 / temperature;
+            }
+            let best = logits.iter().cloned().fold(f32::NEG_INFINITY, f32::max);
+            for (p, l) in prior.iter_mut().zip(logits.iter()) {
+                *p = (l - best).exp();
+            }
+            let sum : f32 = prior.iter().sum();
+            prior.iter_mut().for_each(|p| *p /= sum);
+        }
+        res
+    }
+
+    //moves whose direction prior is below threshold times best prior of their tile go one step
+    //in best direction instead, or are dropped when staying is best, uses last run
+    pub fn prune_moves(&self, map : &Map, side : &TileOwner, actions : Vec<Action>, temperature : f32, threshold : f32) -> Vec<Action> {
+        let priors = self.tile_priors(map, side, temperature);
+        actions.into_iter().filter_map(|a| {
+            let mv = match a {
+                Action::Move(mv) if mv.fromX < map.w && mv.fromY < map.h => {mv}
+                other => {return Some(other)}
+            };
+            let prior = &priors[mv.fromY * map.w + mv.fromX];
+            let best = (0..prior.len()).max_by(|a, b| prior[*a].total_cmp(&prior[*b]).then(b.cmp(a))).unwrap();
+            if prior[best] == 0.0 || prior[move_direction(&mv)] >= threshold * prior[best] {
+                return Some(Action::Move(mv));
+            }
+            let (dx, dy) = MOVE_DIRECTIONS[best];
+            (best != 0).then(|| Action::Move(MoveAction {
+                toX : (mv.fromX as i32 + dx) as usize,
+                toY : (mv.fromY as i32 + dy) as usize,
+                ..mv
+            }))
+        }).collect()
+    }
+
+    //dot product of encoded actions with last run action channels, per committed unit
+    pub fn agreement(&mut self, map : &Map, actions : &[Action]) -> f32 {
+        encode_actions(map, actions, &mut self.encoded);
+        let mut dot = 0.0;
+        let mut mass = 0.0;
+        for y in 0..map.h {
+            for x in 0..map.w {
+                for c in 0..ACTION_CHANNELS {
+                    let e = self.encoded.get(x, y, c);
+                    dot += e * self.output.get(x, y, c);
+                    mass += e.abs();
+                }
+            }
+        }
+        dot / mass.max(1.0)
+    }
+}
+
+//policy actions with moves pruned by network tile priors, threshold 0 skips network run
+pub fn pruned_actions(net : &mut NetworkPolicy, map : &Map, side : &TileOwner, policy : Policy, temperature : f32, threshold : f32) -> Vec<Action> {
+    if policy == Policy::Network {
+        return net.actions(map, side);
+    }
+    let actions = policy_actions(map, side, policy);
+    if threshold <= 0.0 {
+        return actions;
+    }
+    net.run(map, side);
+    net.prune_moves(map, side, actions, temperature, threshold)
+}
+
+//softmax of policy agreement with network, slots outside best keep get 0, keep 0 keeps all,
+//policies are scored after tile prior pruning with threshold, same as they are played
+pub fn policy_priors(net : &mut NetworkPolicy, map : &Map, side : &TileOwner, policies : &[Policy], temperature : f32, keep : usize, threshold : f32) -> Vec<f32> {
+    assert!(temperature > 0.0, "Prior temperature must be positive");
+    net.run(map, side);
+    let network_actions = decode_actions(map, side, &net.output);
+    let scores : Vec<f32> = policies.iter().map(|policy| {
+        let actions = match policy {
+            Policy::Network => {network_actions.clone()}
+            _ if threshold > 0.0 => {net.prune_moves(map, side, policy_actions(map, side, *policy), temperature, threshold)}
+            _ => {policy_actions(map, side, *policy)}
+        };
+        net.agreement(map, &actions) / temperature
+    }).collect();
+
+    let mut order : Vec<usize> = (0..scores.len()).collect();
+    order.sort_by(|a, b| scores[*b].total_cmp(&scores[*a]));
+    let keep = if keep == 0 { scores.len() } else { keep.min(scores.len()) };
+    let best = scores[order[0]];
+    let mut res = vec![0.0; scores.len()];
+    for idx in order[..keep].iter() {
+        //kept slots stay selectable even when softmax underflows
+        res[*idx] = (scores[*idx] - best).exp().max(f32::MIN_POSITIVE);
+    }
+    let sum : f32 = res.iter().sum();
+    res.iter_mut().for_each(|p| *p /= sum);
+    res
+}
+
+//value in [-1, 1] from side point of view: tile difference plus a bit of unit difference
+pub fn evaluate(map : &Map, side : &TileOwner) -> f32 {
+    let mine = map.tile_count(side) as f32;
+    let theirs = map.tile_count(&opponent(side)) as f32;
+    if mine == 0.0 || theirs == 0.0 {
+        return (mine - theirs).signum();
+    }
+    let units : i32 = map.data.iter().map(|t| t.units).sum();
+    let units = if *side == TileOwner::Me { units } else { -units } as f32;
+    let walkable = map.data.iter().filter(|t| t.scrap_amount > 0).count().max(1) as f32;
+    ((mine - theirs + 0.25 * units) / walkable).clamp(-1.0, 1.0)
+}
+
+pub fn game_over(map : &Map) -> bool {
+    map.tile_count(&TileOwner::Me) == 0 || map.tile_count(&TileOwner::Enemy) == 0
+}
+
+#[derive(Debug, Clone)]
+pub struct SearchConfig {
+    //wall clock limit per move, CodinGame allows 50ms so some margin is left
+    pub budget : Option<Duration>,
+    //0 for no limit, fixed count makes search reproducible
+    pub max_iterations : usize,
+    //turns chosen by trees, followed by rollout, 0 plays network policy without search
+    pub depth : usize,
+    pub rollout_turns : usize,
+    pub exploration : f32,
+    pub random_policies : usize,
+    //options below are used only with network, temperature must be positive
+    pub prior_temperature : f32,
+    //number of policies kept by prior in every node, 0 keeps all
+    pub prune_to : usize,
+    //moves with tile prior below tile_prune times best prior of their tile are redirected, 0 disables
+    pub tile_prune : f32,
+    //leaf value from network value head instead of evaluate, needs trained VALUE_CHANNEL
+    pub network_value : bool
+}
+
+impl Default for SearchConfig {
+    fn default() -> Self {
+        Self {
+            budget : Some(Duration::from_millis(45)),
+            max_iterations : 0,
+            depth : 4,
+            rollout_turns : 6,
+            exploration : 1.0,
+            random_policies : 4,
+            prior_temperature : 0.5,
+            prune_to : 0,
+            tile_prune : 0.0,
+            network_value : false
+        }
+    }
+}
+
+impl SearchConfig {
+    //network policy and tile priors, evaluate at leaves, no rollouts
+    pub fn guided(depth : usize) -> Self {
+        Self {
+            depth,
+            rollout_turns : 0,
+            exploration : 2.0,
+            prune_to : 4,
+            tile_prune : 0.25,
+            ..Self::default()
+        }
+    }
+}
+
+const NO_CHILD : u32 = u32::MAX;
+
+struct Node {
+    visits : u32,
+    total : f32,
+    children : Vec<u32>,
+    //per slot network prior, empty without network
+    prior : Vec<f32>
+}
+
+//smitsimax tree of one player, children are indexed by own policy only
+struct Tree {
+    nodes : Vec<Node>,
+    width : usize
+}
+
+impl Tree {
+    fn new(width : usize) -> Self {
+        let mut res = Self {
+            nodes : vec![],
+            width
+        };
+        res.add();
+        res
+    }
+
+    fn add(&mut self) -> usize {
+        self.nodes.push(Node {
+            visits : 0,
+            total : 0.0,
+            children : vec![NO_CHILD; self.width],
+            prior : vec![]
+        });
+        self.nodes.len() - 1
+    }
+
+    //PUCT over non pruned slots with priors, otherwise unvisited children first, then UCB1
+    fn select(&self, node : usize, exploration : f32, rng : &mut StdRng) -> usize {
+        let children = &self.nodes[node].children;
+        let prior = &self.nodes[node].prior;
+        if !prior.is_empty() {
+            let sqrt_n = (self.nodes[node].visits.max(1) as f32).sqrt();
+            let puct = |c : usize| {
+                let (q, n) = match children[c] {
+                    NO_CHILD => {(0.0, 0.0)}
+                    child => {
+                        let child = &self.nodes[child as usize];
+                        (child.total / child.visits.max(1) as f32, child.visits as f32)
+                    }
+                };
+                q + exploration * prior[c] * sqrt_n / (1.0 + n)
+            };
+            return (0..self.width)
+                .filter(|c| prior[*c] > 0.0)
+                .max_by(|a, b| puct(*a).total_cmp(&puct(*b)).then(b.cmp(a)))
+                .unwrap();
+        }
+        let unvisited : Vec<usize> = (0..self.width).filter(|c| children[*c] == NO_CHILD).collect();
+        if !unvisited.is_empty() {
+            return unvisited[rng.gen_range(0..unvisited.len())];
+        }
+        let log_n = (self.nodes[node].visits.max(1) as f32).ln();
+        (0..self.width).max_by(|a, b| {
+            let ucb = |c : usize| {
+                let child = &self.nodes[children[c] as usize];
+                child.total / child.visits as f32 + exploration * (log_n / child.visits as f32).sqrt()
+            };
+            ucb(*a).total_cmp(&ucb(*b))
+        }).unwrap()
+    }
+
+    fn child(&mut self, node : usize, slot : usize) -> usize {
+        if self.nodes[node].children[slot] == NO_CHILD {
+            let child = self.add();
+            self.nodes[node].children[slot] = child as u32;
+        }
+        self.nodes[node].children[slot] as usize
+    }
+
+    fn most_visited(&self) -> usize {
+        let children = &self.nodes[0].children;
+        (0..self.width)
+            .filter(|c| children[*c] != NO_CHILD)
+            .max_by_key(|c| self.nodes[children[*c] as usize].visits)
+            .unwrap_or(0)
+    }
+}
+
+fn expired(deadline : Option<Instant>) -> bool {
+    deadline.map(|d| Instant::now() >= d).unwrap_or(false)
+}
+
+//simultaneous move search, one tree per player, both choose independently and share one simulation
+pub struct SearchBot {
+    pub config : SearchConfig,
+    pub owner : TileOwner,
+    pub policies : Vec<Policy>,
+    //priors, leaf values and rollouts for both sides, random rollouts when None
+    pub network : Option<NetworkPolicy>,
+    pub rng : StdRng,
+    pub last_iterations : usize,
+    scratch : Option<TurnScratch>
+}
+
+impl SearchBot {
+    pub fn new(config : SearchConfig, side : TileOwner, seed : u64) -> Self {
+        assert_ne!(side, TileOwner::No, "Search needs side to play");
+        assert!(config.prior_temperature > 0.0, "Prior temperature must be positive");
+        let mut rng = StdRng::seed_from_u64(seed);
+        let mut policies = vec![Policy::Expand, Policy::Attack, Policy::Defend, Policy::Recycle];
+        policies.extend((0..config.random_policies).map(|_| Policy::Random(rng.gen())));
+        Self {
+            config,
+            owner : side,
+            policies,
+            network : None,
+            rng,
+            last_iterations : 0,
+            scratch : None
+        }
+    }
+
+    //adds Network policy, network output should have ACTION_CHANNELS and optionally VALUE_CHANNEL
+    pub fn guided(config : SearchConfig, network : SimpleNetwork, side : TileOwner, seed : u64) -> Self {
+        let mut res = Self::new(config, side, seed);
+        res.policies.push(Policy::Network);
+        res.network = Some(NetworkPolicy::new(network));
+        res
+    }
+
+    //optional, scratch is allocated on first get_actions otherwise
+    pub fn prepare(&mut self, map : &Map) {
+        self.scratch = Some(TurnScratch::new(map.w, map.h));
+    }
+
+    pub fn get_actions(&mut self, map : &Map) -> Vec<Action> {
+        let owner = self.owner.clone();
+        if self.config.depth == 0 {
+            self.last_iterations = 0;
+            return self.slot_actions(map, &owner, Policy::Network);
+        }
+        assert!(self.config.budget.is_some() || self.config.max_iterations > 0, "Search needs time or iteration limit");
+        //checked inside iteration too, network rollouts and priors make single iteration long
+        let deadline = self.config.budget.map(|b| Instant::now() + b);
+        let mut scratch = self.scratch.take().unwrap_or_else(|| TurnScratch::new(map.w, map.h));
+        let other = opponent(&owner);
+        let mut mine = Tree::new(self.policies.len());
+        let mut theirs = Tree::new(self.policies.len());
+        let mut path = vec![];
+
+        let mut iterations = 0;
+        loop {
+            if self.config.max_iterations > 0 && iterations >= self.config.max_iterations {
+                break;
+            }
+            if expired(deadline) {
+                break;
+            }
+
+            let mut sim = map.clone();
+            let (mut a, mut b) = (0, 0);
+            let mut complete = true;
+            path.clear();
+            path.push((a, b));
+            for _ in 0..self.config.depth {
+                if game_over(&sim) {
+                    break;
+                }
+                if expired(deadline) {
+                    complete = false;
+                    break;
+                }
+                if let Some(net) = self.network.as_mut() {
+                    let (temperature, keep, threshold) = (self.config.prior_temperature, self.config.prune_to, self.config.tile_prune);
+                    if mine.nodes[a].prior.is_empty() {
+                        mine.nodes[a].prior = policy_priors(net, &sim, &owner, &self.policies, temperature, keep, threshold);
+                    }
+                    if theirs.nodes[b].prior.is_empty() {
+                        theirs.nodes[b].prior = policy_priors(net, &sim, &other, &self.policies, temperature, keep, threshold);
+                    }
+                }
+                let sa = mine.select(a, self.config.exploration, &mut self.rng);
+                let sb = theirs.select(b, self.config.exploration, &mut self.rng);
+                let actions_a = self.slot_actions(&sim, &owner, self.policies[sa]);
+                let actions_b = self.slot_actions(&sim, &other, self.policies[sb]);
+                self.play(&mut sim, &actions_a, &actions_b, &mut scratch);
+                a = mine.child(a, sa);
+                b = theirs.child(b, sb);
+                path.push((a, b));
+            }
+
+            for _ in 0..self.config.rollout_turns {
+                if game_over(&sim) || !complete {
+                    break;
+                }
+                if expired(deadline) {
+                    complete = false;
+                    break;
+                }
+                let (actions_a, actions_b) = match self.network.as_mut() {
+                    Some(net) => {(net.actions(&sim, &owner), net.actions(&sim, &other))}
+                    None => {
+                        let (pa, pb) = (Policy::Random(self.rng.gen()), Policy::Random(self.rng.gen()));
+                        (policy_actions(&sim, &owner, pa), policy_actions(&sim, &other, pb))
+                    }
+                };
+                self.play(&mut sim, &actions_a, &actions_b, &mut scratch);
+            }
+            //unfinished iteration is dropped, its leaf would not be comparable
+            if !complete {
+                break;
+            }
+            iterations += 1;
+
+            let value = match self.network.as_mut() {
+                Some(net) if self.config.network_value && !game_over(&sim) => {
+                    net.run(&sim, &owner);
+                    net.value().unwrap_or_else(|| evaluate(&sim, &owner))
+                }
+                _ => {evaluate(&sim, &owner)}
+            };
+            for (a, b) in path.iter() {
+                mine.nodes[*a].visits += 1;
+                mine.nodes[*a].total += value;
+                theirs.nodes[*b].visits += 1;
+                theirs.nodes[*b].total -= value;
+            }
+        }
+
+        self.last_iterations = iterations;
+        self.scratch = Some(scratch);
+        let best = self.policies[mine.most_visited()];
+        self.slot_actions(map, &owner, best)
+    }
+
+    //Network slot without network falls back to first policy
+    fn slot_actions(&mut self, map : &Map, side : &TileOwner, policy : Policy) -> Vec<Action> {
+        let (temperature, threshold) = (self.config.prior_temperature, self.config.tile_prune);
+        match (policy, self.network.as_mut()) {
+            (_, Some(net)) => {pruned_actions(net, map, side, policy, temperature, threshold)}
+            (Policy::Network, None) => {policy_actions(map, side, self.policies[0])}
+            _ => {policy_actions(map, side, policy)}
+        }
+    }
+
+    //a are actions of owner
+    fn play(&self, sim : &mut Map, a : &[Action], b : &[Action], scratch : &mut TurnScratch) {
+        if self.owner == TileOwner::Me {
+            sim.next_turn_with(a, b, scratch);
+        } else {
+            sim.next_turn_with(b, a, scratch);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MAP : &str = include_str!("../../start_map.txt");
+
+    #[test]
+    fn test_policies() {
+        let map = Map::load(MAP.trim().to_string());
+        for policy in [Policy::Expand, Policy::Attack, Policy::Defend, Policy::Recycle, Policy::Random(3)] {
+            for side in [TileOwner::Me, TileOwner::Enemy] {
+                let actions = policy_actions(&map, &side, policy);
+                assert!(!actions.is_empty());
+                assert_eq!(actions, policy_actions(&map, &side, policy));
+                for a in actions.iter() {
+                    if let Action::Move(mv) = a {
+                        assert_eq!(map.data[mv.fromY * map.w + mv.fromX].owner, side);
+                    }
+                }
+            }
+        }
+        assert!(policy_actions(&map, &TileOwner::Me, Policy::Recycle).iter().any(|a| matches!(a, Action::Build(_))));
+    }
+
+    #[test]
+    fn test_search_reproducible() {
+        let map = Map::load(MAP.trim().to_string());
+        let config = SearchConfig { budget : None, max_iterations : 50, ..SearchConfig::default() };
+        let mut a = SearchBot::new(config.clone(), TileOwner::Enemy, 1);
+        let mut b = SearchBot::new(config, TileOwner::Enemy, 1);
+        a.prepare(&map);
+        assert_eq!(a.get_actions(&map), b.get_actions(&map));
+        assert_eq!(a.last_iterations, 50);
+    }
+
+    //CodinGame limit is 50ms per turn, network rollouts and priors make single iterations long
+    #[test]
+    #[cfg_attr(debug_assertions, ignore = "timing is checked in release build")]
+    fn test_search_budget() {
+        let map = Map::load(MAP.trim().to_string());
+        let rollouts = SearchConfig { rollout_turns : 10, ..SearchConfig::guided(4) };
+        let bots = [
+            SearchBot::new(SearchConfig::default(), TileOwner::Me, 2),
+            SearchBot::guided(rollouts, value_network(1), TileOwner::Me, 2),
+            SearchBot::guided(SearchConfig::guided(6), value_network(1), TileOwner::Enemy, 2)
+        ];
+        for mut bot in bots {
+            let start = Instant::now();
+            bot.get_actions(&map);
+            assert!(start.elapsed() < Duration::from_millis(50), "{:?}", start.elapsed());
+            assert!(bot.last_iterations > 0);
+        }
+    }
+
+    #[test]
+    fn test_search_beats_random() {
+        let mut score = 0.0;
+        for game in 0..2 {
+            let mut map = Map::load(MAP.trim().to_string());
+            let mut bot = SearchBot::new(SearchConfig { budget : None, max_iterations : 40, ..SearchConfig::default() }, TileOwner::Me, game);
+            for turn in 0..30 {
+                let mine = bot.get_actions(&map);
+                let enemy = policy_actions(&map, &TileOwner::Enemy, Policy::Random(turn));
+                map.next_turn(&mine, &enemy);
+            }
+            score += evaluate(&map, &TileOwner::Me);
+        }
+        assert!(score > 0.0, "score {}", score);
+    }
+
+    fn value_network(seed : u64) -> SimpleNetwork {
+        let mut rng = StdRng::seed_from_u64(seed);
+        SimpleNetwork::simple_maker(3, INPUT_CHANNELS, 8, ACTION_CHANNELS + 1, 1, &mut rng)
+    }
+
+    #[test]
+    fn test_network_priors() {
+        let map = Map::load(MAP.trim().to_string());
+        let mut net = NetworkPolicy::new(value_network(1));
+        let policies = [Policy::Expand, Policy::Attack, Policy::Defend, Policy::Recycle, Policy::Network];
+        for (keep, threshold) in [(0, 0.0), (2, 0.0), (2, 0.5)] {
+            let priors = policy_priors(&mut net, &map, &TileOwner::Me, &policies, 0.5, keep, threshold);
+            assert!((priors.iter().sum::<f32>() - 1.0).abs() < 1e-5);
+            let kept = priors.iter().filter(|p| **p > 0.0).count();
+            assert_eq!(kept, if keep == 0 { policies.len() } else { keep });
+        }
+        let value = net.value().unwrap();
+        assert!((-1.0..=1.0).contains(&value));
+
+        let mut rng = StdRng::seed_from_u64(1);
+        let mut policy_only = NetworkPolicy::new(SimpleNetwork::simple_maker(3, INPUT_CHANNELS, 8, ACTION_CHANNELS, 1, &mut rng));
+        policy_only.run(&map, &TileOwner::Me);
+        assert_eq!(policy_only.value(), None);
+    }
+
+    #[test]
+    fn test_tile_priors() {
+        let map = Map::load(MAP.trim().to_string());
+        let side = TileOwner::Me;
+        let mut net = NetworkPolicy::new(value_network(1));
+        net.run(&map, &side);
+        net.output.data.fill(0.0);
+        //every stack is pushed along +x
+        let stacks : Vec<usize> = (0..map.data.len()).filter(|idx| unit_count(&map, *idx, &side) > 0).collect();
+        for idx in stacks.iter() {
+            *net.output.get_mut(idx % map.w, idx / map.w, 2) = 2.0 * unit_count(&map, *idx, &side) as f32;
+        }
+
+        let priors = net.tile_priors(&map, &side, 0.5);
+        for (idx, prior) in priors.iter().enumerate() {
+            if !stacks.contains(&idx) {
+                assert_eq!(*prior, [0.0; 5]);
+                continue;
+            }
+            assert!((prior.iter().sum::<f32>() - 1.0).abs() < 1e-5);
+            let right = walkable_neighbours(&map, idx).any(|n| n == idx + 1);
+            assert_eq!(prior[1] > prior[0], right);
+            assert!(prior[2] < prior[0]);
+        }
+
+        //moves going elsewhere step right, moves to blocked side stay
+        let moves : Vec<Action> = stacks.iter().map(|idx| Action::Move(MoveAction {
+            amount : 1, fromX : idx % map.w, fromY : idx / map.w, toX : 0, toY : idx / map.w
+        })).collect();
+        let pruned = net.prune_moves(&map, &side, moves, 0.5, 0.5);
+        for a in pruned.iter() {
+            match a {
+                Action::Move(mv) => {assert_eq!((mv.toX, mv.toY), (mv.fromX + 1, mv.fromY))}
+                _ => {panic!("Only moves expected")}
+            }
+        }
+        let right_count = stacks.iter().filter(|idx| walkable_neighbours(&map, **idx).any(|n| n == **idx + 1)).count();
+        assert_eq!(pruned.len(), right_count);
+        assert!(right_count > 0);
+    }
+
+    #[test]
+    fn test_guided_search() {
+        let map = Map::load(MAP.trim().to_string());
+        let mut pure = SearchBot::guided(SearchConfig::guided(0), value_network(2), TileOwner::Me, 3);
+        let mut net = NetworkPolicy::new(value_network(2));
+        assert_eq!(pure.get_actions(&map), net.actions(&map, &TileOwner::Me));
+        assert_eq!(pure.last_iterations, 0);
+
+        let config = SearchConfig { budget : None, max_iterations : 30, ..SearchConfig::guided(3) };
+        let mut a = SearchBot::guided(config.clone(), value_network(2), TileOwner::Enemy, 3);
+        let mut b = SearchBot::guided(config, value_network(2), TileOwner::Enemy, 3);
+        assert_eq!(a.get_actions(&map), b.get_actions(&map));
+        assert_eq!(a.last_iterations, 30);
+    }
+}
@@ -0,0 +1,553 @@
+use std::fmt::{Display, Formatter};
+use rand::Rng;
+use crate::mutation::MutationState;
+use crate::net::{Conv2d, Layer, Node, PReLU, Padding, SimpleNetwork};
+
+//architecture mutations for networks in simple_maker form:
+//(Padding, Conv2d, PReLU) blocks followed by final (Padding, Conv2d)
+//blocks are addressed by index of their conv among all convs
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TopologyMutation {
+    AddBlock(usize),
+    RemoveBlock(usize),
+    //conv index, new channel count
+    Widen(usize, usize),
+    //conv index, new kernel size
+    Kernel(usize, usize)
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum TopologyError {
+    //node does not follow simple_maker layout
+    Structure(usize),
+    //conv index out of range or not allowed for this operation
+    Block(usize),
+    Size(usize)
+}
+
+impl Display for TopologyError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            TopologyError::Structure(idx) => write!(f, "node {} does not match padding, conv, prelu layout", idx),
+            TopologyError::Block(idx) => write!(f, "conv {} can not be changed by this operation", idx),
+            TopologyError::Size(size) => write!(f, "unsupported size {}", size)
+        }
+    }
+}
+
+impl std::error::Error for TopologyError {}
+
+#[derive(Clone, Debug)]
+pub struct TopologyConfig {
+    //probabilities of every operation per mutate_topology call, at most one is applied
+    pub add_block_rate : f32,
+    pub remove_block_rate : f32,
+    pub widen_rate : f32,
+    pub kernel_rate : f32,
+    pub max_blocks : usize,
+    pub max_channels : usize,
+    pub kernel_sizes : Vec<usize>
+}
+
+impl Default for TopologyConfig {
+    fn default() -> Self {
+        Self {
+            add_block_rate : 0.03,
+            remove_block_rate : 0.02,
+            widen_rate : 0.03,
+            kernel_rate : 0.02,
+            max_blocks : 8,
+            max_channels : 32,
+            kernel_sizes : vec![1, 3, 5, 7]
+        }
+    }
+}
+
+pub fn conv_indices(net : &SimpleNetwork) -> Vec<usize> {
+    net.nodes.iter().enumerate()
+        .filter(|(_, n)| n.layer.as_any().downcast_ref::<Conv2d>().is_some())
+        .map(|(idx, _)| idx)
+        .collect()
+}
+
+fn conv_at(net : &SimpleNetwork, idx : usize) -> Result<&Conv2d, TopologyError> {
+    net.nodes.get(idx).and_then(|n| n.layer.as_any().downcast_ref::<Conv2d>()).ok_or(TopologyError::Structure(idx))
+}
+
+fn check_layout(net : &SimpleNetwork, node : usize, has_prelu : bool) -> Result<(), TopologyError> {
+    if node == 0 || net.nodes[node - 1].layer.as_any().downcast_ref::<Padding>().is_none() {
+        return Err(TopologyError::Structure(node.saturating_sub(1)));
+    }
+    if has_prelu && net.nodes.get(node + 1).and_then(|n| n.layer.as_any().downcast_ref::<PReLU>()).is_none() {
+        return Err(TopologyError::Structure(node + 1));
+    }
+    Ok(())
+}
+
+//node index of hidden conv, that is followed by PReLU
+fn hidden_conv(net : &SimpleNetwork, conv : usize) -> Result<usize, TopologyError> {
+    let convs = conv_indices(net);
+    if conv + 1 >= convs.len() {
+        return Err(TopologyError::Block(conv));
+    }
+    check_layout(net, convs[conv], true)?;
+    Ok(convs[conv])
+}
+
+//inserts identity block after conv block, network output is unchanged
+pub fn add_block(net : &mut SimpleNetwork, conv : usize) -> Result<(), TopologyError> {
+    let node = hidden_conv(net, conv)?;
+    let (size, c) = {
+        let layer = conv_at(net, node)?;
+        (layer.w, layer.out_c)
+    };
+
+    let mut weights = vec![0.0; size * size * c * c];
+    let center = size / 2;
+    for ch in 0..c {
+        weights[((ch * size + center) * size + center) * c + ch] = 1.0;
+    }
+    let block = [
+        Node::new(Padding::new(size / 2, size / 2)),
+        Node::new(Conv2d::from_weights(size, size, c, c, weights)),
+        Node::new(PReLU { k : vec![1.0; c], k_grad : vec![0.0; c] })
+    ];
+    let pos = node + 2;
+    net.nodes.splice(pos..pos, block);
+    Ok(())
+}
+
+//removes hidden block which keeps channel count
+pub fn remove_block(net : &mut SimpleNetwork, conv : usize) -> Result<(), TopologyError> {
+    let node = hidden_conv(net, conv)?;
+    let layer = conv_at(net, node)?;
+    if layer.in_c != layer.out_c {
+        return Err(TopologyError::Block(conv));
+    }
+    net.nodes.drain(node - 1..=node + 1);
+    Ok(())
+}
+
+//net2net widening: new channels copy random existing ones,
+//next conv splits outgoing weights between copies so output is unchanged
+pub fn widen<R : Rng + ?Sized>(net : &mut SimpleNetwork, conv : usize, new_c : usize, rng : &mut R) -> Result<(), TopologyError> {
+    let node = hidden_conv(net, conv)?;
+    let next_node = conv_indices(net)[conv + 1];
+    let cur = conv_at(net, node)?.clone();
+    let next = conv_at(net, next_node)?.clone();
+    let c = cur.out_c;
+    if new_c <= c {
+        return Err(TopologyError::Size(new_c));
+    }
+
+    let mapping : Vec<usize> = (0..new_c).map(|j| if j < c { j } else { rng.gen_range(0..c) }).collect();
+    let mut copies = vec![0; c];
+    for src in &mapping {
+        copies[*src] += 1;
+    }
+
+    let filter = cur.w * cur.h * cur.in_c;
+    let mut weights = Vec::with_capacity(filter * new_c);
+    for src in &mapping {
+        weights.extend_from_slice(&cur.weights()[src * filter..(src + 1) * filter]);
+    }
+    net.nodes[node] = Node::new(Conv2d::from_weights(cur.w, cur.h, cur.in_c, new_c, weights));
+
+    let prelu = net.nodes[node + 1].layer.as_any().downcast_ref::<PReLU>().unwrap();
+    let k : Vec<f32> = mapping.iter().map(|src| prelu.k[*src]).collect();
+    net.nodes[node + 1] = Node::new(PReLU { k, k_grad : vec![0.0; new_c] });
+
+    let mut next_weights = vec![0.0; next.w * next.h * new_c * next.out_c];
+    for tap in 0..next.w * next.h * next.out_c {
+        for (j, src) in mapping.iter().enumerate() {
+            next_weights[tap * new_c + j] = next.weights()[tap * c + src] / copies[*src] as f32;
+        }
+    }
+    net.nodes[next_node] = Node::new(Conv2d::from_weights(next.w, next.h, new_c, next.out_c, next_weights));
+    Ok(())
+}
+
+//growing keeps old kernel in center and preserves output, shrinking crops it
+pub fn change_kernel(net : &mut SimpleNetwork, conv : usize, size : usize) -> Result<(), TopologyError> {
+    if size.is_multiple_of(2) {
+        return Err(TopologyError::Size(size));
+    }
+    let convs = conv_indices(net);
+    let node = *convs.get(conv).ok_or(TopologyError::Block(conv))?;
+    check_layout(net, node, false)?;
+    let old = conv_at(net, node)?.clone();
+
+    let mut weights = vec![0.0; size * size * old.in_c * old.out_c];
+    let shift = size as isize / 2 - old.w as isize / 2;
+    for out in 0..old.out_c {
+        for dy in 0..size {
+            for dx in 0..size {
+                let (ox, oy) = (dx as isize - shift, dy as isize - shift);
+                if ox < 0 || oy < 0 || ox >= old.w as isize || oy >= old.h as isize {
+                    continue;
+                }
+                let src = ((out * old.h + oy as usize) * old.w + ox as usize) * old.in_c;
+                let dst = ((out * size + dy) * size + dx) * old.in_c;
+                weights[dst..dst + old.in_c].copy_from_slice(&old.weights()[src..src + old.in_c]);
+            }
+        }
+    }
+
+    net.nodes[node - 1] = Node::new(Padding::new(size / 2, size / 2));
+    net.nodes[node] = Node::new(Conv2d::from_weights(size, size, old.in_c, old.out_c, weights));
+    Ok(())
+}
+
+//applies at most one random architecture mutation
+pub fn mutate_topology<R : Rng + ?Sized>(net : &mut SimpleNetwork, config : &TopologyConfig, rng : &mut R) -> Option<TopologyMutation> {
+    let convs = conv_indices(net);
+    if convs.len() < 2 {
+        return None;
+    }
+    let hidden = convs.len() - 1;
+    let roll : f32 = rng.gen();
+
+    let mutation = if roll < config.add_block_rate {
+        if convs.len() > config.max_blocks {
+            return None;
+        }
+        TopologyMutation::AddBlock(rng.gen_range(0..hidden))
+    } else if roll < config.add_block_rate + config.remove_block_rate {
+        TopologyMutation::RemoveBlock(rng.gen_range(0..hidden))
+    } else if roll < config.add_block_rate + config.remove_block_rate + config.widen_rate {
+        let conv = rng.gen_range(0..hidden);
+        let c = conv_at(net, convs[conv]).ok()?.out_c;
+        if c >= config.max_channels {
+            return None;
+        }
+        let new_c = (c + rng.gen_range(1..=(c / 4).max(1))).min(config.max_channels);
+        TopologyMutation::Widen(conv, new_c)
+    } else if roll < config.add_block_rate + config.remove_block_rate + config.widen_rate + config.kernel_rate {
+        let conv = rng.gen_range(0..convs.len());
+        let current = conv_at(net, convs[conv]).ok()?.w;
+        let sizes : Vec<usize> = config.kernel_sizes.iter().copied().filter(|s| *s != current).collect();
+        if sizes.is_empty() {
+            return None;
+        }
+        TopologyMutation::Kernel(conv, sizes[rng.gen_range(0..sizes.len())])
+    } else {
+        return None;
+    };
+
+    let res = match mutation {
+        TopologyMutation::AddBlock(conv) => add_block(net, conv),
+        TopologyMutation::RemoveBlock(conv) => remove_block(net, conv),
+        TopologyMutation::Widen(conv, new_c) => widen(net, conv, new_c, rng),
+        TopologyMutation::Kernel(conv, size) => change_kernel(net, conv, size)
+    };
+    res.ok().map(|_| mutation)
+}
+
+//mutate_topology that keeps self adapted sigmas of parameter groups that were not changed,
+//added identity block starts with sigmas of block it was inserted after
+pub fn mutate_genome<R : Rng + ?Sized>(net : &mut SimpleNetwork, state : &mut MutationState, config : &TopologyConfig, rng : &mut R) -> Option<TopologyMutation> {
+    let convs = conv_indices(net);
+    //one parameter group per node with parameters
+    let groups : Vec<usize> = net.nodes.iter().map(|n| (n.layer.param_count() > 0) as usize).collect();
+    let mutation = mutate_topology(net, config, rng)?;
+    if state.sigmas.len() != groups.iter().sum::<usize>() {
+        return Some(mutation);
+    }
+
+    match mutation {
+        TopologyMutation::AddBlock(conv) => {
+            let pos = convs[conv] + 2;
+            let at : usize = groups[..pos].iter().sum();
+            let block : usize = groups[pos - 3..pos].iter().sum();
+            let copy = state.sigmas[at - block..at].to_vec();
+            state.sigmas.splice(at..at, copy);
+        }
+        TopologyMutation::RemoveBlock(conv) => {
+            let node = convs[conv];
+            let at : usize = groups[..node - 1].iter().sum();
+            let block : usize = groups[node - 1..=node + 1].iter().sum();
+            state.sigmas.drain(at..at + block);
+        }
+        //group count is unchanged
+        TopologyMutation::Widen(..) | TopologyMutation::Kernel(..) => {}
+    }
+    Some(mutation)
+}
+
+//kernel size and output channels of every conv
+pub fn architecture(net : &SimpleNetwork) -> Vec<(usize, usize)> {
+    net.nodes.iter()
+        .filter_map(|n| n.layer.as_any().downcast_ref::<Conv2d>())
+        .map(|c| (c.w, c.out_c))
+        .collect()
+}
+
+//structural distance, every missing block counts 1, kernel change 0.5, channel change relative;
+//networks with same architecture also add weight_k * mean absolute weight difference
+pub fn distance(a : &SimpleNetwork, b : &SimpleNetwork, weight_k : f32) -> f32 {
+    let arch_a = architecture(a);
+    let arch_b = architecture(b);
+    let mut res = 0.0;
+    for idx in 0..arch_a.len().max(arch_b.len()) {
+        match (arch_a.get(idx), arch_b.get(idx)) {
+            (Some((ka, ca)), Some((kb, cb))) => {
+                if ka != kb {
+                    res += 0.5;
+                }
+                res += (*ca as f32 - *cb as f32).abs() / (*ca).max(*cb) as f32;
+            }
+            _ => res += 1.0
+        }
+    }
+
+    if res == 0.0 && weight_k > 0.0 {
+        let pa = a.params_vec();
+        let pb = b.params_vec();
+        let diff : f32 = pa.iter().zip(pb.iter()).map(|(x, y)| (x - y).abs()).sum();
+        res += weight_k * diff / pa.len().max(1) as f32;
+    }
+    res
+}
+
+#[derive(Clone)]
+pub struct Species {
+    pub id : usize,
+    pub representative : SimpleNetwork,
+    //indices into population of last speciate call
+    pub members : Vec<usize>,
+    pub best_fitness : f32,
+    pub created : usize,
+    pub last_improved : usize
+}
+
+//NEAT-style speciation with fitness sharing, young species are protected
+//so new architectures get time to tune weights
+#[derive(Clone)]
+pub struct Speciation {
+    pub threshold : f32,
+    pub weight_k : f32,
+    //species without improvement for this many generations get no offspring
+    pub stagnation : usize,
+    //species younger than this always get at least protected_offspring children
+    pub protect_age : usize,
+    pub protected_offspring : usize,
+    pub species : Vec<Species>,
+    pub next_id : usize
+}
+
+impl Default for Speciation {
+    fn default() -> Self {
+        Self {
+            threshold : 0.25,
+            weight_k : 0.0,
+            stagnation : 15,
+            protect_age : 5,
+            protected_offspring : 2,
+            species : vec![],
+            next_id : 0
+        }
+    }
+}
+
+impl Speciation {
+    //assigns every network to first close species, representatives are kept from previous generation
+    pub fn speciate(&mut self, networks : &[&SimpleNetwork], generation : usize) {
+        for s in self.species.iter_mut() {
+            s.members.clear();
+        }
+        for (idx, net) in networks.iter().enumerate() {
+            let found = self.species.iter_mut().find(|s| distance(&s.representative, net, self.weight_k) < self.threshold);
+            match found {
+                Some(s) => s.members.push(idx),
+                None => {
+                    self.species.push(Species {
+                        id : self.next_id,
+                        representative : (*net).clone(),
+                        members : vec![idx],
+                        best_fitness : f32::NEG_INFINITY,
+                        created : generation,
+                        last_improved : generation
+                    });
+                    self.next_id += 1;
+                }
+            }
+        }
+        self.species.retain(|s| !s.members.is_empty());
+        for s in self.species.iter_mut() {
+            s.representative = networks[s.members[0]].clone();
+        }
+    }
+
+    pub fn update_fitness(&mut self, fitness : &[f32], generation : usize) {
+        for s in self.species.iter_mut() {
+            let best = s.members.iter().map(|m| fitness[*m]).fold(f32::NEG_INFINITY, f32::max);
+            if best > s.best_fitness {
+                s.best_fitness = best;
+                s.last_improved = generation;
+            }
+        }
+    }
+
+    //number of children per species, proportional to summed shared fitness
+    pub fn offspring_counts(&self, fitness : &[f32], total : usize, generation : usize) -> Vec<usize> {
+        if self.species.is_empty() {
+            return vec![];
+        }
+        let min = fitness.iter().copied().fold(f32::INFINITY, f32::min);
+        let best_species = (0..self.species.len())
+            .max_by(|a, b| self.species[*a].best_fitness.total_cmp(&self.species[*b].best_fitness))
+            .unwrap();
+
+        let shares : Vec<f32> = self.species.iter().enumerate().map(|(idx, s)| {
+            let stagnant = generation - s.last_improved > self.stagnation;
+            if stagnant && idx != best_species {
+                return 0.0;
+            }
+            //fitness is shifted to be positive and shared between members
+            s.members.iter().map(|m| fitness[*m] - min + 1e-3).sum::<f32>() / s.members.len() as f32
+        }).collect();
+        let sum : f32 = shares.iter().sum();
+
+        let mut counts : Vec<usize> = self.species.iter().zip(shares.iter()).map(|(s, share)| {
+            let count = (share / sum * total as f32).floor() as usize;
+            if generation - s.created < self.protect_age {
+                count.max(self.protected_offspring)
+            } else {
+                count
+            }
+        }).collect();
+
+        //fix rounding, extra children go to best species, missing are taken from largest
+        let mut assigned : usize = counts.iter().sum();
+        while assigned < total {
+            counts[best_species] += 1;
+            assigned += 1;
+        }
+        while assigned > total {
+            let largest = (0..counts.len()).max_by_key(|i| counts[*i]).unwrap();
+            counts[largest] -= 1;
+            assigned -= 1;
+        }
+        counts
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::SeedableRng;
+    use rand::rngs::StdRng;
+    use crate::net::NetImage;
+
+    fn output(net : &mut SimpleNetwork, inp : &NetImage) -> Vec<f32> {
+        let mut out = net.allocate_output(inp);
+        net.process(inp, &mut out);
+        out.data
+    }
+
+    fn input() -> NetImage {
+        let mut rng = StdRng::seed_from_u64(11);
+        let mut inp = NetImage::new(6, 5, 4);
+        for v in inp.data.iter_mut() {
+            *v = rng.gen_range(-1.0..=1.0);
+        }
+        inp
+    }
+
+    fn assert_close(a : &[f32], b : &[f32]) {
+        assert_eq!(a.len(), b.len());
+        for (x, y) in a.iter().zip(b.iter()) {
+            assert!((x - y).abs() < 1e-3 * (1.0 + x.abs()), "{} vs {}", x, y);
+        }
+    }
+
+    #[test]
+    fn test_function_preserving_mutations() {
+        let inp = input();
+        let mut net = SimpleNetwork::simple_maker(3, 4, 6, 4, 1, &mut StdRng::seed_from_u64(1));
+        let before = output(&mut net, &inp);
+
+        add_block(&mut net, 1).unwrap();
+        assert_eq!(conv_indices(&net).len(), 4);
+        assert_close(&output(&mut net, &inp), &before);
+
+        widen(&mut net, 0, 9, &mut StdRng::seed_from_u64(2)).unwrap();
+        assert_eq!(architecture(&net)[0], (3, 9));
+        assert_close(&output(&mut net, &inp), &before);
+
+        change_kernel(&mut net, 3, 5).unwrap();
+        assert_eq!(architecture(&net)[3], (5, 4));
+        assert_close(&output(&mut net, &inp), &before);
+    }
+
+    #[test]
+    fn test_remove_and_invalid() {
+        let mut net = SimpleNetwork::simple_maker(3, 4, 6, 4, 2, &mut StdRng::seed_from_u64(2));
+        assert_eq!(remove_block(&mut net, 0), Err(TopologyError::Block(0)));
+        assert_eq!(remove_block(&mut net, 3), Err(TopologyError::Block(3)));
+        remove_block(&mut net, 1).unwrap();
+        assert_eq!(architecture(&net), vec![(3, 6), (3, 6), (3, 4)]);
+        assert_eq!(change_kernel(&mut net, 0, 4), Err(TopologyError::Size(4)));
+
+        let inp = input();
+        let mut rng = StdRng::seed_from_u64(4);
+        let config = TopologyConfig { add_block_rate : 0.25, remove_block_rate : 0.25, widen_rate : 0.25, kernel_rate : 0.25, ..TopologyConfig::default() };
+        for _ in 0..50 {
+            mutate_topology(&mut net, &config, &mut rng);
+            assert_eq!(output(&mut net, &inp).len(), 6 * 5 * 4);
+        }
+    }
+
+    #[test]
+    fn test_genome_keeps_sigmas() {
+        let mut net = SimpleNetwork::simple_maker(3, 4, 6, 4, 1, &mut StdRng::seed_from_u64(6));
+        //groups: conv, prelu, conv, prelu, conv
+        let mut state = MutationState { sigmas : vec![0.1, 0.2, 0.3, 0.4, 0.5] };
+        let mut rng = StdRng::seed_from_u64(1);
+
+        let add = TopologyConfig { add_block_rate : 1.0, ..TopologyConfig::default() };
+        let mutation = mutate_genome(&mut net, &mut state, &add, &mut rng).unwrap();
+        let TopologyMutation::AddBlock(conv) = mutation else { panic!("{:?}", mutation) };
+        let mut expected = vec![0.1, 0.2, 0.3, 0.4, 0.5];
+        expected.splice(conv * 2 + 2..conv * 2 + 2, [expected[conv * 2], expected[conv * 2 + 1]]);
+        assert_eq!(state.sigmas, expected);
+        assert_eq!(state.sigmas.len(), crate::mutation::group_count(&net));
+
+        let remove = TopologyConfig { remove_block_rate : 1.0, add_block_rate : 0.0, ..TopologyConfig::default() };
+        //first block changes channel count and can not be removed, failed tries change nothing
+        let mutation = loop {
+            if let Some(m) = mutate_genome(&mut net, &mut state, &remove, &mut rng) {
+                break m;
+            }
+        };
+        let TopologyMutation::RemoveBlock(conv) = mutation else { panic!("{:?}", mutation) };
+        expected.drain(conv * 2..conv * 2 + 2);
+        assert_eq!(state.sigmas, expected);
+        assert_eq!(state.sigmas.len(), crate::mutation::group_count(&net));
+    }
+
+    #[test]
+    fn test_speciation() {
+        let a = SimpleNetwork::simple_maker(3, 4, 6, 4, 1, &mut StdRng::seed_from_u64(3));
+        let b = SimpleNetwork::simple_maker(3, 4, 6, 4, 1, &mut StdRng::seed_from_u64(4));
+        let c = SimpleNetwork::simple_maker(5, 4, 6, 4, 2, &mut StdRng::seed_from_u64(5));
+        assert_eq!(distance(&a, &b, 0.0), 0.0);
+        assert!(distance(&a, &c, 0.0) > 1.0);
+
+        let mut speciation = Speciation::default();
+        speciation.speciate(&[&a, &b, &c], 0);
+        assert_eq!(speciation.species.len(), 2);
+        assert_eq!(speciation.species[0].members, vec![0, 1]);
+
+        //new species is protected even when its fitness is worst
+        let fitness = [5.0, 4.0, -10.0];
+        speciation.update_fitness(&fitness, 0);
+        let counts = speciation.offspring_counts(&fitness, 10, 1);
+        assert_eq!(counts.iter().sum::<usize>(), 10);
+        assert!(counts[1] >= 2);
+
+        let counts = speciation.offspring_counts(&fitness, 10, 30);
+        assert_eq!(counts, vec![10, 0]);
+    }
+}
@@ -0,0 +1,199 @@
+use crate::rng::mix;
+use crate::{Map, Tile, TileOwner};
+
+const TILE_SEED : u64 = 0x5A0B_2157_0000_0001;
+const BALANCE_SEED : u64 = 0x5A0B_2157_0000_0002;
+
+//game ends when board does not change for this many turns
+pub const STALL_TURNS : usize = 20;
+
+//key of tile state at index, keys are mixed on the fly instead of stored in table
+pub fn tile_key(idx : usize, tile : &Tile) -> u64 {
+    let owner : u64 = match tile.owner {
+        TileOwner::No => {0}
+        TileOwner::Me => {1}
+        TileOwner::Enemy => {2}
+    };
+    let state = tile.units as u32 as u64
+        | (tile.scrap_amount as u16 as u64) << 32
+        | owner << 48
+        | (tile.recycler as u64) << 50;
+    mix(mix(TILE_SEED ^ idx as u64) ^ state)
+}
+
+pub fn balance_key(my_scrap : i32, enemy_scrap : i32) -> u64 {
+    mix(BALANCE_SEED ^ (my_scrap as u32 as u64 | (enemy_scrap as u32 as u64) << 32))
+}
+
+pub fn board_hash(data : &[Tile]) -> u64 {
+    data.iter().enumerate().fold(0, |hash, (idx, tile)| hash ^ tile_key(idx, tile))
+}
+
+//fixed size table, colliding entry is replaced
+pub struct TranspositionTable<T> {
+    pub entries : Vec<Option<(u64, T)>>,
+    mask : usize
+}
+
+impl<T> TranspositionTable<T> {
+    //2^bits entries
+    pub fn new(bits : u32) -> Self {
+        let size = 1usize << bits;
+        Self {
+            entries : (0..size).map(|_| None).collect(),
+            mask : size - 1
+        }
+    }
+
+    #[inline(always)]
+    fn slot(&self, hash : u64) -> usize {
+        hash as usize & self.mask
+    }
+
+    pub fn get(&self, hash : u64) -> Option<&T> {
+        match &self.entries[self.slot(hash)] {
+            Some((key, value)) if *key == hash => {Some(value)}
+            _ => {None}
+        }
+    }
+
+    pub fn get_mut(&mut self, hash : u64) -> Option<&mut T> {
+        let slot = self.slot(hash);
+        match &mut self.entries[slot] {
+            Some((key, value)) if *key == hash => {Some(value)}
+            _ => {None}
+        }
+    }
+
+    pub fn insert(&mut self, hash : u64, value : T) {
+        let slot = self.slot(hash);
+        self.entries[slot] = Some((hash, value));
+    }
+
+    pub fn get_or_insert_with<F : FnOnce() -> T>(&mut self, hash : u64, f : F) -> &mut T {
+        let slot = self.slot(hash);
+        if !matches!(&self.entries[slot], Some((key, _)) if *key == hash) {
+            self.entries[slot] = Some((hash, f()));
+        }
+        &mut self.entries[slot].as_mut().unwrap().1
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.iter().filter(|e| e.is_some()).count()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn clear(&mut self) {
+        self.entries.iter_mut().for_each(|e| *e = None);
+    }
+}
+
+//counts turns in row without board change, scrap balances are ignored since they grow every turn
+#[derive(Default)]
+pub struct StallCounter {
+    pub last : Option<u64>,
+    pub turns : usize
+}
+
+impl StallCounter {
+    //call after every turn, true once game is over by stall rule
+    pub fn update(&mut self, map : &Map) -> bool {
+        if self.last == Some(map.board_hash) {
+            self.turns += 1;
+        } else {
+            self.last = Some(map.board_hash);
+            self.turns = 0;
+        }
+        self.turns >= STALL_TURNS
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::Rng;
+    use crate::{Action, MoveAction, SpawnAction, TurnScratch, TurnUndo};
+    use crate::rng::stream_rng;
+
+    fn random_actions(map : &Map, side : &TileOwner, rnd : &mut rand::rngs::StdRng) -> Vec<Action> {
+        let mut res = vec![];
+        for (idx, tile) in map.data.iter().enumerate() {
+            if tile.owner != *side {
+                continue;
+            }
+            let (x, y) = (idx % map.w, idx / map.w);
+            res.push(Action::Move(MoveAction {
+                amount : tile.units.unsigned_abs(),
+                fromX : x,
+                fromY : y,
+                toX : rnd.gen_range(0..map.w),
+                toY : rnd.gen_range(0..map.h)
+            }));
+            if rnd.gen_bool(0.1) {
+                res.push(Action::Spawn(SpawnAction { amount : 1, x, y }));
+            }
+            if rnd.gen_bool(0.03) {
+                res.push(Action::Build(crate::BuildAction { x, y }));
+            }
+        }
+        res
+    }
+
+    #[test]
+    fn test_incremental_hash() {
+        let mut rnd = stream_rng(9, 0);
+        let mut map = Map::load_file("../start_map.txt");
+        let mut scratch = TurnScratch::new(map.w, map.h);
+        let mut undo = TurnUndo::default();
+        let start = map.hash();
+
+        for _ in 0..80 {
+            let my = random_actions(&map, &TileOwner::Me, &mut rnd);
+            let enemy = random_actions(&map, &TileOwner::Enemy, &mut rnd);
+            let before = map.hash();
+            map.apply_turn(&my, &enemy, &mut scratch, &mut undo);
+            assert_eq!(map.board_hash, board_hash(&map.data));
+            assert_ne!(map.hash(), before);
+
+            map.undo_turn(&undo);
+            assert_eq!(map.hash(), before);
+            map.next_turn(&my, &enemy);
+            assert_eq!(map.board_hash, board_hash(&map.data));
+        }
+        assert_ne!(map.hash(), start);
+    }
+
+    #[test]
+    fn test_transposition_table() {
+        let mut table = TranspositionTable::new(4);
+        assert!(table.is_empty());
+        table.insert(5, "a");
+        assert_eq!(table.get(5), Some(&"a"));
+        //same slot, different key
+        assert_eq!(table.get(5 + 16), None);
+        *table.get_or_insert_with(5 + 16, || "b") = "c";
+        assert_eq!(table.get(5), None);
+        assert_eq!(table.get(5 + 16), Some(&"c"));
+        assert_eq!(*table.get_or_insert_with(5 + 16, || "d"), "c");
+        assert_eq!(table.len(), 1);
+        table.clear();
+        assert!(table.is_empty());
+    }
+
+    #[test]
+    fn test_stall_counter() {
+        let mut map = Map::load_file("../start_map.txt");
+        let mut stall = StallCounter::default();
+        let wait = vec![];
+        for turn in 0..=STALL_TURNS {
+            map.next_turn(&wait, &wait);
+            assert_eq!(stall.update(&map), turn == STALL_TURNS);
+        }
+        map.data[0].units = 1;
+        map.rehash();
+        assert!(!stall.update(&map));
+    }
+}
@@ -0,0 +1,342 @@
+use std::fmt::{Display, Formatter};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use bot::mutation::MutationState;
+use bot::net::{load_network, save_network, ParamsError, SimpleNetwork};
+use crate::{Agent, RunConfig, Trainer};
+
+//line based text state: "key value" lines, "key count v1 v2 .." lists and
+//"key line_count" blocks followed by raw lines; floats use exact round trip formatting
+#[derive(Default)]
+pub struct StateWriter {
+    pub data : String
+}
+
+impl StateWriter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn value<T : Display>(&mut self, key : &str, val : T) {
+        self.data.push_str(&format!("{} {}\n", key, val));
+    }
+
+    pub fn values<T : Display>(&mut self, key : &str, vals : &[T]) {
+        self.data.push_str(&format!("{} {}", key, vals.len()));
+        for val in vals {
+            self.data.push_str(&format!(" {}", val));
+        }
+        self.data.push('\n');
+    }
+
+    pub fn block(&mut self, key : &str, text : &str) {
+        self.data.push_str(&format!("{} {}\n", key, text.lines().count()));
+        for line in text.lines() {
+            self.data.push_str(line);
+            self.data.push('\n');
+        }
+    }
+
+    pub fn agent(&mut self, key : &str, agent : &Agent) {
+        self.value(key, agent.fitness);
+        self.values("sigmas", &agent.mutation.sigmas);
+        self.block("network", &save_network(&agent.network));
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum StateError {
+    //key that was expected after the last line
+    Ended(String),
+    //expected key, line found instead
+    UnexpectedLine(String, String),
+    //key whose value or list does not parse
+    BadValue(String),
+    //key of network block
+    Network(String, ParamsError)
+}
+
+impl Display for StateError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            StateError::Ended(key) => write!(f, "state ended before {}", key),
+            StateError::UnexpectedLine(key, line) => write!(f, "expected {}, found {:?}", key, line),
+            StateError::BadValue(key) => write!(f, "bad value for {}", key),
+            StateError::Network(key, err) => write!(f, "bad network in {}: {}", key, err)
+        }
+    }
+}
+
+impl std::error::Error for StateError {}
+
+pub struct StateReader<'a> {
+    lines : std::iter::Peekable<std::str::Lines<'a>>
+}
+
+impl<'a> StateReader<'a> {
+    pub fn new(data : &'a str) -> Self {
+        Self {
+            lines : data.lines().peekable()
+        }
+    }
+
+    //value of key added to format later, None when state was saved before it existed
+    pub fn optional<T : FromStr>(&mut self, key : &str) -> Result<Option<T>, StateError> {
+        let name = self.lines.peek().and_then(|l| l.split(' ').next());
+        if name == Some(key) {
+            self.value(key).map(Some)
+        } else {
+            Ok(None)
+        }
+    }
+
+    fn line(&mut self, key : &str) -> Result<&'a str, StateError> {
+        let line = self.lines.next().ok_or_else(|| StateError::Ended(key.to_string()))?;
+        let (name, rest) = line.split_once(' ').unwrap_or((line, ""));
+        if name != key {
+            return Err(StateError::UnexpectedLine(key.to_string(), line.to_string()));
+        }
+        Ok(rest)
+    }
+
+    pub fn value<T : FromStr>(&mut self, key : &str) -> Result<T, StateError> {
+        self.line(key)?.parse().map_err(|_| StateError::BadValue(key.to_string()))
+    }
+
+    pub fn values<T : FromStr>(&mut self, key : &str) -> Result<Vec<T>, StateError> {
+        let bad = || StateError::BadValue(key.to_string());
+        let mut parts = self.line(key)?.split(' ');
+        let count : usize = parts.next().and_then(|c| c.parse().ok()).ok_or_else(bad)?;
+        let res = parts.map(|v| v.parse().map_err(|_| bad())).collect::<Result<Vec<T>, _>>()?;
+        if res.len() != count {
+            return Err(bad());
+        }
+        Ok(res)
+    }
+
+    pub fn block(&mut self, key : &str) -> Result<String, StateError> {
+        let count : usize = self.value(key)?;
+        let mut res = String::new();
+        for _ in 0..count {
+            res.push_str(self.lines.next().ok_or_else(|| StateError::Ended(key.to_string()))?);
+            res.push('\n');
+        }
+        Ok(res)
+    }
+
+    pub fn network(&mut self, key : &str) -> Result<SimpleNetwork, StateError> {
+        load_network(&self.block(key)?).map_err(|e| StateError::Network(key.to_string(), e))
+    }
+
+    pub fn agent(&mut self, key : &str) -> Result<Agent, StateError> {
+        let fitness = self.value(key)?;
+        let sigmas = self.values("sigmas")?;
+        let network = self.network("network")?;
+        let mut agent = Agent::from_network(network, MutationState { sigmas });
+        agent.fitness = fitness;
+        Ok(agent)
+    }
+}
+
+//generation rngs are derived from config seed and generation counter,
+//so config plus trainer state is enough to continue run bit exactly;
+//ui_seed restarts rng of ui actions, see GeneticScene::save_checkpoint
+pub fn save_checkpoint(config : &RunConfig, map_data : &str, trainer : &dyn Trainer, ui_seed : u64) -> String {
+    let mut w = StateWriter::new();
+    w.block("config", &config.save());
+    w.block("map", map_data);
+    w.block("trainer", &trainer.save_state());
+    w.value("ui_seed", ui_seed);
+    w.data
+}
+
+pub fn load_checkpoint(data : &str) -> Result<(RunConfig, String, Box<dyn Trainer>, u64), StateError> {
+    let mut r = StateReader::new(data);
+    let config = RunConfig::load(&r.block("config")?);
+    let map_data = r.block("map")?.trim_end().to_string();
+    let mut trainer = config.build_trainer(map_data.clone());
+    trainer.load_state(&r.block("trainer")?)?;
+    let ui_seed = r.optional("ui_seed")?.unwrap_or_else(|| config.ui_seed());
+    Ok((config, map_data, trainer, ui_seed))
+}
+
+//writes to temporary file first, so interrupted save never leaves broken checkpoint
+fn write_atomic(path : &Path, data : &str) -> std::io::Result<()> {
+    let tmp = path.with_extension("tmp");
+    std::fs::write(&tmp, data)?;
+    std::fs::rename(tmp, path)
+}
+
+//periodic checkpoints with retention and best so far snapshot
+pub struct CheckpointManager {
+    pub dir : PathBuf,
+    pub every : usize,
+    pub keep : usize,
+    pub best_fitness : f32
+}
+
+impl CheckpointManager {
+    pub fn new<P : AsRef<Path>>(dir : P, every : usize, keep : usize) -> std::io::Result<Self> {
+        std::fs::create_dir_all(&dir)?;
+        let mut res = Self {
+            dir : dir.as_ref().to_path_buf(),
+            every,
+            keep,
+            best_fitness : f32::NEG_INFINITY
+        };
+        //unreadable snapshot counts as missing, next improvement overwrites it
+        if let Ok(data) = std::fs::read_to_string(res.best_path()) {
+            if let Ok(best) = StateReader::new(&data).agent("best") {
+                res.best_fitness = best.fitness;
+            }
+        }
+        Ok(res)
+    }
+
+    pub fn best_path(&self) -> PathBuf {
+        self.dir.join("best.txt")
+    }
+
+    //checkpoint files sorted from oldest to newest
+    pub fn checkpoints(&self) -> Vec<PathBuf> {
+        let mut res : Vec<PathBuf> = std::fs::read_dir(&self.dir)
+            .map(|entries| entries.flatten().map(|e| e.path()).collect())
+            .unwrap_or_default();
+        res.retain(|p| {
+            let name = p.file_name().and_then(|n| n.to_str()).unwrap_or("");
+            name.starts_with("checkpoint_") && name.ends_with(".txt")
+        });
+        res.sort();
+        res
+    }
+
+    pub fn latest(&self) -> Option<PathBuf> {
+        self.checkpoints().pop()
+    }
+
+    //called after every generation, saves checkpoint every n generations and best agent when it improves
+    pub fn update(&mut self, config : &RunConfig, map_data : &str, trainer : &dyn Trainer, ui_seed : u64) -> std::io::Result<()> {
+        let generation = trainer.generation();
+        if let Some(best) = trainer.best() {
+            if best.fitness > self.best_fitness {
+                self.best_fitness = best.fitness;
+                let mut w = StateWriter::new();
+                w.agent("best", best);
+                w.value("generation", generation);
+                write_atomic(&self.best_path(), &w.data)?;
+            }
+        }
+
+        if self.every == 0 || !generation.is_multiple_of(self.every) {
+            return Ok(());
+        }
+        let path = self.dir.join(format!("checkpoint_{:08}.txt", generation));
+        write_atomic(&path, &save_checkpoint(config, map_data, trainer, ui_seed))?;
+
+        let checkpoints = self.checkpoints();
+        let remove = checkpoints.len().saturating_sub(self.keep.max(1));
+        for old in &checkpoints[..remove] {
+            std::fs::remove_file(old)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bot::cmaes::{CmaEs, CmaEsConfig};
+    use bot::net::Layer;
+    use crate::cmaes::CmaEsTrainer;
+    use crate::es::EvolutionStrategy;
+    use crate::tests::{small_agent, small_ga, MAP};
+    use crate::TrainerKind;
+
+    fn run(config : &RunConfig, trainer : &mut dyn Trainer, generations : usize) {
+        while trainer.generation() < generations {
+            trainer.step(&config.generation_streams(trainer.generation()));
+        }
+    }
+
+    //first generations, checkpoint round trip, then rest must equal uninterrupted run
+    fn check_resume(config : &RunConfig, build : impl Fn() -> Box<dyn Trainer>, first : usize, total : usize) {
+        let mut straight = build();
+        run(config, straight.as_mut(), total);
+
+        let mut part = build();
+        run(config, part.as_mut(), first);
+        let data = save_checkpoint(config, MAP.trim(), part.as_ref(), 11);
+        let (loaded, map_data, mut resumed, ui_seed) = load_checkpoint(&data).unwrap();
+        assert_eq!(&loaded, config);
+        assert_eq!(map_data, MAP.trim());
+        assert_eq!(ui_seed, 11);
+        assert_eq!(resumed.save_state(), part.save_state());
+
+        run(&loaded, resumed.as_mut(), total);
+        assert_eq!(resumed.save_state(), straight.save_state());
+    }
+
+    #[test]
+    fn test_resume_genetic() {
+        let config = RunConfig { seed : 3, trainer : TrainerKind::Genetic, ..RunConfig::default() };
+        check_resume(&config, || Box::new(small_ga(&config)), 2, 3);
+    }
+
+    #[test]
+    fn test_resume_evolution_strategy() {
+        let config = RunConfig { seed : 4, trainer : TrainerKind::EvolutionStrategy, ..RunConfig::default() };
+        check_resume(&config, || {
+            let mut es = EvolutionStrategy::new(MAP.trim().to_string(), &mut config.init_rng());
+            es.center = small_agent(&mut config.init_rng());
+            es.pairs = 3;
+            es.max_turns = 6;
+            Box::new(es)
+        }, 2, 4);
+    }
+
+    #[test]
+    fn test_resume_cma_es() {
+        let config = RunConfig { seed : 5, trainer : TrainerKind::CmaEs, ..RunConfig::default() };
+        check_resume(&config, || {
+            let mut cma = CmaEsTrainer::new(MAP.trim().to_string(), &mut config.init_rng());
+            //default network is small enough for full covariance, so its state is resumed too
+            assert!(cma.cma.is_full());
+            cma.cma = CmaEs::new(&cma.center.network.params_vec(), CmaEsConfig { sigma : 0.05, lambda : 6, ..CmaEsConfig::default() });
+            cma.max_turns = 6;
+            Box::new(cma)
+        }, 2, 4);
+    }
+
+    #[test]
+    fn test_state_optional_value() {
+        let mut r = StateReader::new("a 1\nb 2\n");
+        assert_eq!(r.optional::<u32>("b"), Ok(None));
+        assert_eq!(r.value::<u32>("a"), Ok(1));
+        assert_eq!(r.optional::<u32>("b"), Ok(Some(2)));
+        assert_eq!(r.optional::<u32>("c"), Ok(None));
+    }
+
+    #[test]
+    fn test_state_errors() {
+        let mut r = StateReader::new("a x\nb 3 1 2\n");
+        assert_eq!(r.value::<u32>("a"), Err(StateError::BadValue(String::from("a"))));
+        assert_eq!(r.values::<u32>("b"), Err(StateError::BadValue(String::from("b"))));
+        assert_eq!(r.value::<u32>("c"), Err(StateError::Ended(String::from("c"))));
+
+        let mut r = StateReader::new("a 1\n");
+        assert_eq!(r.value::<u32>("b"), Err(StateError::UnexpectedLine(String::from("b"), String::from("a 1"))));
+    }
+
+    #[test]
+    fn test_load_bad_checkpoint() {
+        let config = RunConfig { seed : 3, trainer : TrainerKind::Genetic, ..RunConfig::default() };
+        let data = save_checkpoint(&config, MAP.trim(), &small_ga(&config), 11);
+
+        let truncated = &data[..data.len() / 2];
+        assert!(load_checkpoint(truncated).is_err());
+
+        //first network of trainer state gets an unknown layer
+        let corrupt = data.replacen("\nconv2d ", "\nconv3d ", 1);
+        assert!(matches!(load_checkpoint(&corrupt), Err(StateError::Network(_, ParamsError::UnknownLayer(_)))));
+    }
+}
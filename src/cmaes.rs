@@ -0,0 +1,185 @@
+use rand::RngCore;
+use rayon::prelude::*;
+use bot::cmaes::{CmaEs, CmaEsConfig, Covariance};
+use bot::encoding::{ACTION_CHANNELS, INPUT_CHANNELS};
+use bot::mutation::MutationState;
+use bot::net::{Layer, SimpleNetwork};
+use crate::checkpoint::{StateError, StateReader, StateWriter};
+use crate::{play_params, Agent, GenerationStreams, Trainer};
+
+//CMA-ES over network parameters, samples play both sides against current mean network
+pub struct CmaEsTrainer {
+    pub center : Agent,
+    pub cma : CmaEs,
+    pub map_data : String,
+    pub max_turns : usize,
+    pub generation : usize,
+    pub mean_fitness : f32
+}
+
+impl CmaEsTrainer {
+    //small network with 146 parameters, so default full_limit keeps full covariance
+    pub fn new(map_data : String, rng : &mut dyn RngCore) -> Self {
+        let network = SimpleNetwork::simple_maker(3, INPUT_CHANNELS, 2, ACTION_CHANNELS, 0, rng);
+        Self::with_agent(map_data, Agent::from_network(network, MutationState::default()))
+    }
+
+    //any architecture, separable CMA-ES is used above full_limit parameters
+    pub fn with_agent(map_data : String, center : Agent) -> Self {
+        let cma = CmaEs::new(&center.network.params_vec(), CmaEsConfig {
+            sigma : 0.05,
+            ..CmaEsConfig::default()
+        });
+        Self {
+            center,
+            cma,
+            map_data,
+            max_turns : 50,
+            generation : 0,
+            mean_fitness : 0.0
+        }
+    }
+
+    pub fn cma_step(&mut self, streams : &GenerationStreams) {
+        let samples = self.cma.ask(&mut streams.main());
+        let fitness : Vec<f32> = samples.par_iter().map(|s| play_params(&self.center, s, &self.map_data, self.max_turns)).collect();
+        self.mean_fitness = fitness.iter().sum::<f32>() / fitness.len().max(1) as f32;
+
+        //CMA-ES minimizes cost
+        let costs : Vec<f64> = fitness.iter().map(|f| -*f as f64).collect();
+        self.cma.tell(&costs, &samples);
+
+        let mean : Vec<f32> = self.cma.mean.iter().map(|v| *v as f32).collect();
+        self.center.network.import_params(&mean);
+        self.center.fitness = self.mean_fitness;
+        self.generation += 1;
+    }
+}
+
+impl Trainer for CmaEsTrainer {
+    fn name(&self) -> &'static str {
+        "CMA-ES"
+    }
+
+    fn step(&mut self, streams : &GenerationStreams) {
+        self.cma_step(streams);
+    }
+
+    fn generation(&self) -> usize {
+        self.generation
+    }
+
+    fn best(&self) -> Option<&Agent> {
+        Some(&self.center)
+    }
+
+    fn agents_mut(&mut self) -> Vec<&mut Agent> {
+        vec![&mut self.center]
+    }
+
+    fn ui(&mut self, ui : &mut egui::Ui, _rng : &mut dyn RngCore) {
+        let mode = if self.cma.is_full() { "full" } else { "separable" };
+        ui.label(format!("Dimension: {} ({} covariance)", self.cma.dim, mode));
+        ui.label(format!("Population: {}, restarts: {}", self.cma.lambda, self.cma.restarts));
+        ui.label(format!("Sigma: {:.5}", self.cma.sigma));
+        ui.label(format!("Mean sample fitness: {:.2}", self.mean_fitness));
+        if ui.button("Restart").clicked() {
+            self.cma.restart();
+        }
+    }
+
+    fn save_state(&self) -> String {
+        let mut w = StateWriter::new();
+        w.value("generation", self.generation);
+        w.value("mean_fitness", self.mean_fitness);
+        w.value("max_turns", self.max_turns);
+        w.agent("center", &self.center);
+
+        let cma = &self.cma;
+        w.value("full_limit", cma.config.full_limit);
+        w.value("config_sigma", cma.config.sigma);
+        w.value("config_lambda", cma.config.lambda);
+        w.value("stagnation", cma.config.stagnation);
+        w.value("tol_sigma", cma.config.tol_sigma);
+        w.value("restart_factor", cma.config.restart_factor);
+        w.values("start", &cma.start);
+        w.values("mean", &cma.mean);
+        w.value("sigma", cma.sigma);
+        w.value("lambda", cma.lambda);
+        w.value("c1", cma.c1);
+        w.value("cmu", cma.cmu);
+        w.values("pc", &cma.pc);
+        w.values("ps", &cma.ps);
+        match &cma.cov {
+            Covariance::Full { c, b, d, eigen_eval } => {
+                w.value("cov", "full");
+                w.values("c", c);
+                w.values("b", b);
+                w.values("d", d);
+                w.value("eigen_eval", eigen_eval);
+            }
+            Covariance::Diagonal { c } => {
+                w.value("cov", "diagonal");
+                w.values("c", c);
+            }
+        }
+        w.value("cma_generation", cma.generation);
+        w.value("evaluations", cma.evaluations);
+        w.value("restarts", cma.restarts);
+        w.values("best", &cma.best);
+        w.value("best_cost", cma.best_cost);
+        w.value("run_best_cost", cma.run_best_cost);
+        w.value("run_best_gen", cma.run_best_gen);
+        w.value("run_generation", cma.run_generation);
+        w.data
+    }
+
+    fn load_state(&mut self, data : &str) -> Result<(), StateError> {
+        let mut r = StateReader::new(data);
+        self.generation = r.value("generation")?;
+        self.mean_fitness = r.value("mean_fitness")?;
+        self.max_turns = r.value("max_turns")?;
+        self.center = r.agent("center")?;
+
+        let config = CmaEsConfig {
+            full_limit : r.value("full_limit")?,
+            sigma : r.value("config_sigma")?,
+            lambda : r.value("config_lambda")?,
+            stagnation : r.value("stagnation")?,
+            tol_sigma : r.value("tol_sigma")?,
+            restart_factor : r.value("restart_factor")?
+        };
+        let start : Vec<f64> = r.values("start")?;
+        let mean = r.values("mean")?;
+        let sigma = r.value("sigma")?;
+        let lambda = r.value("lambda")?;
+        let start_f32 : Vec<f32> = start.iter().map(|v| *v as f32).collect();
+        let mut cma = CmaEs::new(&start_f32, config);
+        //strategy constants depend on population size, which grows on restarts
+        cma.init_run(lambda);
+        cma.start = start;
+        cma.mean = mean;
+        cma.sigma = sigma;
+        cma.c1 = r.value("c1")?;
+        cma.cmu = r.value("cmu")?;
+        cma.pc = r.values("pc")?;
+        cma.ps = r.values("ps")?;
+        let kind : String = r.value("cov")?;
+        cma.cov = if kind == "full" {
+            Covariance::Full { c : r.values("c")?, b : r.values("b")?, d : r.values("d")?, eigen_eval : r.value("eigen_eval")? }
+        } else {
+            Covariance::Diagonal { c : r.values("c")? }
+        };
+        cma.generation = r.value("cma_generation")?;
+        cma.evaluations = r.value("evaluations")?;
+        cma.restarts = r.value("restarts")?;
+        cma.best = r.values("best")?;
+        cma.best_cost = r.value("best_cost")?;
+        cma.run_best_cost = r.value("run_best_cost")?;
+        cma.run_best_gen = r.value("run_best_gen")?;
+        //older states did not track run age, generation count matches their behaviour
+        cma.run_generation = r.optional("run_generation")?.unwrap_or(cma.generation);
+        self.cma = cma;
+        Ok(())
+    }
+}
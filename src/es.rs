@@ -0,0 +1,168 @@
+use rand::{RngCore, SeedableRng};
+use rand::rngs::StdRng;
+use rayon::prelude::*;
+use bot::mutation::gaussian;
+use bot::net::Layer;
+use bot::optim::Adam;
+use crate::checkpoint::{StateError, StateReader, StateWriter};
+use crate::{play_params, Agent, GenerationStreams, Trainer};
+
+//OpenAI-ES: antithetic gaussian perturbations of central network,
+//centered rank fitness shaping and Adam update of central parameters
+pub struct EvolutionStrategy {
+    pub center : Agent,
+    pub sigma : f32,
+    pub pairs : usize,
+    pub adam : Adam,
+    pub weight_decay : f32,
+    pub map_data : String,
+    pub max_turns : usize,
+    pub generation : usize,
+    pub mean_fitness : f32
+}
+
+impl EvolutionStrategy {
+    pub fn new(map_data : String, rng : &mut dyn RngCore) -> Self {
+        Self {
+            center : Agent::build(rng),
+            sigma : 0.05,
+            pairs : 16,
+            adam : Adam::new(0.01),
+            weight_decay : 0.005,
+            map_data,
+            max_turns : 50,
+            generation : 0,
+            mean_fitness : 0.0
+        }
+    }
+
+    fn noise(seed : u64, len : usize) -> Vec<f32> {
+        let mut rng = StdRng::seed_from_u64(seed);
+        (0..len).map(|_| gaussian(&mut rng)).collect()
+    }
+
+    //ranks mapped to [-0.5, 0.5]
+    pub fn centered_ranks(values : &[f32]) -> Vec<f32> {
+        let mut order : Vec<usize> = (0..values.len()).collect();
+        order.sort_by(|a, b| values[*a].total_cmp(&values[*b]));
+        let mut res = vec![0.0; values.len()];
+        let k = (values.len().max(2) - 1) as f32;
+        for (rank, idx) in order.into_iter().enumerate() {
+            res[idx] = rank as f32 / k - 0.5;
+        }
+        res
+    }
+
+    //noise of pair p is seeded from worker stream p
+    pub fn es_step(&mut self, streams : &GenerationStreams) {
+        let center = self.center.network.params_vec();
+        let seeds : Vec<u64> = (0..self.pairs).map(|pair| streams.worker(pair).next_u64()).collect();
+
+        let fitness : Vec<(f32, f32)> = seeds.par_iter().map(|seed| {
+            let eps = Self::noise(*seed, center.len());
+            let plus : Vec<f32> = center.iter().zip(eps.iter()).map(|(c, e)| c + self.sigma * e).collect();
+            let minus : Vec<f32> = center.iter().zip(eps.iter()).map(|(c, e)| c - self.sigma * e).collect();
+            (play_params(&self.center, &plus, &self.map_data, self.max_turns),
+                play_params(&self.center, &minus, &self.map_data, self.max_turns))
+        }).collect();
+
+        let flat : Vec<f32> = fitness.iter().flat_map(|(p, m)| [*p, *m]).collect();
+        let ranks = Self::centered_ranks(&flat);
+        self.mean_fitness = flat.iter().sum::<f32>() / flat.len().max(1) as f32;
+
+        //Adam minimizes, so gradient of negative shaped fitness
+        let mut grad = vec![0.0; center.len()];
+        for (pair, seed) in seeds.iter().enumerate() {
+            let weight = ranks[pair * 2] - ranks[pair * 2 + 1];
+            let eps = Self::noise(*seed, center.len());
+            for (g, e) in grad.iter_mut().zip(eps.iter()) {
+                *g -= weight * e;
+            }
+        }
+        let k = 1.0 / (2.0 * self.pairs as f32 * self.sigma);
+        for (g, c) in grad.iter_mut().zip(center.iter()) {
+            *g = *g * k + self.weight_decay * c;
+        }
+
+        let mut params = center;
+        self.adam.update(&mut params, &grad);
+        self.center.network.import_params(&params);
+        self.center.fitness = self.mean_fitness;
+        self.generation += 1;
+    }
+}
+
+impl Trainer for EvolutionStrategy {
+    fn name(&self) -> &'static str {
+        "Evolution strategy"
+    }
+
+    fn step(&mut self, streams : &GenerationStreams) {
+        self.es_step(streams);
+    }
+
+    fn generation(&self) -> usize {
+        self.generation
+    }
+
+    fn best(&self) -> Option<&Agent> {
+        Some(&self.center)
+    }
+
+    fn agents_mut(&mut self) -> Vec<&mut Agent> {
+        vec![&mut self.center]
+    }
+
+    fn ui(&mut self, ui : &mut egui::Ui, _rng : &mut dyn RngCore) {
+        ui.label(format!("Mean perturbation fitness: {:.2}", self.mean_fitness));
+        ui.add(egui::Slider::new(&mut self.sigma, 0.001..=0.5).logarithmic(true).text("Sigma"));
+        ui.add(egui::Slider::new(&mut self.pairs, 1..=128).text("Antithetic pairs"));
+        ui.add(egui::Slider::new(&mut self.adam.lr, 0.0001..=0.1).logarithmic(true).text("Learning rate"));
+    }
+
+    fn save_state(&self) -> String {
+        let mut w = StateWriter::new();
+        w.value("generation", self.generation);
+        w.value("mean_fitness", self.mean_fitness);
+        w.value("sigma", self.sigma);
+        w.value("pairs", self.pairs);
+        w.value("weight_decay", self.weight_decay);
+        w.value("max_turns", self.max_turns);
+        w.value("lr", self.adam.lr);
+        w.value("beta1", self.adam.beta1);
+        w.value("beta2", self.adam.beta2);
+        w.value("eps", self.adam.eps);
+        w.value("t", self.adam.t);
+        w.value("moments", self.adam.m.len());
+        for (m, v) in self.adam.m.iter().zip(self.adam.v.iter()) {
+            w.values("m", m);
+            w.values("v", v);
+        }
+        w.agent("center", &self.center);
+        w.data
+    }
+
+    fn load_state(&mut self, data : &str) -> Result<(), StateError> {
+        let mut r = StateReader::new(data);
+        self.generation = r.value("generation")?;
+        self.mean_fitness = r.value("mean_fitness")?;
+        self.sigma = r.value("sigma")?;
+        self.pairs = r.value("pairs")?;
+        self.weight_decay = r.value("weight_decay")?;
+        self.max_turns = r.value("max_turns")?;
+        self.adam.lr = r.value("lr")?;
+        self.adam.beta1 = r.value("beta1")?;
+        self.adam.beta2 = r.value("beta2")?;
+        self.adam.eps = r.value("eps")?;
+        self.adam.t = r.value("t")?;
+        let count : usize = r.value("moments")?;
+        self.adam.m.clear();
+        self.adam.v.clear();
+        for _ in 0..count {
+            self.adam.m.push(r.values("m")?);
+            self.adam.v.push(r.values("v")?);
+        }
+        self.center = r.agent("center")?;
+        Ok(())
+    }
+}
@@ -1,35 +1,616 @@
 extern crate core;
 
-use core::panicking::panic;
 use std::fmt::{Debug, Formatter};
-use egui::Context;
-use rand::Rng;
+use egui::{Color32, Context, Pos2, Rect, Sense, Vec2};
+use rand::{Rng, RngCore, SeedableRng};
+use rand::rngs::StdRng;
 use bot::{Action, Map, TileOwner};
-use bot::net::{Layer, NetImage, SimpleNetwork};
+use bot::encoding::{decode_actions, fill_input, ACTION_CHANNELS, INPUT_CHANNELS};
+use bot::crossover::{check_same_architecture, crossover, CrossoverKind};
+use bot::imitation::{self, Replay, Sample};
+use bot::mutation::{self, MutationConfig, MutationState};
+use bot::net::{save_network, Conv2d, Layer, NetImage, SimpleNetwork};
+use bot::optim::Adam;
+use bot::rng::{stream_rng, stream_seed};
+use bot::search::{SearchBot, SearchConfig};
+use bot::topology::{self, Species, Speciation, TopologyConfig};
+use crate::checkpoint::{CheckpointManager, StateError, StateReader, StateWriter};
+use crate::cmaes::CmaEsTrainer;
+use crate::es::EvolutionStrategy;
+use rayon::prelude::*;
 
 
+pub mod es;
+pub mod cmaes;
+pub mod checkpoint;
+
 pub trait Scene {
     fn update(&mut self, ctx: &egui::Context);
 }
 
+//training algorithm that can be run from egui scene or headless
+pub trait Trainer {
+    fn name(&self) -> &'static str;
+    fn step(&mut self, streams : &GenerationStreams);
+    fn generation(&self) -> usize;
+    fn best(&self) -> Option<&Agent>;
+    fn agents_mut(&mut self) -> Vec<&mut Agent>;
+    fn ui(&mut self, ui : &mut egui::Ui, rng : &mut dyn RngCore);
+    //full state needed to continue training, see checkpoint module
+    fn save_state(&self) -> String;
+    fn load_state(&mut self, data : &str) -> Result<(), StateError>;
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TrainerKind {
+    Genetic,
+    EvolutionStrategy,
+    CmaEs
+}
+
+impl TrainerKind {
+    pub const ALL : [TrainerKind; 3] = [TrainerKind::Genetic, TrainerKind::EvolutionStrategy, TrainerKind::CmaEs];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            TrainerKind::Genetic => "ga",
+            TrainerKind::EvolutionStrategy => "es",
+            TrainerKind::CmaEs => "cmaes"
+        }
+    }
+
+    pub fn parse(name : &str) -> Option<TrainerKind> {
+        TrainerKind::ALL.into_iter().find(|k| k.name() == name)
+    }
+
+    pub fn build(&self, map_data : String, rng : &mut dyn RngCore) -> Box<dyn Trainer> {
+        match self {
+            TrainerKind::Genetic => Box::new(GeneticAlgorithm {
+                map_data,
+                ..GeneticAlgorithm::default()
+            }),
+            TrainerKind::EvolutionStrategy => Box::new(EvolutionStrategy::new(map_data, rng)),
+            TrainerKind::CmaEs => Box::new(CmaEsTrainer::new(map_data, rng))
+        }
+    }
+}
+
+//stream used for trainer initialization, generation g uses stream g
+pub const INIT_STREAM : u64 = u64::MAX;
+//stream for random actions triggered from ui
+pub const UI_STREAM : u64 = u64::MAX - 1;
+//sequential stream inside generation, workers use streams 0..n
+pub const MAIN_STREAM : u64 = u64::MAX;
+
+//random streams of one generation, parallel work takes stream of its agent or worker index,
+//so results do not depend on thread count or scheduling
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct GenerationStreams {
+    pub seed : u64
+}
+
+impl GenerationStreams {
+    pub fn main(&self) -> StdRng {
+        stream_rng(self.seed, MAIN_STREAM)
+    }
+
+    pub fn worker(&self, idx : usize) -> StdRng {
+        stream_rng(self.seed, idx as u64)
+    }
+}
+
+//everything needed to reproduce training run, all randomness is derived from seed
+#[derive(Clone, Debug, PartialEq)]
+pub struct RunConfig {
+    pub seed : u64,
+    pub trainer : TrainerKind,
+    pub generations : usize,
+    pub map_file : String,
+    //empty dir disables checkpoints
+    pub checkpoint_dir : String,
+    pub checkpoint_every : usize,
+    pub keep_checkpoints : usize
+}
+
+impl Default for RunConfig {
+    fn default() -> Self {
+        Self {
+            seed : 0,
+            trainer : TrainerKind::Genetic,
+            generations : 100,
+            map_file : String::from("start_map.txt"),
+            checkpoint_dir : String::from("checkpoints"),
+            checkpoint_every : 10,
+            keep_checkpoints : 3
+        }
+    }
+}
+
+impl RunConfig {
+    //seed for runs started without one, it is recorded in config so run can be repeated
+    pub fn random_seed() -> u64 {
+        let nanos = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map(|d| d.as_nanos() as u64)
+            .unwrap_or(0);
+        bot::rng::mix(nanos)
+    }
+
+    pub fn init_rng(&self) -> StdRng {
+        stream_rng(self.seed, INIT_STREAM)
+    }
+
+    //seed of ui rng at run start
+    pub fn ui_seed(&self) -> u64 {
+        stream_seed(self.seed, UI_STREAM)
+    }
+
+    pub fn generation_streams(&self, generation : usize) -> GenerationStreams {
+        GenerationStreams {
+            seed : stream_seed(self.seed, generation as u64)
+        }
+    }
+
+    pub fn build_trainer(&self, map_data : String) -> Box<dyn Trainer> {
+        self.trainer.build(map_data, &mut self.init_rng())
+    }
+
+    pub fn save(&self) -> String {
+        format!("seed={}\ntrainer={}\ngenerations={}\nmap={}\ncheckpoint_dir={}\ncheckpoint_every={}\nkeep_checkpoints={}\n",
+            self.seed, self.trainer.name(), self.generations, self.map_file,
+            self.checkpoint_dir, self.checkpoint_every, self.keep_checkpoints)
+    }
+
+    pub fn load(data : &str) -> Self {
+        let mut res = RunConfig::default();
+        for line in data.lines() {
+            let Some((key, val)) = line.split_once('=') else {
+                continue;
+            };
+            let val = val.trim();
+            match key.trim() {
+                "seed" => res.seed = val.parse().expect("Bad seed"),
+                "trainer" => res.trainer = TrainerKind::parse(val).expect("Unknown trainer"),
+                "generations" => res.generations = val.parse().expect("Bad generation count"),
+                "map" => res.map_file = val.to_string(),
+                "checkpoint_dir" => res.checkpoint_dir = val.to_string(),
+                "checkpoint_every" => res.checkpoint_every = val.parse().expect("Bad checkpoint interval"),
+                "keep_checkpoints" => res.keep_checkpoints = val.parse().expect("Bad checkpoint count"),
+                _ => {}
+            }
+        }
+        res
+    }
+}
+
+#[derive(Clone)]
 pub struct Agent {
     pub network : SimpleNetwork,
     pub output : NetImage,
     pub input : NetImage,
     pub fitness : f32,
-    pub owner : TileOwner
+    pub owner : TileOwner,
+    pub mutation : MutationState
 }
 
 
 pub struct GeneticAlgorithm {
     pub population : Vec<Agent>,
+    pub population_size : usize,
     pub game_count : usize,
-    pub selection_rate : f32
+    pub selection_rate : f32,
+    pub mutation : MutationConfig,
+    pub crossover : CrossoverKind,
+    pub crossover_rate : f32,
+    pub topology : TopologyConfig,
+    pub use_speciation : bool,
+    pub speciation : Speciation,
+    pub map_data : String,
+    pub max_turns : usize,
+    //extra games of every agent against search bot limited to sparring_iterations per move
+    pub sparring_games : usize,
+    pub sparring_iterations : usize,
+    pub generation : usize,
+    //best agent of every generation, oldest are dropped
+    pub hall_of_fame : Vec<Agent>,
+    pub hall_of_fame_size : usize
+}
+
+//plays one game, a is Me and b is Enemy, returns tile difference from a point of view
+pub fn play_match(a : &mut Agent, b : &mut Agent, map_data : &str, max_turns : usize) -> f32 {
+    let mut map = Map::load(map_data.to_string());
+    a.prepare(&map, TileOwner::Me);
+    b.prepare(&map, TileOwner::Enemy);
+
+    for _ in 0..max_turns {
+        let my_actions = a.get_actions(&map);
+        let enemy_actions = b.get_actions(&map);
+        map.next_turn(&my_actions, &enemy_actions);
+        if map.tile_count(&TileOwner::Me) == 0 || map.tile_count(&TileOwner::Enemy) == 0 {
+            break;
+        }
+    }
+
+    map.tile_count(&TileOwner::Me) as f32 - map.tile_count(&TileOwner::Enemy) as f32
+}
+
+//center with params plays both sides against unchanged center, returns mean tile difference of candidate
+pub fn play_params(center : &Agent, params : &[f32], map_data : &str, max_turns : usize) -> f32 {
+    let mut candidate = center.clone();
+    candidate.network.import_params(params);
+    let mut opponent = center.clone();
+    let first = play_match(&mut candidate, &mut opponent, map_data, max_turns);
+    let second = play_match(&mut opponent, &mut candidate, map_data, max_turns);
+    (first - second) / 2.0
+}
+
+//agent plays side against search bot, returns tile difference from agent point of view
+pub fn play_sparring(a : &mut Agent, side : TileOwner, iterations : usize, seed : u64, map_data : &str, max_turns : usize) -> f32 {
+    let mut map = Map::load(map_data.to_string());
+    let config = SearchConfig {
+        budget : None,
+        max_iterations : iterations,
+        ..SearchConfig::default()
+    };
+    let bot_side = if side == TileOwner::Me { TileOwner::Enemy } else { TileOwner::Me };
+    let mut bot = SearchBot::new(config, bot_side, seed);
+    a.prepare(&map, side.clone());
+    bot.prepare(&map);
+
+    for _ in 0..max_turns {
+        let agent_actions = a.get_actions(&map);
+        let bot_actions = bot.get_actions(&map);
+        if side == TileOwner::Me {
+            map.next_turn(&agent_actions, &bot_actions);
+        } else {
+            map.next_turn(&bot_actions, &agent_actions);
+        }
+        if map.tile_count(&TileOwner::Me) == 0 || map.tile_count(&TileOwner::Enemy) == 0 {
+            break;
+        }
+    }
+
+    let score = map.tile_count(&TileOwner::Me) as f32 - map.tile_count(&TileOwner::Enemy) as f32;
+    if side == TileOwner::Me { score } else { -score }
 }
 
 impl GeneticAlgorithm {
-    fn step(&mut self) {
+    //every agent plays game_count games against random opponents and sparring_games against search bot,
+    //fitness is mean tile difference; agent idx draws its opponents and bot seeds from worker stream idx;
+    //single agent has no opponent, so it plays sparring games only
+    pub fn evaluate(&mut self, streams : &GenerationStreams) {
+        let count = self.population.len();
+        let mut matches = vec![];
+        let mut sparring = vec![];
+        let game_count = if count < 2 { 0 } else { self.game_count };
+        for idx in 0..count {
+            let mut rng = streams.worker(idx);
+            for _ in 0..game_count {
+                let mut opponent = rng.gen_range(0..count - 1);
+                if opponent >= idx {
+                    opponent += 1;
+                }
+                matches.push((idx, opponent));
+            }
+            for game in 0..self.sparring_games {
+                let side = if game % 2 == 0 { TileOwner::Me } else { TileOwner::Enemy };
+                sparring.push((idx, side, rng.gen::<u64>()));
+            }
+        }
+
+        let population = &self.population;
+        let match_scores : Vec<f32> = matches.par_iter().map(|(a, b)| {
+            let (mut a, mut b) = (population[*a].clone(), population[*b].clone());
+            play_match(&mut a, &mut b, &self.map_data, self.max_turns)
+        }).collect();
+        let sparring_scores : Vec<f32> = sparring.par_iter().map(|(idx, side, seed)| {
+            let mut agent = population[*idx].clone();
+            play_sparring(&mut agent, side.clone(), self.sparring_iterations, *seed, &self.map_data, self.max_turns)
+        }).collect();
+
+        let mut fitness = vec![0.0; count];
+        let mut games = vec![0; count];
+        for ((a, b), score) in matches.iter().zip(match_scores) {
+            fitness[*a] += score;
+            fitness[*b] -= score;
+            games[*a] += 1;
+            games[*b] += 1;
+        }
+        for ((idx, _, _), score) in sparring.iter().zip(sparring_scores) {
+            fitness[*idx] += score;
+            games[*idx] += 1;
+        }
+        for (agent, (fitness, games)) in self.population.iter_mut().zip(fitness.iter().zip(games.iter())) {
+            agent.fitness = fitness / (*games).max(1) as f32;
+        }
+    }
+
+    fn tournament<R : Rng + ?Sized>(&self, candidates : &[usize], rng : &mut R) -> usize {
+        let a = candidates[rng.gen_range(0..candidates.len())];
+        let b = candidates[rng.gen_range(0..candidates.len())];
+        if self.population[a].fitness >= self.population[b].fitness { a } else { b }
+    }
+
+    //child of two tournament winners from candidates, with weight and architecture mutation;
+    //second parent is chosen only among candidates with same architecture as first
+    fn make_child<R : Rng + ?Sized>(&self, candidates : &[usize], rng : &mut R) -> Agent {
+        let pa = self.tournament(candidates, rng);
+        let parent = &self.population[pa];
+        let mates : Vec<usize> = candidates.iter()
+            .copied()
+            .filter(|c| *c != pa && check_same_architecture(&parent.network, &self.population[*c].network).is_ok())
+            .collect();
+
+        let network = if !mates.is_empty() && rng.gen::<f32>() < self.crossover_rate {
+            let pb = self.tournament(&mates, rng);
+            crossover(&parent.network, &self.population[pb].network, self.crossover, rng)
+                .expect("Mates have same architecture")
+        } else {
+            parent.network.clone()
+        };
+
+        let mut child = Agent::from_network(network, parent.mutation.clone());
+        topology::mutate_genome(&mut child.network, &mut child.mutation, &self.topology, rng);
+        child.mutate(&self.mutation, rng);
+        child
+    }
+
+    //keeps best selection_rate part of population and refills it with mutated children
+    pub fn refill<R : Rng + ?Sized>(&mut self, rng : &mut R) {
+        if self.use_speciation {
+            self.refill_species(rng);
+            return;
+        }
+
+        let size = self.population.len();
+        self.population.sort_by(|a, b| b.fitness.total_cmp(&a.fitness));
+        let survivors = ((size as f32 * self.selection_rate) as usize).clamp(1, size);
+        let candidates : Vec<usize> = (0..survivors).collect();
+
+        let mut children = vec![];
+        while survivors + children.len() < size {
+            children.push(self.make_child(&candidates, rng));
+        }
+
+        self.population.truncate(survivors);
+        self.population.extend(children);
+    }
+
+    //every species keeps its best agent and breeds its share of population from own members
+    pub fn refill_species<R : Rng + ?Sized>(&mut self, rng : &mut R) {
+        let size = self.population.len();
+        let networks : Vec<&SimpleNetwork> = self.population.iter().map(|a| &a.network).collect();
+        let fitness : Vec<f32> = self.population.iter().map(|a| a.fitness).collect();
+        self.speciation.speciate(&networks, self.generation);
+        self.speciation.update_fitness(&fitness, self.generation);
+        let counts = self.speciation.offspring_counts(&fitness, size, self.generation);
+
+        let mut next = vec![];
+        for (species, count) in self.speciation.species.iter().zip(counts) {
+            if count == 0 {
+                continue;
+            }
+            let mut members = species.members.clone();
+            members.sort_by(|a, b| fitness[*b].total_cmp(&fitness[*a]));
+            let survivors = ((members.len() as f32 * self.selection_rate).ceil() as usize).clamp(1, members.len());
+
+            next.push(self.population[members[0]].clone());
+            for _ in 1..count {
+                next.push(self.make_child(&members[..survivors], rng));
+            }
+        }
+        self.population = next;
+    }
+
+    pub fn step(&mut self, streams : &GenerationStreams) {
+        let mut rng = streams.main();
+        while self.population.len() < self.population_size.max(2) {
+            self.population.push(Agent::build(&mut rng));
+        }
+        self.evaluate(streams);
+        if let Some(best) = self.best() {
+            self.hall_of_fame.push(best.clone());
+        }
+        let extra = self.hall_of_fame.len().saturating_sub(self.hall_of_fame_size);
+        self.hall_of_fame.drain(..extra);
+        self.refill(&mut rng);
+        self.generation += 1;
+    }
+
+    pub fn best(&self) -> Option<&Agent> {
+        self.population.iter().max_by(|a, b| a.fitness.total_cmp(&b.fitness))
+    }
 
+    //sigmas are inherited by children, so change of config sigma is applied to every agent
+    pub fn set_sigma(&mut self, sigma : f32) {
+        let old = self.mutation.sigma;
+        self.mutation.sigma = sigma;
+        for agent in self.population.iter_mut() {
+            if old > 0.0 {
+                let (k, min, max) = (sigma / old, self.mutation.min_sigma, self.mutation.max_sigma);
+                agent.mutation.sigmas.iter_mut().for_each(|s| *s = (*s * k).clamp(min, max));
+            } else {
+                //reinitialized from config on next mutation
+                agent.mutation.sigmas.clear();
+            }
+        }
+    }
+}
+
+impl Trainer for GeneticAlgorithm {
+    fn name(&self) -> &'static str {
+        "Genetic algorithm"
+    }
+
+    fn step(&mut self, streams : &GenerationStreams) {
+        GeneticAlgorithm::step(self, streams);
+    }
+
+    fn generation(&self) -> usize {
+        self.generation
+    }
+
+    fn best(&self) -> Option<&Agent> {
+        GeneticAlgorithm::best(self)
+    }
+
+    fn agents_mut(&mut self) -> Vec<&mut Agent> {
+        self.population.iter_mut().collect()
+    }
+
+    fn ui(&mut self, ui : &mut egui::Ui, rng : &mut dyn RngCore) {
+        ui.label(format!("Population size: {}", self.population.len()));
+        ui.add(egui::Slider::new(&mut self.population_size, 2..=500).text("Target population"));
+        if ui.button("Fill population").clicked() {
+            while self.population.len() < self.population_size {
+                self.population.push(Agent::build(rng));
+            }
+        }
+        egui::ComboBox::from_label("Crossover")
+            .selected_text(format!("{:?}", self.crossover))
+            .show_ui(ui, |ui| {
+                for kind in CrossoverKind::ALL {
+                    ui.selectable_value(&mut self.crossover, kind, format!("{:?}", kind));
+                }
+            });
+        ui.add(egui::Slider::new(&mut self.crossover_rate, 0.0..=1.0).text("Crossover rate"));
+        let mut sigma = self.mutation.sigma;
+        ui.add(egui::Slider::new(&mut sigma, 0.0..=1.0).text("Mutation sigma"));
+        if sigma != self.mutation.sigma {
+            self.set_sigma(sigma);
+        }
+        ui.add(egui::Slider::new(&mut self.topology.add_block_rate, 0.0..=0.5).text("Add block rate"));
+        ui.add(egui::Slider::new(&mut self.topology.remove_block_rate, 0.0..=0.5).text("Remove block rate"));
+        ui.add(egui::Slider::new(&mut self.topology.widen_rate, 0.0..=0.5).text("Widen rate"));
+        ui.add(egui::Slider::new(&mut self.topology.kernel_rate, 0.0..=0.5).text("Kernel change rate"));
+        ui.add(egui::Slider::new(&mut self.sparring_games, 0..=4).text("Games against search bot"));
+        ui.add(egui::Slider::new(&mut self.sparring_iterations, 10..=500).text("Search bot iterations"));
+        ui.checkbox(&mut self.use_speciation, "Speciation");
+        if self.use_speciation {
+            ui.label(format!("Species: {}", self.speciation.species.len()));
+            ui.add(egui::Slider::new(&mut self.speciation.threshold, 0.01..=2.0).text("Species threshold"));
+        }
+    }
+
+    fn save_state(&self) -> String {
+        let mut w = StateWriter::new();
+        w.value("generation", self.generation);
+        w.value("population_size", self.population_size);
+        w.value("game_count", self.game_count);
+        w.value("selection_rate", self.selection_rate);
+        w.value("crossover", format!("{:?}", self.crossover));
+        w.value("crossover_rate", self.crossover_rate);
+        w.value("max_turns", self.max_turns);
+
+        let m = &self.mutation;
+        w.value("sigma", m.sigma);
+        w.values("layer_sigma", &m.layer_sigma);
+        w.value("rate", m.rate);
+        w.value("reset_rate", m.reset_rate);
+        w.value("reset_range", m.reset_range);
+        w.value("tau", m.tau);
+        w.value("min_sigma", m.min_sigma);
+        w.value("max_sigma", m.max_sigma);
+
+        let t = &self.topology;
+        w.value("add_block_rate", t.add_block_rate);
+        w.value("remove_block_rate", t.remove_block_rate);
+        w.value("widen_rate", t.widen_rate);
+        w.value("kernel_rate", t.kernel_rate);
+        w.value("max_blocks", t.max_blocks);
+        w.value("max_channels", t.max_channels);
+        w.values("kernel_sizes", &t.kernel_sizes);
+
+        let sp = &self.speciation;
+        w.value("use_speciation", self.use_speciation);
+        w.value("threshold", sp.threshold);
+        w.value("weight_k", sp.weight_k);
+        w.value("stagnation", sp.stagnation);
+        w.value("protect_age", sp.protect_age);
+        w.value("protected_offspring", sp.protected_offspring);
+        w.value("next_id", sp.next_id);
+        w.value("species", sp.species.len());
+        for s in &sp.species {
+            w.value("id", s.id);
+            w.values("members", &s.members);
+            w.value("best_fitness", s.best_fitness);
+            w.value("created", s.created);
+            w.value("last_improved", s.last_improved);
+            w.block("representative", &save_network(&s.representative));
+        }
+
+        w.value("population", self.population.len());
+        for agent in &self.population {
+            w.agent("agent", agent);
+        }
+        w.value("hall_of_fame_size", self.hall_of_fame_size);
+        w.value("hall_of_fame", self.hall_of_fame.len());
+        for agent in &self.hall_of_fame {
+            w.agent("agent", agent);
+        }
+        //keys added after first checkpoint format go last and are optional when loading
+        w.value("sparring_games", self.sparring_games);
+        w.value("sparring_iterations", self.sparring_iterations);
+        w.data
+    }
+
+    fn load_state(&mut self, data : &str) -> Result<(), StateError> {
+        let mut r = StateReader::new(data);
+        self.generation = r.value("generation")?;
+        self.population_size = r.value("population_size")?;
+        self.game_count = r.value("game_count")?;
+        self.selection_rate = r.value("selection_rate")?;
+        let crossover : String = r.value("crossover")?;
+        self.crossover = CrossoverKind::ALL.into_iter().find(|k| format!("{:?}", k) == crossover).ok_or_else(|| StateError::BadValue(String::from("crossover")))?;
+        self.crossover_rate = r.value("crossover_rate")?;
+        self.max_turns = r.value("max_turns")?;
+
+        let m = &mut self.mutation;
+        m.sigma = r.value("sigma")?;
+        m.layer_sigma = r.values("layer_sigma")?;
+        m.rate = r.value("rate")?;
+        m.reset_rate = r.value("reset_rate")?;
+        m.reset_range = r.value("reset_range")?;
+        m.tau = r.value("tau")?;
+        m.min_sigma = r.value("min_sigma")?;
+        m.max_sigma = r.value("max_sigma")?;
+
+        let t = &mut self.topology;
+        t.add_block_rate = r.value("add_block_rate")?;
+        t.remove_block_rate = r.value("remove_block_rate")?;
+        t.widen_rate = r.value("widen_rate")?;
+        t.kernel_rate = r.value("kernel_rate")?;
+        t.max_blocks = r.value("max_blocks")?;
+        t.max_channels = r.value("max_channels")?;
+        t.kernel_sizes = r.values("kernel_sizes")?;
+
+        self.use_speciation = r.value("use_speciation")?;
+        let sp = &mut self.speciation;
+        sp.threshold = r.value("threshold")?;
+        sp.weight_k = r.value("weight_k")?;
+        sp.stagnation = r.value("stagnation")?;
+        sp.protect_age = r.value("protect_age")?;
+        sp.protected_offspring = r.value("protected_offspring")?;
+        sp.next_id = r.value("next_id")?;
+        let count : usize = r.value("species")?;
+        sp.species = (0..count).map(|_| Ok(Species {
+            id : r.value("id")?,
+            members : r.values("members")?,
+            best_fitness : r.value("best_fitness")?,
+            created : r.value("created")?,
+            last_improved : r.value("last_improved")?,
+            representative : r.network("representative")?
+        })).collect::<Result<_, StateError>>()?;
+
+        let count : usize = r.value("population")?;
+        self.population = (0..count).map(|_| r.agent("agent")).collect::<Result<_, _>>()?;
+        self.hall_of_fame_size = r.value("hall_of_fame_size")?;
+        let count : usize = r.value("hall_of_fame")?;
+        self.hall_of_fame = (0..count).map(|_| r.agent("agent")).collect::<Result<_, _>>()?;
+        let default = GeneticAlgorithm::default();
+        self.sparring_games = r.optional("sparring_games")?.unwrap_or(default.sparring_games);
+        self.sparring_iterations = r.optional("sparring_iterations")?.unwrap_or(default.sparring_iterations);
+        Ok(())
     }
 }
 
@@ -37,91 +618,493 @@ impl Default for GeneticAlgorithm {
     fn default() -> Self {
         GeneticAlgorithm {
             population : vec![],
+            population_size : 100,
             game_count : 3,
-            selection_rate : 0.5
+            selection_rate : 0.5,
+            mutation : MutationConfig::default(),
+            crossover : CrossoverKind::Filter,
+            crossover_rate : 0.5,
+            topology : TopologyConfig::default(),
+            use_speciation : false,
+            speciation : Speciation::default(),
+            map_data : String::new(),
+            max_turns : 50,
+            sparring_games : 0,
+            sparring_iterations : 100,
+            generation : 0,
+            hall_of_fame : vec![],
+            hall_of_fame_size : 10
         }
     }
 }
 
 impl Agent {
-    pub fn build() -> Agent {
+    pub fn build<R : Rng + ?Sized>(rng : &mut R) -> Agent {
+        Agent {
+            network : SimpleNetwork::simple_maker(5, INPUT_CHANNELS, 16, ACTION_CHANNELS, 2, rng),
+            ..Agent::build_empty()
+        }
+    }
+
+    pub fn from_network(network : SimpleNetwork, mutation : MutationState) -> Agent {
         Agent {
-            network : SimpleNetwork::simple_maker(5, 4, 16, 4, 2),
+            network,
+            mutation,
+            ..Agent::build_empty()
+        }
+    }
+
+    fn build_empty() -> Agent {
+        Agent {
+            network : SimpleNetwork { nodes : vec![] },
             fitness : 0.0,
             output : NetImage::new(1,1,1),
             input : NetImage::new(1,1,1),
-            owner : TileOwner::No
+            owner : TileOwner::No,
+            mutation : MutationState::default()
         }
     }
 
+    pub fn mutate<R : Rng + ?Sized>(&mut self, config : &MutationConfig, rng : &mut R) {
+        mutation::mutate(&mut self.network, &mut self.mutation, config, rng);
+    }
+
     pub fn prepare(&mut self, map : &Map, side : TileOwner) {
-        let input = NetImage::new(map.w, map.h, 4);
+        let input = NetImage::new(map.w, map.h, INPUT_CHANNELS);
         self.output = self.network.allocate_output(&input);
         self.input = input;
         self.owner = side;
     }
 
     pub fn get_actions(&mut self, map : &Map) -> Vec<Action> {
-        //fill data
-        let unit_k = match self.owner {
-            TileOwner::Me => {1.0}
-            TileOwner::Enemy => {-1.0}
-            TileOwner::No => {panic!("Unsupported owner")}
+        fill_input(map, &self.owner, &mut self.input);
+        self.network.process(&self.input, &mut self.output);
+        decode_actions(map, &self.owner, &self.output)
+    }
+
+    //supervised pretraining on recorded games, returns loss of last epoch
+    pub fn pretrain(&mut self, samples : &[Sample], epochs : usize) -> f32 {
+        let mut adam = Adam::new(1e-3);
+        let mut loss = 0.0;
+        for _ in 0..epochs {
+            loss = imitation::train_epoch(&mut self.network, samples, &mut adam, 16);
+        }
+        loss
+    }
+}
+
+pub struct GeneticScene {
+    pub trainer : Box<dyn Trainer>,
+    pub config : RunConfig,
+    pub ui_rng : StdRng,
+    pub map_data : String,
+    pub replay_dir : String,
+    pub pretrain_epochs : usize,
+    pub pretrain_loss : Option<f32>,
+    //files skipped by last pretrain with their errors
+    pub replay_errors : Vec<String>,
+    //why last resume failed, current run is kept
+    pub checkpoint_error : Option<String>
+}
+
+
+
+impl Default for GeneticScene {
+    fn default() -> Self {
+        let config = RunConfig {
+            seed : RunConfig::random_seed(),
+            ..RunConfig::default()
         };
+        let map_data = std::fs::read_to_string(&config.map_file).unwrap_or_default();
+        Self {
+            trainer : config.build_trainer(map_data.clone()),
+            ui_rng : StdRng::seed_from_u64(config.ui_seed()),
+            config,
+            map_data,
+            replay_dir : String::from("replays"),
+            pretrain_epochs : 5,
+            pretrain_loss : None,
+            replay_errors : vec![],
+            checkpoint_error : None
+        }
+    }
+}
 
-        for y in 0..map.h {
-            for x in 0..map.w {
-                let idx = y * map.w + x;
-                *self.input.get_mut(x, y, 0) = map.data[idx].units as f32 * unit_k;
-                *self.input.get_mut(x, y, 1) = map.data[idx].scrap_amount as f32;
-
-                let mut owner = 0.0;
-                if map.data[idx].owner == TileOwner::Me {
-                    owner = 1.0;
-                } else if map.data[idx].owner == TileOwner::Enemy {
-                    owner = -1.0;
-                }
-                *self.input.get_mut(x, y, 2) = owner;
+impl GeneticScene {
+    //inspector over copy of current best agent on training map, trainer keeps running unchanged
+    pub fn inspect_best(&self) -> Option<NetworkInspectorScene> {
+        let best = self.trainer.best()?.clone();
+        if self.map_data.trim().is_empty() {
+            return None;
+        }
+        Some(NetworkInspectorScene::new(best, Map::load(self.map_data.trim().to_string())))
+    }
 
-                *self.input.get_mut(x, y, 3) = match map.data[idx].recycler {
-                    true => {1.0}
-                    false => {0.0}
-                };
-            }
+    //StdRng state can not be written, so ui rng is reseeded from itself and new seed is saved,
+    //continued and resumed runs then draw same ui numbers
+    fn save_checkpoint(&mut self) {
+        let ui_seed = self.ui_rng.next_u64();
+        self.ui_rng = StdRng::seed_from_u64(ui_seed);
+        if let Ok(mut manager) = CheckpointManager::new(&self.config.checkpoint_dir, 1, self.config.keep_checkpoints) {
+            let _ = manager.update(&self.config, &self.map_data, self.trainer.as_ref(), ui_seed);
         }
+    }
+}
 
-        self.network.process(&self.input, &mut self.output);
+impl Scene for GeneticScene {
+    fn update(&mut self, ctx: &Context) {
+        egui::SidePanel::left("Left").show(ctx, |ui| {
+            let mut kind = self.config.trainer;
+            egui::ComboBox::from_label("Trainer")
+                .selected_text(kind.name())
+                .show_ui(ui, |ui| {
+                    for k in TrainerKind::ALL {
+                        ui.selectable_value(&mut kind, k, k.name());
+                    }
+                });
+            ui.horizontal(|ui| {
+                ui.label("Seed");
+                ui.add(egui::DragValue::new(&mut self.config.seed));
+            });
+            if kind != self.config.trainer || ui.button("Restart with seed").clicked() {
+                self.config.trainer = kind;
+                self.trainer = self.config.build_trainer(self.map_data.clone());
+                self.ui_rng = StdRng::seed_from_u64(self.config.ui_seed());
+            }
 
+            ui.label(format!("Generation: {}", self.trainer.generation()));
+            if let Some(best) = self.trainer.best() {
+                ui.label(format!("Best fitness: {:.2}", best.fitness));
+            }
+            self.trainer.ui(ui, &mut self.ui_rng);
+            if ui.button("Step").clicked() && !self.map_data.is_empty() {
+                let streams = self.config.generation_streams(self.trainer.generation());
+                self.trainer.step(&streams);
+            }
+            ui.horizontal(|ui| {
+                if ui.button("Save checkpoint").clicked() {
+                    self.save_checkpoint();
+                }
+                if ui.button("Resume latest").clicked() {
+                    let latest = CheckpointManager::new(&self.config.checkpoint_dir, 0, 0).ok().and_then(|m| m.latest());
+                    if let Some(data) = latest.and_then(|path| std::fs::read_to_string(path).ok()) {
+                        match checkpoint::load_checkpoint(&data) {
+                            Ok((config, map_data, trainer, ui_seed)) => {
+                                self.config = config;
+                                self.map_data = map_data;
+                                self.trainer = trainer;
+                                self.ui_rng = StdRng::seed_from_u64(ui_seed);
+                                self.checkpoint_error = None;
+                            }
+                            Err(e) => self.checkpoint_error = Some(e.to_string())
+                        }
+                    }
+                }
+            });
+            if let Some(error) = &self.checkpoint_error {
+                ui.label(format!("Resume failed: {}", error));
+            }
+
+            ui.separator();
+            ui.horizontal(|ui| {
+                ui.label("Replays");
+                ui.text_edit_singleline(&mut self.replay_dir);
+            });
+            ui.add(egui::Slider::new(&mut self.pretrain_epochs, 1..=50).text("Epochs"));
+            if ui.button("Pretrain agents on replays").clicked() {
+                let (replays, failed) = Replay::load_dir(&self.replay_dir);
+                self.replay_errors = failed.iter().map(|(path, e)| format!("{}: {}", path.display(), e)).collect();
+                let samples = imitation::collect_samples(&replays);
+                if !samples.is_empty() {
+                    let mut agents = self.trainer.agents_mut();
+                    let count = agents.len().max(1);
+                    let mut loss = 0.0;
+                    for agent in agents.iter_mut() {
+                        loss += agent.pretrain(&samples, self.pretrain_epochs);
+                    }
+                    self.pretrain_loss = Some(loss / count as f32);
+                }
+            }
+            if let Some(loss) = self.pretrain_loss {
+                ui.label(format!("Mean pretrain loss: {:.4}", loss));
+            }
+            for error in self.replay_errors.iter() {
+                ui.label(format!("Skipped {}", error));
+            }
+        });
+    }
+}
 
 
-        vec![]
+fn value_to_color(val : f32, max_abs : f32) -> Color32 {
+    let k = (val / max_abs).clamp(-1.0, 1.0);
+    let fade = (255.0 * (1.0 - k.abs())) as u8;
+    if k >= 0.0 {
+        Color32::from_rgb(255, fade, fade)
+    } else {
+        Color32::from_rgb(fade, fade, 255)
     }
 }
 
-pub struct GeneticScene {
-    pub ga : GeneticAlgorithm
+fn channel_max_abs(img : &NetImage, c : usize) -> f32 {
+    let mut max_abs = 0.0_f32;
+    for y in 0..img.h {
+        for x in 0..img.w {
+            max_abs = max_abs.max(img.get(x, y, c).abs());
+        }
+    }
+    if max_abs == 0.0 {
+        1.0
+    } else {
+        max_abs
+    }
 }
 
+//draw one channel of image as heatmap, returns clicked pixel
+fn draw_heatmap(ui : &mut egui::Ui, img : &NetImage, c : usize, pixel_size : f32) -> Option<(usize, usize)> {
+    let size = Vec2::new(img.w as f32 * pixel_size, img.h as f32 * pixel_size);
+    let (response, painter) = ui.allocate_painter(size, Sense::click());
+    let origin = response.rect.min;
+    let max_abs = channel_max_abs(img, c);
+
+    for y in 0..img.h {
+        for x in 0..img.w {
+            let min = origin + Vec2::new(x as f32 * pixel_size, y as f32 * pixel_size);
+            let rect = Rect::from_min_size(min, Vec2::new(pixel_size, pixel_size));
+            painter.rect_filled(rect, 0.0, value_to_color(img.get(x, y, c), max_abs));
+        }
+    }
 
+    if response.clicked() {
+        if let Some(pos) = response.interact_pointer_pos() {
+            let local : Pos2 = (pos - origin).to_pos2();
+            let x = (local.x / pixel_size) as usize;
+            let y = (local.y / pixel_size) as usize;
+            if x < img.w && y < img.h {
+                return Some((x, y));
+            }
+        }
+    }
+    None
+}
 
-impl Default for GeneticScene {
-    fn default() -> Self {
+pub struct NetworkInspectorScene {
+    pub agent : Agent,
+    pub map : Map,
+    pub selected_tile : Option<(usize, usize)>,
+    pub pixel_size : f32,
+    pub kernel_pixel_size : f32,
+    pub show_kernels : bool
+}
+
+impl NetworkInspectorScene {
+    pub fn new(mut agent : Agent, map : Map) -> Self {
+        agent.prepare(&map, TileOwner::Me);
+        agent.get_actions(&map);
         Self {
-            ga : GeneticAlgorithm::default()
+            agent,
+            map,
+            selected_tile : None,
+            pixel_size : 8.0,
+            kernel_pixel_size : 4.0,
+            show_kernels : false
         }
     }
+
+    fn channels_row(ui : &mut egui::Ui, img : &NetImage, pixel_size : f32) -> Option<(usize, usize)> {
+        let mut clicked = None;
+        ui.horizontal_wrapped(|ui| {
+            for c in 0..img.c {
+                ui.vertical(|ui| {
+                    ui.label(format!("c{}", c));
+                    if let Some(p) = draw_heatmap(ui, img, c, pixel_size) {
+                        clicked = Some(p);
+                    }
+                });
+            }
+        });
+        clicked
+    }
+
+    fn kernels_row(ui : &mut egui::Ui, conv : &Conv2d, pixel_size : f32) {
+        //each (out_c, in_c) pair is shown as small w x h image
+        ui.horizontal_wrapped(|ui| {
+            for out_c in 0..conv.out_c {
+                for in_c in 0..conv.in_c {
+                    let mut kernel = NetImage::new(conv.w, conv.h, 1);
+                    for dy in 0..conv.h {
+                        for dx in 0..conv.w {
+                            let w_idx = ((out_c * conv.h + dy) * conv.w + dx) * conv.in_c + in_c;
+                            *kernel.get_mut(dx, dy, 0) = conv.weights()[w_idx];
+                        }
+                    }
+                    draw_heatmap(ui, &kernel, 0, pixel_size);
+                }
+                ui.separator();
+            }
+        });
+    }
 }
 
-impl Scene for GeneticScene {
+impl Scene for NetworkInspectorScene {
     fn update(&mut self, ctx: &Context) {
-        egui::SidePanel::left("Left").show(ctx, |ui| {
-            ui.label(format!("Population size: {}", self.ga.population.len()));
-            if ui.button("Fill population").clicked() {
-                for idx in 0..100 {
-                    self.ga.population.push(Agent::build());
+        egui::SidePanel::left("InspectorLeft").show(ctx, |ui| {
+            if ui.button("Recompute").clicked() {
+                self.agent.get_actions(&self.map);
+            }
+            ui.add(egui::Slider::new(&mut self.pixel_size, 2.0..=32.0).text("Pixel size"));
+            ui.add(egui::Slider::new(&mut self.kernel_pixel_size, 1.0..=16.0).text("Kernel pixel size"));
+            ui.checkbox(&mut self.show_kernels, "Show Conv2d kernels");
+
+            ui.separator();
+            match self.selected_tile {
+                Some((x, y)) if x < self.agent.output.w && y < self.agent.output.h => {
+                    ui.label(format!("Tile ({}, {})", x, y));
+                    let vals = self.agent.output.get_channel_slice(x, y);
+                    for (c, val) in vals.iter().enumerate() {
+                        ui.label(format!("out c{}: {:.4}", c, val));
+                    }
+                }
+                _ => {
+                    ui.label("Click a tile to see output values");
                 }
             }
         });
+
+        egui::CentralPanel::default().show(ctx, |ui| {
+            egui::ScrollArea::vertical().show(ui, |ui| {
+                ui.heading("Input (units, scrap, owner, recycler)");
+                if let Some(p) = Self::channels_row(ui, &self.agent.input, self.pixel_size) {
+                    self.selected_tile = Some(p);
+                }
+
+                for (idx, node) in self.agent.network.nodes.iter().enumerate() {
+                    ui.separator();
+                    if let Some(conv) = node.layer.as_ref().as_any().downcast_ref::<Conv2d>() {
+                        ui.heading(format!("Node {}: Conv2d {}x{} {} -> {}", idx, conv.w, conv.h, conv.in_c, conv.out_c));
+                        if self.show_kernels {
+                            Self::kernels_row(ui, conv, self.kernel_pixel_size);
+                        }
+                    } else {
+                        ui.heading(format!("Node {}", idx));
+                    }
+                    if let Some(cache) = &node.cache {
+                        Self::channels_row(ui, cache, self.pixel_size);
+                    }
+                }
+
+                ui.separator();
+                ui.heading("Output");
+                if let Some(p) = Self::channels_row(ui, &self.agent.output, self.pixel_size) {
+                    self.selected_tile = Some(p);
+                }
+            });
+        });
     }
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    pub const MAP : &str = include_str!("../start_map.txt");
+
+    pub fn small_agent<R : Rng + ?Sized>(rng : &mut R) -> Agent {
+        Agent::from_network(SimpleNetwork::simple_maker(3, INPUT_CHANNELS, 4, ACTION_CHANNELS, 0, rng), MutationState::default())
+    }
+
+    //small and fast GA for tests
+    pub fn small_ga(config : &RunConfig) -> GeneticAlgorithm {
+        let mut rng = config.init_rng();
+        GeneticAlgorithm {
+            population : (0..6).map(|_| small_agent(&mut rng)).collect(),
+            map_data : MAP.trim().to_string(),
+            population_size : 6,
+            game_count : 2,
+            max_turns : 8,
+            sparring_games : 1,
+            sparring_iterations : 2,
+            ..GeneticAlgorithm::default()
+        }
+    }
+
+    #[test]
+    fn test_same_seed_same_generations() {
+        let config = RunConfig { seed : 7, ..RunConfig::default() };
+        let run = |threads : usize| {
+            let pool = rayon::ThreadPoolBuilder::new().num_threads(threads).build().unwrap();
+            pool.install(|| {
+                let mut ga = small_ga(&config);
+                for generation in 0..2 {
+                    ga.step(&config.generation_streams(generation));
+                }
+                ga.save_state()
+            })
+        };
+        let single = run(1);
+        assert_eq!(single, run(1));
+        assert_eq!(single, run(4));
+
+        let streams = config.generation_streams(0);
+        assert_ne!(streams.worker(0).gen::<u64>(), streams.worker(1).gen::<u64>());
+        assert_ne!(streams, config.generation_streams(1));
+    }
+
+    #[test]
+    fn test_load_state_without_sparring() {
+        let config = RunConfig::default();
+        let mut ga = small_ga(&config);
+        ga.sparring_games = 3;
+        let state = ga.save_state();
+        let old : String = state.lines()
+            .filter(|l| !l.starts_with("sparring_"))
+            .map(|l| format!("{}\n", l))
+            .collect();
+
+        let mut loaded = GeneticAlgorithm::default();
+        loaded.load_state(&old).unwrap();
+        assert_eq!(loaded.sparring_games, GeneticAlgorithm::default().sparring_games);
+        assert_eq!(loaded.population.len(), ga.population.len());
+        loaded.load_state(&state).unwrap();
+        assert_eq!(loaded.sparring_games, 3);
+    }
+
+    #[test]
+    fn test_set_sigma_scales_agents() {
+        let config = RunConfig::default();
+        let mut ga = small_ga(&config);
+        ga.population[0].mutation.sigmas = vec![0.125, 0.25];
+        ga.set_sigma(0.2);
+        assert_eq!(ga.population[0].mutation.sigmas, vec![0.25, 0.5]);
+        assert_eq!(ga.mutation.sigma, 0.2);
+        ga.set_sigma(0.0);
+        ga.set_sigma(0.3);
+        assert!(ga.population[0].mutation.sigmas.is_empty());
+    }
+
+    #[test]
+    fn test_inspect_best() {
+        let config = RunConfig::default();
+        let mut scene = GeneticScene {
+            trainer : Box::new(small_ga(&config)),
+            config,
+            ..GeneticScene::default()
+        };
+        scene.map_data = MAP.trim().to_string();
+        scene.trainer.agents_mut()[2].fitness = 3.0;
+        let inspector = scene.inspect_best().unwrap();
+        assert_eq!(inspector.agent.network.params_vec(), scene.trainer.best().unwrap().network.params_vec());
+        assert_eq!(scene.trainer.agents_mut().len(), 6);
+    }
+
+    #[test]
+    fn test_evaluate_single_agent() {
+        let config = RunConfig::default();
+        let mut ga = small_ga(&config);
+        ga.population.truncate(1);
+        ga.sparring_games = 0;
+        ga.population[0].fitness = 5.0;
+        ga.evaluate(&config.generation_streams(0));
+        assert_eq!(ga.population[0].fitness, 0.0);
+    }
+}
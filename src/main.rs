@@ -2,8 +2,74 @@ use eframe::egui;
 use egui::*;
 use bot::*;
 use robot_codingame_rust::*;
+use robot_codingame_rust::checkpoint::{load_checkpoint, CheckpointManager};
+
+//usage: robot_codingame_rust train <ga|es|cmaes> [generations] [map file] [seed]
+//same seed and arguments reproduce identical generations
+fn run_headless(args : &[String]) {
+    let config = RunConfig {
+        trainer : args.first().and_then(|name| TrainerKind::parse(name)).unwrap_or(TrainerKind::Genetic),
+        generations : args.get(1).and_then(|g| g.parse::<usize>().ok()).unwrap_or(100),
+        map_file : args.get(2).cloned().unwrap_or_else(|| String::from("start_map.txt")),
+        seed : args.get(3).and_then(|s| s.parse::<u64>().ok()).unwrap_or_else(RunConfig::random_seed),
+        ..RunConfig::default()
+    };
+    print!("{}", config.save());
+    let map_data = std::fs::read_to_string(&config.map_file).expect("Cannot read map file");
+
+    let trainer = config.build_trainer(map_data.clone());
+    train_loop(&config, &map_data, trainer);
+}
+
+//usage: robot_codingame_rust resume <checkpoint file or dir> [total generations]
+fn run_resume(args : &[String]) {
+    let path = std::path::PathBuf::from(args.first().map(|a| a.as_str()).unwrap_or("checkpoints"));
+    let path = if path.is_dir() {
+        CheckpointManager::new(&path, 0, 0).unwrap().latest().expect("No checkpoints in directory")
+    } else {
+        path
+    };
+    println!("resuming from {}", path.display());
+    let data = std::fs::read_to_string(&path).expect("Cannot read checkpoint");
+    let (mut config, map_data, trainer, _) = load_checkpoint(&data).unwrap_or_else(|e| {
+        eprintln!("Bad checkpoint {}: {}", path.display(), e);
+        std::process::exit(1)
+    });
+    if let Some(generations) = args.get(1).and_then(|g| g.parse::<usize>().ok()) {
+        config.generations = generations;
+    }
+    train_loop(&config, &map_data, trainer);
+}
+
+fn train_loop(config : &RunConfig, map_data : &str, mut trainer : Box<dyn Trainer>) {
+    let mut checkpoints = if config.checkpoint_dir.is_empty() {
+        None
+    } else {
+        Some(CheckpointManager::new(&config.checkpoint_dir, config.checkpoint_every, config.keep_checkpoints)
+            .expect("Cannot create checkpoint dir"))
+    };
+
+    while trainer.generation() < config.generations {
+        let streams = config.generation_streams(trainer.generation());
+        trainer.step(&streams);
+        let best = trainer.best().map(|a| a.fitness).unwrap_or(0.0);
+        println!("{} generation {}: best fitness {:.2}", trainer.name(), trainer.generation(), best);
+        if let Some(manager) = checkpoints.as_mut() {
+            manager.update(config, map_data, trainer.as_ref(), config.ui_seed()).expect("Cannot write checkpoint");
+        }
+    }
+}
 
 fn main() {
+    let args : Vec<String> = std::env::args().skip(1).collect();
+    if args.first().map(|a| a == "train").unwrap_or(false) {
+        run_headless(&args[1..]);
+        return;
+    }
+    if args.first().map(|a| a == "resume").unwrap_or(false) {
+        run_resume(&args[1..]);
+        return;
+    }
 
     let options = eframe::NativeOptions::default();
     eframe::run_native(
@@ -19,16 +85,11 @@ struct ShowGameMap {
     map : Map
 }
 
+//training scene lives for whole session, inspector is shown over it when open
+#[derive(Default)]
 struct Content {
-    scene : Box<dyn Scene>
-}
-
-impl Default for Content {
-    fn default() -> Self {
-        Self {
-            scene : Box::new(GeneticScene::default())
-        }
-    }
+    training : GeneticScene,
+    inspector : Option<NetworkInspectorScene>
 }
 
 fn tile_to_color(tile : &Tile) -> Color32 {
@@ -88,6 +149,20 @@ impl Default for ShowGameMap {
 
 impl eframe::App for Content {
     fn update(&mut self, ctx: &egui::Context, _frame: &mut eframe::Frame) {
-        self.scene.update(ctx);
+        egui::TopBottomPanel::top("Scenes").show(ctx, |ui| {
+            ui.horizontal(|ui| {
+                if ui.button("Genetic").clicked() {
+                    self.inspector = None;
+                }
+                let has_best = self.training.trainer.best().is_some();
+                if ui.add_enabled(has_best, egui::Button::new("Network inspector")).clicked() {
+                    self.inspector = self.training.inspect_best();
+                }
+            });
+        });
+        match self.inspector.as_mut() {
+            Some(inspector) => {inspector.update(ctx)}
+            None => {self.training.update(ctx)}
+        }
     }
 }
\ No newline at end of file